@@ -0,0 +1,67 @@
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+
+use super::{AttachmentError, BlobStore};
+
+pub struct LocalDirectoryBlobStore {
+	root: PathBuf,
+}
+
+impl LocalDirectoryBlobStore {
+	pub fn new(root: &str) -> Self {
+		Self { root: root.into() }
+	}
+
+	// file names come from other clients, so only the final component is kept
+	fn sanitize(file_name: &str) -> String {
+		let name = Path::new(file_name)
+			.file_name()
+			.and_then(|name| name.to_str())
+			.unwrap_or("");
+		let name: String = name
+			.chars()
+			.filter(|c| !c.is_control() && *c != '/' && *c != '\\')
+			.collect();
+
+		match name.trim_start_matches('.') {
+			"" => "attachment".to_string(),
+			_ => name,
+		}
+	}
+
+	// picks "name.ext", "name (1).ext", ... so existing files are never overwritten
+	async fn free_path(&self, file_name: &str) -> PathBuf {
+		let path = Path::new(file_name);
+		let stem = path
+			.file_stem()
+			.and_then(|s| s.to_str())
+			.unwrap_or(file_name);
+		let extension = path.extension().and_then(|e| e.to_str());
+
+		let mut candidate = self.root.join(file_name);
+		let mut counter = 1;
+		while tokio::fs::try_exists(&candidate).await.unwrap_or(false) {
+			let name = match extension {
+				Some(extension) => format!("{} ({}).{}", stem, counter, extension),
+				None => format!("{} ({})", stem, counter),
+			};
+			candidate = self.root.join(name);
+			counter += 1;
+		}
+
+		candidate
+	}
+}
+
+#[async_trait]
+impl BlobStore for LocalDirectoryBlobStore {
+	async fn put(&self, file_name: &str, data: &[u8]) -> Result<Box<str>, AttachmentError> {
+		tokio::fs::create_dir_all(&self.root).await?;
+
+		let path = self.free_path(&Self::sanitize(file_name)).await;
+		tokio::fs::write(&path, data).await?;
+
+		Ok(path.to_string_lossy().into())
+	}
+}
@@ -0,0 +1,552 @@
+/*
+ * Files are sent as a series of FileChunk events, each carrying a base64 slice
+ * of the file small enough to fit a single AppSync event. The receiving side
+ * collects the chunks, checks the SHA-256 of the reassembled file and hands it
+ * to a BlobStore for saving.
+ * Every chunk is signed over the transfer's description, including the hash,
+ * so the received file is checked against the sender's key like a message.
+ * Chunks are only collected once their signature is checked, each sender's
+ * transfers apart from the others', and at most MAX_PENDING_TRANSFERS at once,
+ * each in a temporary file until complete.
+*/
+use std::{
+	collections::HashMap,
+	fmt,
+	fs::{File, OpenOptions},
+	io::{Read, Seek, SeekFrom, Write},
+	path::PathBuf,
+	time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD as base64_engine, Engine as _};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::message::Verification;
+
+// base64 grows the data by a third, keeping each event well under 240KB
+pub const FILE_CHUNK_SIZE: usize = 128 * 1024;
+pub const MAX_FILE_SIZE: usize = 64 * 1024 * 1024;
+pub const TRANSFER_TIMEOUT: Duration = Duration::from_secs(120);
+pub const MAX_PENDING_TRANSFERS: usize = 4;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileChunk {
+	pub transfer_id: Box<str>,
+	pub sender: Box<str>,
+	pub channel: Box<str>,
+	pub file_name: Box<str>,
+	pub file_size: usize,
+	pub sha256: Box<str>,
+	pub chunk_index: usize,
+	pub chunk_count: usize,
+	pub data: Box<str>,
+	// whether the file was sealed with its channel's key before being split
+	#[serde(default)]
+	pub encrypted: bool,
+	#[serde(default)]
+	pub public_key: Option<Box<str>>,
+	#[serde(default)]
+	pub signature: Option<Box<str>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferDirection {
+	Sending,
+	Receiving,
+}
+
+#[derive(Debug, Clone)]
+pub struct TransferProgress {
+	pub transfer_id: Box<str>,
+	pub file_name: Box<str>,
+	pub direction: TransferDirection,
+	pub chunks_done: usize,
+	pub chunk_count: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Attachment {
+	pub sender: Box<str>,
+	pub channel: Box<str>,
+	pub file_name: Box<str>,
+	pub file_size: usize,
+	pub sha256: Box<str>,
+	pub location: Box<str>,
+	pub encrypted: bool,
+	pub verification: Verification,
+}
+
+#[derive(Debug)]
+pub enum AttachmentError {
+	FileTooLarge(usize),
+	BadChunk(String),
+	IntegrityError(String),
+	StorageError(String),
+	EncryptionError(String),
+}
+
+#[async_trait]
+pub trait BlobStore {
+	// stores the data under a name derived from file_name, returning where it was put
+	async fn put(&self, file_name: &str, data: &[u8]) -> Result<Box<str>, AttachmentError>;
+}
+
+// a transfer's chunks are written to a temporary file as they come, rather than kept in memory
+struct PartialFile {
+	path: PathBuf,
+	file: File,
+	// what the transfer's chunks are signed over, which each of them must match
+	description: Vec<u8>,
+	chunks: Vec<bool>,
+	received: usize,
+	last_update: Instant,
+}
+
+pub struct ReceivedFile {
+	pub attachment: Attachment,
+	pub data: Vec<u8>,
+}
+
+pub struct FileAssembler {
+	// where the partial files are kept
+	directory: PathBuf,
+	// by sender and transfer id, so no one can take over another's transfer
+	pending: HashMap<(Box<str>, Box<str>), PartialFile>,
+}
+
+pub fn sha256_hex(data: &[u8]) -> Box<str> {
+	Sha256::digest(data)
+		.iter()
+		.map(|byte| format!("{:02x}", byte))
+		.collect::<String>()
+		.into()
+}
+
+impl FileChunk {
+	pub fn split_file(
+		sender: &str,
+		channel: &str,
+		file_name: &str,
+		data: &[u8],
+		encrypted: bool,
+	) -> Result<Vec<FileChunk>, AttachmentError> {
+		if data.len() > MAX_FILE_SIZE {
+			return Err(AttachmentError::FileTooLarge(data.len()));
+		}
+
+		let transfer_id: Box<str> = Uuid::new_v4().simple().to_string().into();
+		let sha256 = sha256_hex(data);
+		let chunk_count = data.len().div_ceil(FILE_CHUNK_SIZE).max(1);
+
+		let chunks = (0..chunk_count)
+			.map(|chunk_index| {
+				let start = chunk_index * FILE_CHUNK_SIZE;
+				let end = (start + FILE_CHUNK_SIZE).min(data.len());
+				FileChunk {
+					transfer_id: transfer_id.clone(),
+					sender: sender.into(),
+					channel: channel.into(),
+					file_name: file_name.into(),
+					file_size: data.len(),
+					sha256: sha256.clone(),
+					chunk_index,
+					chunk_count,
+					data: base64_engine.encode(&data[start..end]).into(),
+					encrypted,
+					public_key: None,
+					signature: None,
+				}
+			})
+			.collect();
+
+		Ok(chunks)
+	}
+
+	// what a chunk is signed over, the same for each of the transfer's chunks
+	pub fn signed_bytes(&self) -> Vec<u8> {
+		serde_json::to_vec(&(
+			&self.transfer_id,
+			&self.sender,
+			&self.channel,
+			&self.file_name,
+			self.file_size,
+			&self.sha256,
+			self.chunk_count,
+			self.encrypted,
+		))
+		.unwrap()
+	}
+
+	// whether the sizes agree with each other, before anything is allocated for them
+	fn is_consistent(&self) -> bool {
+		self.file_size <= MAX_FILE_SIZE
+			&& self.chunk_count == self.file_size.div_ceil(FILE_CHUNK_SIZE).max(1)
+			&& self.chunk_index < self.chunk_count
+	}
+
+	// every chunk is full but the last
+	fn expected_size(&self) -> usize {
+		match self.chunk_index + 1 == self.chunk_count {
+			true => self.file_size - self.chunk_index * FILE_CHUNK_SIZE,
+			false => FILE_CHUNK_SIZE,
+		}
+	}
+
+	pub fn progress(&self, direction: TransferDirection, chunks_done: usize) -> TransferProgress {
+		TransferProgress {
+			transfer_id: self.transfer_id.clone(),
+			file_name: self.file_name.clone(),
+			direction,
+			chunks_done,
+			chunk_count: self.chunk_count,
+		}
+	}
+}
+
+impl FileAssembler {
+	pub fn new() -> Self {
+		Self::in_directory(std::env::temp_dir())
+	}
+
+	pub fn in_directory(directory: PathBuf) -> Self {
+		Self {
+			directory,
+			pending: HashMap::new(),
+		}
+	}
+
+	/*
+	 * returns the number of chunks collected so far for the chunk's transfer,
+	 * and the file once all of them have arrived and its hash matches. The
+	 * chunk's signature must already have been checked
+	 */
+	pub fn insert(
+		&mut self,
+		chunk: FileChunk,
+	) -> Result<(usize, Option<ReceivedFile>), AttachmentError> {
+		if !chunk.is_consistent() {
+			return Err(AttachmentError::BadChunk(format!(
+				"chunk {}/{} of {}",
+				chunk.chunk_index, chunk.chunk_count, chunk.file_name
+			)));
+		}
+		let key = (chunk.sender.clone(), chunk.transfer_id.clone());
+		if !self.pending.contains_key(&key) && self.pending.len() >= MAX_PENDING_TRANSFERS {
+			return Err(AttachmentError::BadChunk(format!(
+				"too many transfers in progress for {}",
+				chunk.file_name
+			)));
+		}
+
+		let data = base64_engine
+			.decode(chunk.data.as_bytes())
+			.map_err(|e| AttachmentError::BadChunk(e.to_string()))?;
+		if data.len() != chunk.expected_size() {
+			return Err(AttachmentError::BadChunk(format!(
+				"chunk {} of {} has the wrong size",
+				chunk.chunk_index, chunk.file_name
+			)));
+		}
+
+		let partial = match self.pending.get_mut(&key) {
+			Some(partial) => partial,
+			None => {
+				let partial = self.create_partial(&chunk)?;
+				self.pending.entry(key.clone()).or_insert(partial)
+			}
+		};
+		if partial.description != chunk.signed_bytes() {
+			return Err(AttachmentError::BadChunk(format!(
+				"chunk {} doesn't match the transfer of {}",
+				chunk.chunk_index, chunk.file_name
+			)));
+		}
+
+		if !partial.chunks[chunk.chunk_index] {
+			let offset = (chunk.chunk_index * FILE_CHUNK_SIZE) as u64;
+			partial.file.seek(SeekFrom::Start(offset))?;
+			partial.file.write_all(&data)?;
+			partial.chunks[chunk.chunk_index] = true;
+			partial.received += 1;
+		}
+		partial.last_update = Instant::now();
+
+		let received = partial.received;
+		if received < chunk.chunk_count {
+			return Ok((received, None));
+		}
+
+		// the temporary file goes once it's read
+		let mut partial = self.pending.remove(&key).unwrap();
+		let mut data = Vec::with_capacity(chunk.file_size);
+		partial.file.seek(SeekFrom::Start(0))?;
+		partial.file.read_to_end(&mut data)?;
+
+		let sha256 = sha256_hex(&data);
+		if sha256 != chunk.sha256 || data.len() != chunk.file_size {
+			return Err(AttachmentError::IntegrityError(
+				chunk.file_name.into_string(),
+			));
+		}
+
+		let attachment = Attachment {
+			sender: chunk.sender.clone(),
+			channel: chunk.channel.clone(),
+			file_name: chunk.file_name.clone(),
+			file_size: data.len(),
+			sha256,
+			location: "".into(),
+			encrypted: chunk.encrypted,
+			// set by whoever checked the chunks' signatures
+			verification: Verification::Unsigned,
+		};
+		Ok((received, Some(ReceivedFile { attachment, data })))
+	}
+
+	// returns a line about each transfer discarded, for the user
+	pub fn discard_expired(&mut self) -> Vec<String> {
+		let mut discarded = Vec::new();
+		self.pending.retain(|(_, transfer_id), partial| {
+			let keep = partial.last_update.elapsed() < TRANSFER_TIMEOUT;
+			if !keep {
+				discarded.push(format!(
+					"Discarding incomplete file transfer {} ({}/{} chunks)",
+					transfer_id,
+					partial.received,
+					partial.chunks.len()
+				));
+			}
+			keep
+		});
+		discarded
+	}
+
+	fn create_partial(&self, chunk: &FileChunk) -> Result<PartialFile, AttachmentError> {
+		std::fs::create_dir_all(&self.directory)?;
+		let path = self
+			.directory
+			.join(format!("transfer-{}.part", Uuid::new_v4().simple()));
+		let file = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create_new(true)
+			.open(&path)?;
+
+		Ok(PartialFile {
+			path,
+			file,
+			description: chunk.signed_bytes(),
+			chunks: vec![false; chunk.chunk_count],
+			received: 0,
+			last_update: Instant::now(),
+		})
+	}
+}
+
+impl Default for FileAssembler {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Drop for PartialFile {
+	fn drop(&mut self) {
+		std::fs::remove_file(&self.path).unwrap_or(());
+	}
+}
+
+impl std::error::Error for AttachmentError {}
+
+impl fmt::Display for AttachmentError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::FileTooLarge(size) => write!(
+				f,
+				"File of {} bytes exceeds the limit of {} bytes",
+				size, MAX_FILE_SIZE
+			),
+			Self::BadChunk(e) => write!(f, "Bad file chunk: {}", e),
+			Self::IntegrityError(name) => write!(f, "Integrity check failed for file: {}", name),
+			Self::StorageError(e) => write!(f, "Storage Error: {}", e),
+			Self::EncryptionError(e) => write!(f, "Encryption Error: {}", e),
+		}
+	}
+}
+
+impl From<std::io::Error> for AttachmentError {
+	fn from(error: std::io::Error) -> Self {
+		Self::StorageError(error.to_string())
+	}
+}
+
+pub mod local_directory;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// an assembler keeping its partial files in a directory of its own, removed when dropped
+	struct TestAssembler {
+		directory: PathBuf,
+		assembler: FileAssembler,
+	}
+
+	impl TestAssembler {
+		fn new() -> Self {
+			let directory =
+				std::env::temp_dir().join(format!("attachment-test-{}", Uuid::new_v4()));
+			let assembler = FileAssembler::in_directory(directory.clone());
+			Self {
+				directory,
+				assembler,
+			}
+		}
+
+		fn partial_files(&self) -> usize {
+			std::fs::read_dir(&self.directory).map_or(0, |entries| entries.count())
+		}
+	}
+
+	impl Drop for TestAssembler {
+		fn drop(&mut self) {
+			std::fs::remove_dir_all(&self.directory).unwrap_or(());
+		}
+	}
+
+	// two and a half chunks, each byte telling where it is
+	fn file() -> Vec<u8> {
+		(0..FILE_CHUNK_SIZE * 5 / 2)
+			.map(|index| (index % 251) as u8)
+			.collect()
+	}
+
+	fn chunks(sender: &str, data: &[u8]) -> Vec<FileChunk> {
+		FileChunk::split_file(sender, "general", "file.bin", data, false).unwrap()
+	}
+
+	#[test]
+	fn reassembles_chunks_in_any_order() {
+		let mut test = TestAssembler::new();
+		let data = file();
+		let chunks = chunks("alice", &data);
+		assert_eq!(chunks.len(), 3);
+
+		let (done, received) = test.assembler.insert(chunks[2].clone()).unwrap();
+		assert_eq!((done, received.is_none()), (1, true));
+		assert_eq!(test.partial_files(), 1);
+		// a chunk received twice is only counted once
+		let (done, _) = test.assembler.insert(chunks[2].clone()).unwrap();
+		assert_eq!(done, 1);
+		test.assembler.insert(chunks[0].clone()).unwrap();
+
+		let (done, received) = test.assembler.insert(chunks[1].clone()).unwrap();
+		let received = received.unwrap();
+		assert_eq!(done, 3);
+		assert_eq!(received.data, data);
+		assert_eq!(received.attachment.file_size, data.len());
+		assert_eq!(received.attachment.sha256, sha256_hex(&data));
+		assert_eq!(test.partial_files(), 0);
+	}
+
+	#[test]
+	fn empty_files_are_one_chunk() {
+		let mut test = TestAssembler::new();
+		let chunks = chunks("alice", b"");
+		assert_eq!(chunks.len(), 1);
+
+		let (_, received) = test.assembler.insert(chunks[0].clone()).unwrap();
+		assert!(received.unwrap().data.is_empty());
+	}
+
+	#[test]
+	fn corrupted_data_fails_the_integrity_check() {
+		let mut test = TestAssembler::new();
+		let mut chunks = chunks("alice", &file());
+		let mut data = base64_engine.decode(chunks[1].data.as_bytes()).unwrap();
+		data[0] ^= 1;
+		chunks[1].data = base64_engine.encode(data).into();
+
+		test.assembler.insert(chunks[0].clone()).unwrap();
+		test.assembler.insert(chunks[1].clone()).unwrap();
+		assert!(matches!(
+			test.assembler.insert(chunks[2].clone()),
+			Err(AttachmentError::IntegrityError(_))
+		));
+		assert_eq!(test.partial_files(), 0);
+	}
+
+	#[test]
+	fn chunks_of_the_wrong_size_are_rejected() {
+		let mut test = TestAssembler::new();
+		let mut chunks = chunks("alice", &file());
+		chunks[0].data = base64_engine.encode(b"short").into();
+
+		assert!(matches!(
+			test.assembler.insert(chunks[0].clone()),
+			Err(AttachmentError::BadChunk(_))
+		));
+	}
+
+	#[test]
+	fn inconsistent_chunks_are_rejected() {
+		let mut test = TestAssembler::new();
+		let mut chunks = chunks("alice", &file());
+		chunks[0].chunk_count = 1_000_000;
+
+		assert!(matches!(
+			test.assembler.insert(chunks[0].clone()),
+			Err(AttachmentError::BadChunk(_))
+		));
+		assert_eq!(test.partial_files(), 0);
+	}
+
+	#[test]
+	fn chunks_must_match_their_transfer() {
+		let mut test = TestAssembler::new();
+		let mut chunks = chunks("alice", &file());
+		chunks[1].file_name = "other.bin".into();
+
+		test.assembler.insert(chunks[0].clone()).unwrap();
+		assert!(matches!(
+			test.assembler.insert(chunks[1].clone()),
+			Err(AttachmentError::BadChunk(_))
+		));
+	}
+
+	#[test]
+	fn senders_cannot_take_over_each_others_transfers() {
+		let mut test = TestAssembler::new();
+		let data = file();
+		let chunks = chunks("alice", &data);
+
+		let mut forged = chunks[1].clone();
+		forged.sender = "mallory".into();
+		forged.data = base64_engine.encode(vec![0; FILE_CHUNK_SIZE]).into();
+		test.assembler.insert(forged).unwrap();
+
+		for chunk in &chunks[..2] {
+			test.assembler.insert(chunk.clone()).unwrap();
+		}
+		let (_, received) = test.assembler.insert(chunks[2].clone()).unwrap();
+		assert_eq!(received.unwrap().data, data);
+	}
+
+	#[test]
+	fn pending_transfers_are_limited() {
+		let mut test = TestAssembler::new();
+		for _ in 0..MAX_PENDING_TRANSFERS {
+			let chunks = chunks("alice", &file());
+			test.assembler.insert(chunks[0].clone()).unwrap();
+		}
+
+		let chunks = chunks("alice", &file());
+		assert!(matches!(
+			test.assembler.insert(chunks[0].clone()),
+			Err(AttachmentError::BadChunk(_))
+		));
+		assert_eq!(test.partial_files(), MAX_PENDING_TRANSFERS);
+	}
+}
@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::{Client, Url};
+
+use super::{BackfillError, BackfillProvider, BackfillQuery};
+use crate::message::{Message, WireMessage};
+
+// a service that doesn't answer is given up on, rather than waited for
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// talks to a history service such as the one in service.rs
+pub struct HttpBackfillProvider {
+	uri: Box<str>,
+	client: Client,
+}
+
+impl HttpBackfillProvider {
+	pub fn new(uri: &str) -> Self {
+		Self {
+			uri: uri.into(),
+			client: Client::builder()
+				.connect_timeout(CONNECT_TIMEOUT)
+				.timeout(REQUEST_TIMEOUT)
+				.build()
+				.unwrap_or_default(),
+		}
+	}
+
+	fn channel_url(&self, channel: &str) -> Result<Url, BackfillError> {
+		let mut url = Url::parse(&self.uri).map_err(|e| BackfillError::HTTPError(e.to_string()))?;
+		url.path_segments_mut()
+			.map_err(|_| {
+				BackfillError::HTTPError(format!("bad history service url: {}", self.uri))
+			})?
+			.pop_if_empty()
+			.extend(["channels", channel, "messages"]);
+		Ok(url)
+	}
+
+	fn query_parameters(query: &BackfillQuery) -> Vec<(&str, String)> {
+		match query {
+			BackfillQuery::Since { since, .. } => vec![("since", since.to_string())],
+			BackfillQuery::Sequences {
+				sender, from, to, ..
+			} => vec![
+				("sender", sender.to_string()),
+				("from", from.to_string()),
+				("to", to.to_string()),
+			],
+		}
+	}
+}
+
+#[async_trait]
+impl BackfillProvider for HttpBackfillProvider {
+	async fn record(&self, message: &Message) -> Result<(), BackfillError> {
+		let url = self.channel_url(&message.channel)?;
+		let wire = WireMessage::from(message.clone());
+		let response = self.client.post(url).json(&wire).send().await?;
+		if !response.status().is_success() {
+			return Err(BackfillError::RequestFailed(response.text().await?));
+		}
+		Ok(())
+	}
+
+	async fn fetch(&self, query: &BackfillQuery) -> Result<Vec<Message>, BackfillError> {
+		let url = self.channel_url(query.channel())?;
+		let request = self.client.get(url).query(&Self::query_parameters(query));
+
+		let response = request.send().await?;
+		if !response.status().is_success() {
+			return Err(BackfillError::RequestFailed(response.text().await?));
+		}
+		// as from the network, malformed messages are skipped
+		let messages: Vec<WireMessage> = response.json().await?;
+		Ok(messages
+			.into_iter()
+			.filter_map(|wire| Message::try_from(wire).ok())
+			.collect())
+	}
+}
+
+impl From<reqwest::Error> for BackfillError {
+	fn from(error: reqwest::Error) -> Self {
+		Self::HTTPError(error.to_string())
+	}
+}
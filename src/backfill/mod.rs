@@ -0,0 +1,62 @@
+/*
+ * AppSync Events doesn't replay what was published while a client was
+ * offline, so a backfill provider keeps a copy of every message published,
+ * and is asked for what was missed: a channel's messages since the last one
+ * seen on resubscribe, or a sender's range of sequence numbers on a gap.
+ * Messages are kept as they were sent, so encrypted channels stay encrypted.
+*/
+use std::fmt;
+
+use async_trait::async_trait;
+
+use crate::message::{Message, Timestamp};
+
+#[derive(Debug)]
+pub enum BackfillError {
+	HTTPError(String),
+	RequestFailed(String),
+}
+
+#[derive(Debug)]
+pub enum BackfillQuery {
+	// the channel's messages sent at or after the given time
+	Since {
+		channel: Box<str>,
+		since: Timestamp,
+	},
+	// the sender's messages in the channel, with sequence numbers in the range
+	Sequences {
+		channel: Box<str>,
+		sender: Box<str>,
+		from: u64,
+		to: u64,
+	},
+}
+
+#[async_trait]
+pub trait BackfillProvider {
+	async fn record(&self, message: &Message) -> Result<(), BackfillError>;
+	async fn fetch(&self, query: &BackfillQuery) -> Result<Vec<Message>, BackfillError>;
+}
+
+impl BackfillQuery {
+	pub fn channel(&self) -> &str {
+		match self {
+			Self::Since { channel, .. } | Self::Sequences { channel, .. } => channel,
+		}
+	}
+}
+
+impl std::error::Error for BackfillError {}
+
+impl fmt::Display for BackfillError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::HTTPError(e) => write!(f, "HTTP Error: {}", e),
+			Self::RequestFailed(e) => write!(f, "Backfill Request Failed: {}", e),
+		}
+	}
+}
+
+pub mod http_backfill_provider;
+pub mod service;
@@ -0,0 +1,94 @@
+/*
+ * A minimal history service, standing in for a real one when running
+ * locally: it keeps the messages posted to it in memory, and answers the
+ * queries HttpBackfillProvider makes.
+ *   POST /channels/{channel}/messages   stores a message (once per id)
+ *   GET  /channels/{channel}/messages?since=<ms>
+ *   GET  /channels/{channel}/messages?sender=<name>&from=<n>&to=<n>
+*/
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+	extract::{Path, Query, State},
+	http::StatusCode,
+	routing::get,
+	Json, Router,
+};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+
+use crate::message::{Message, Timestamp, WireMessage};
+
+type Channels = Arc<Mutex<HashMap<Box<str>, Vec<Message>>>>;
+
+#[derive(Deserialize)]
+struct MessagesQuery {
+	since: Option<Timestamp>,
+	sender: Option<Box<str>>,
+	from: Option<u64>,
+	to: Option<u64>,
+}
+
+pub async fn serve(address: &str) -> std::io::Result<()> {
+	let channels = Channels::default();
+	let router = Router::new()
+		.route(
+			"/channels/{channel}/messages",
+			get(get_messages).post(post_message),
+		)
+		.with_state(channels);
+
+	let listener = tokio::net::TcpListener::bind(address).await?;
+	println!("History service listening on {}", listener.local_addr()?);
+	axum::serve(listener, router).await
+}
+
+async fn post_message(
+	State(channels): State<Channels>,
+	Path(channel): Path<Box<str>>,
+	Json(message): Json<WireMessage>,
+) -> StatusCode {
+	let message = match Message::try_from(message) {
+		Ok(message) if message.channel == channel => message,
+		_ => return StatusCode::BAD_REQUEST,
+	};
+
+	let mut channels = channels.lock().await;
+	let messages = channels.entry(channel).or_default();
+	if !messages.iter().any(|stored| stored.id == message.id) {
+		messages.push(message);
+	}
+	StatusCode::NO_CONTENT
+}
+
+async fn get_messages(
+	State(channels): State<Channels>,
+	Path(channel): Path<Box<str>>,
+	Query(query): Query<MessagesQuery>,
+) -> Json<Vec<WireMessage>> {
+	let channels = channels.lock().await;
+	let messages = channels
+		.get(&channel)
+		.map(Vec::as_slice)
+		.unwrap_or_default();
+
+	let matching = messages.iter().filter(|message| {
+		let time = message.sent_at.or(message.received_at);
+		let sequence = message.sequence;
+		query
+			.since
+			.is_none_or(|since| time.is_some_and(|time| time >= since))
+			&& query
+				.sender
+				.as_ref()
+				.is_none_or(|sender| *sender == message.sender)
+			&& query
+				.from
+				.is_none_or(|from| sequence.is_some_and(|sequence| sequence >= from))
+			&& query
+				.to
+				.is_none_or(|to| sequence.is_some_and(|sequence| sequence <= to))
+	});
+
+	Json(matching.cloned().map(WireMessage::from).collect())
+}
@@ -0,0 +1,297 @@
+/*
+ * AppSync Events rejects events larger than 240KB. Events over that size are
+ * split into numbered chunks sharing an id, each carrying a piece of the
+ * serialized event, and the receiving side reassembles them before parsing.
+ * Incomplete sets are discarded after CHUNK_TIMEOUT, and at most
+ * MAX_PENDING_EVENTS sets of MAX_PENDING_SIZE bytes in all are held at once.
+*/
+use std::{
+	collections::HashMap,
+	time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const MAX_EVENT_SIZE: usize = 240 * 1024;
+pub const MAX_CHUNKS: usize = 64;
+pub const CHUNK_TIMEOUT: Duration = Duration::from_secs(60);
+pub const MAX_PENDING_EVENTS: usize = 32;
+// room for a couple of the largest events at once
+pub const MAX_PENDING_SIZE: usize = 2 * MAX_CHUNKS * MAX_EVENT_SIZE;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventChunk {
+	pub chunk_id: Box<str>,
+	pub index: usize,
+	pub total: usize,
+	pub data: Box<str>,
+}
+
+#[derive(Debug)]
+pub struct ChunkingError {
+	pub event_size: usize,
+}
+
+struct PartialEvent {
+	parts: Vec<Option<Box<str>>>,
+	received: usize,
+	started: Instant,
+}
+
+#[derive(Default)]
+pub struct ChunkAssembler {
+	pending: HashMap<Box<str>, PartialEvent>,
+	// the data held in pending, in bytes
+	pending_size: usize,
+}
+
+impl EventChunk {
+	// room left for the chunk's own fields once serialized
+	const OVERHEAD: usize = 128;
+
+	/*
+	 * returns the event unchanged if it fits in max_size, otherwise the
+	 * serialized chunks it was split into
+	 */
+	pub fn split(event: String, max_size: usize) -> Result<Vec<String>, ChunkingError> {
+		if event.len() <= max_size {
+			return Ok(vec![event]);
+		}
+
+		let budget = max_size.saturating_sub(Self::OVERHEAD);
+		let pieces = Self::split_escaped(&event, budget);
+		if budget == 0 || pieces.len() > MAX_CHUNKS {
+			return Err(ChunkingError {
+				event_size: event.len(),
+			});
+		}
+
+		let chunk_id: Box<str> = Uuid::new_v4().simple().to_string().into();
+		let total = pieces.len();
+
+		Ok(pieces
+			.into_iter()
+			.enumerate()
+			.map(|(index, data)| {
+				let chunk = EventChunk {
+					chunk_id: chunk_id.clone(),
+					index,
+					total,
+					data: data.into(),
+				};
+				serde_json::to_string(&chunk).unwrap()
+			})
+			.collect())
+	}
+
+	// splits on char boundaries so that every piece, once JSON-escaped, fits in budget
+	fn split_escaped(event: &str, budget: usize) -> Vec<&str> {
+		let mut pieces = Vec::new();
+		let mut start = 0;
+		let mut size = 0;
+
+		for (position, c) in event.char_indices() {
+			let char_size = Self::escaped_len(c);
+			if size + char_size > budget && position > start {
+				pieces.push(&event[start..position]);
+				start = position;
+				size = 0;
+			}
+			size += char_size;
+		}
+		pieces.push(&event[start..]);
+
+		pieces
+	}
+
+	fn escaped_len(c: char) -> usize {
+		match c {
+			'"' | '\\' | '\n' | '\r' | '\t' | '\u{08}' | '\u{0c}' => 2,
+			c if (c as u32) < 0x20 => 6,
+			c => c.len_utf8(),
+		}
+	}
+}
+
+impl ChunkAssembler {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/*
+	 * returns the full event once the last missing chunk of its set arrives,
+	 * or why the chunk was dropped
+	 */
+	pub fn insert(&mut self, chunk: EventChunk) -> Result<Option<String>, String> {
+		if chunk.total == 0 || chunk.total > MAX_CHUNKS || chunk.index >= chunk.total {
+			return Ok(None);
+		}
+		if !self.pending.contains_key(&chunk.chunk_id) && self.pending.len() >= MAX_PENDING_EVENTS {
+			return Err(format!(
+				"Dropping chunk of {}, too many incomplete messages",
+				chunk.chunk_id
+			));
+		}
+		if self.pending_size + chunk.data.len() > MAX_PENDING_SIZE {
+			return Err(format!(
+				"Dropping chunk of {}, too much incomplete data",
+				chunk.chunk_id
+			));
+		}
+
+		let partial = self
+			.pending
+			.entry(chunk.chunk_id.clone())
+			.or_insert_with(|| PartialEvent {
+				parts: vec![None; chunk.total],
+				received: 0,
+				started: Instant::now(),
+			});
+
+		if partial.parts.len() != chunk.total {
+			return Ok(None);
+		}
+
+		let slot = &mut partial.parts[chunk.index];
+		if slot.is_none() {
+			self.pending_size += chunk.data.len();
+			*slot = Some(chunk.data);
+			partial.received += 1;
+		}
+
+		if partial.received < chunk.total {
+			return Ok(None);
+		}
+
+		let partial = match self.pending.remove(&chunk.chunk_id) {
+			Some(partial) => partial,
+			None => return Ok(None),
+		};
+		self.pending_size -= partial.size();
+		Ok(Some(partial.parts.into_iter().flatten().collect()))
+	}
+
+	// returns a line about each set discarded, for the user
+	pub fn discard_expired(&mut self) -> Vec<String> {
+		let mut discarded = Vec::new();
+		let pending_size = &mut self.pending_size;
+		self.pending.retain(|chunk_id, partial| {
+			let keep = partial.started.elapsed() < CHUNK_TIMEOUT;
+			if !keep {
+				*pending_size -= partial.size();
+				discarded.push(format!(
+					"Discarding incomplete message {} ({}/{} chunks)",
+					chunk_id,
+					partial.received,
+					partial.parts.len()
+				));
+			}
+			keep
+		});
+		discarded
+	}
+}
+
+impl PartialEvent {
+	fn size(&self) -> usize {
+		self.parts.iter().flatten().map(|part| part.len()).sum()
+	}
+}
+
+impl std::error::Error for ChunkingError {}
+
+impl std::fmt::Display for ChunkingError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(
+			f,
+			"event of {} bytes exceeds the limit of {} chunks of {} bytes",
+			self.event_size, MAX_CHUNKS, MAX_EVENT_SIZE
+		)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parsed(chunks: &[String]) -> Vec<EventChunk> {
+		chunks
+			.iter()
+			.map(|chunk| serde_json::from_str(chunk).unwrap())
+			.collect()
+	}
+
+	// with characters that grow when escaped, and some wider than a byte
+	fn event(length: usize) -> String {
+		"ab\"c\\d\né🙂\u{1}".chars().cycle().take(length).collect()
+	}
+
+	#[test]
+	fn small_events_are_left_whole() {
+		let event = event(100);
+		assert_eq!(EventChunk::split(event.clone(), 1024).unwrap(), vec![event]);
+	}
+
+	#[test]
+	fn chunks_fit_and_reassemble_in_any_order() {
+		let event = event(10_000);
+		let chunks = EventChunk::split(event.clone(), 1024).unwrap();
+		assert!(chunks.len() > 1);
+		assert!(chunks.iter().all(|chunk| chunk.len() <= 1024));
+
+		let mut assembler = ChunkAssembler::new();
+		let mut chunks = parsed(&chunks);
+		let last = chunks.remove(0);
+		for chunk in chunks.into_iter().rev() {
+			assert_eq!(assembler.insert(chunk.clone()).unwrap(), None);
+			// a chunk received twice is only taken once
+			assert_eq!(assembler.insert(chunk).unwrap(), None);
+		}
+		assert_eq!(assembler.insert(last).unwrap(), Some(event));
+		assert_eq!(assembler.pending_size, 0);
+	}
+
+	#[test]
+	fn oversized_events_fail() {
+		assert!(EventChunk::split(event(MAX_CHUNKS * 1024), 1024).is_err());
+		// too small to fit any of the event along with the chunk's fields
+		assert!(EventChunk::split(event(200), 100).is_err());
+	}
+
+	#[test]
+	fn inconsistent_chunks_are_dropped() {
+		let mut assembler = ChunkAssembler::new();
+		let chunk = |index, total| EventChunk {
+			chunk_id: "id".into(),
+			index,
+			total,
+			data: "data".into(),
+		};
+
+		assert_eq!(assembler.insert(chunk(0, 0)).unwrap(), None);
+		assert_eq!(assembler.insert(chunk(2, 2)).unwrap(), None);
+		assert_eq!(assembler.insert(chunk(0, MAX_CHUNKS + 1)).unwrap(), None);
+		assert!(assembler.pending.is_empty());
+
+		assembler.insert(chunk(0, 2)).unwrap();
+		assert_eq!(assembler.insert(chunk(1, 3)).unwrap(), None);
+		assert_eq!(assembler.pending["id"].received, 1);
+	}
+
+	#[test]
+	fn incomplete_events_are_limited() {
+		let mut assembler = ChunkAssembler::new();
+		let chunk = |chunk_id: usize| EventChunk {
+			chunk_id: chunk_id.to_string().into(),
+			index: 0,
+			total: 2,
+			data: "data".into(),
+		};
+
+		for chunk_id in 0..MAX_PENDING_EVENTS {
+			assembler.insert(chunk(chunk_id)).unwrap();
+		}
+		assert!(assembler.insert(chunk(MAX_PENDING_EVENTS)).is_err());
+	}
+}
@@ -0,0 +1,334 @@
+/*
+ * One-shot subcommands, for scripts, which run without the client:
+ *   send --channel <channel> [--as <sender>] <text>...
+ *       publishes a text message and prints its id. The sender defaults to
+ *       NICKNAME. The message isn't stored in history, as a running client may
+ *       hold the database, but it is once received back by a client
+ *   listen --channel <channel>... [--json]
+ *       prints the channels' messages as they arrive, as lines like the
+ *       simplified UI's or as the JSON Lines UI's message events, until
+ *       interrupted or disconnected
+ *   channels [--json]
+ *       prints the channels a client subscribes to on start, one a line, or
+ *       each as JSON
+ *   check
+ *       checks the settings, and that the servers can be reached
+ *   config show
+ *       prints the settings in effect, as they would be written in the env
+ *       file, with secrets redacted
+ * They exit with 0 when done, 1 when something failed, such as sending, and 2
+ * when used wrong. Those needing the identity only ask for its passphrase on
+ * a terminal, so IDENTITY_PASSPHRASE is needed in scripts.
+*/
+use std::{net::SocketAddr, path::Path, process::ExitCode, sync::Arc, time::Duration};
+
+use reqwest::Url;
+
+use crate::{
+	authenticator::{appsync_api_authenticator::AppSyncAPIAuthenticator, Authenticator},
+	backfill::{http_backfill_provider::HttpBackfillProvider, BackfillProvider, BackfillQuery},
+	identity::Keyring,
+	message::{timestamp_now, Message, Verification},
+	message_receiver::{appsync_message_receiver::AppSyncMessageReceiver, MessageReceiver},
+	message_sender::{appsync_message_sender::AppSyncMessageSender, MessageSender},
+	messenger::shutdown_signal,
+	sequence::SequenceCounter,
+	settings::{Settings, SECRET_SETTINGS},
+	subscriptions::Subscriptions,
+	task_queue::{TaskData, TaskQueue},
+	ui_connector::{json_lines::Event, simplified::SimplifiedUI, UIKind},
+};
+
+const USAGE_ERROR: u8 = 2;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// a channel no one uses, for checking the history service answers
+const CHECK_CHANNEL: &str = "desktop-messenger-check";
+
+const SEND_USAGE: &str = "usage: send --channel <channel> [--as <sender>] <text>...";
+const LISTEN_USAGE: &str = "usage: listen --channel <channel>... [--json]";
+const CHANNELS_USAGE: &str = "usage: channels [--json]";
+
+#[derive(Default)]
+struct Flags {
+	channels: Vec<Box<str>>,
+	sender: Option<Box<str>>,
+	json: bool,
+	words: Vec<String>,
+}
+
+pub fn usage_error(usage: &str) -> ExitCode {
+	eprintln!("{}", usage);
+	ExitCode::from(USAGE_ERROR)
+}
+
+pub async fn send(settings: &Settings, keyring: &Keyring, args: &[&str]) -> ExitCode {
+	let flags = match Flags::parse(args) {
+		Ok(flags) if flags.channels.len() == 1 && !flags.words.is_empty() && !flags.json => flags,
+		Ok(_) => return usage_error(SEND_USAGE),
+		Err(e) => return usage_error(&format!("{}\n{}", e, SEND_USAGE)),
+	};
+	let sender = match flags.sender {
+		Some(sender) => sender,
+		None if !settings.NICKNAME.is_empty() => settings.NICKNAME.to_string().into(),
+		None => return usage_error("--as is needed when NICKNAME isn't set"),
+	};
+
+	let mut message = Message::text(&sender, &flags.channels[0], &flags.words.join(" "));
+	match SequenceCounter::open(&settings.DATA_DIRECTORY)
+		.and_then(|mut sequences| sequences.next(&message.sender, &message.channel))
+	{
+		Ok(sequence) => message.sequence = Some(sequence),
+		Err(e) => eprintln!("Error counting message sequence: {}", e),
+	}
+	keyring.sign(&mut message);
+
+	let auth = authenticator(settings);
+	if !auth.authenticate() {
+		eprintln!("Authentication Failed!");
+		return ExitCode::FAILURE;
+	}
+	let message_sender = AppSyncMessageSender::new(&settings.APPSYNC_PUBLISH_URL, auth);
+	if let Err(e) = message_sender.send_text_message(message.clone()).await {
+		eprintln!("Error sending message: {}", e);
+		return ExitCode::FAILURE;
+	}
+
+	// it was sent either way
+	if !settings.BACKFILL_URL.is_empty() {
+		let backfill = HttpBackfillProvider::new(&settings.BACKFILL_URL);
+		if let Err(e) = backfill.record(&message).await {
+			eprintln!("Error recording message for backfill: {}", e);
+		}
+	}
+	println!("{}", message.id);
+	ExitCode::SUCCESS
+}
+
+pub async fn listen(settings: &Settings, keyring: &mut Keyring, args: &[&str]) -> ExitCode {
+	let flags = match Flags::parse(args) {
+		Ok(flags)
+			if !flags.channels.is_empty() && flags.words.is_empty() && flags.sender.is_none() =>
+		{
+			flags
+		}
+		Ok(_) => return usage_error(LISTEN_USAGE),
+		Err(e) => return usage_error(&format!("{}\n{}", e, LISTEN_USAGE)),
+	};
+
+	let mut task_queue = TaskQueue::new();
+	let receiver =
+		AppSyncMessageReceiver::new(&settings.APPSYNC_WEBSOCKET_URL, authenticator(settings));
+	let connection = match receiver.listen(task_queue.clone()).await {
+		Ok(connection) => connection,
+		Err(e) => {
+			eprintln!("Could not connect: {}", e);
+			return ExitCode::FAILURE;
+		}
+	};
+	for channel in &flags.channels {
+		connection.lock().await.add_channel(channel).await;
+	}
+
+	let signal = shutdown_signal();
+	tokio::pin!(signal);
+	let status = loop {
+		let task = tokio::select! {
+			task = task_queue.pop() => task,
+			_ = &mut signal => break ExitCode::SUCCESS,
+		};
+		match task {
+			TaskData::ReceiveMessage(mut message) if !message.kind.is_ephemeral() => {
+				message.verification = keyring.verify(&message).unwrap_or_else(|e| {
+					eprintln!("Error updating trusted keys: {}", e);
+					Verification::Trusted
+				});
+				match flags.json {
+					true => match serde_json::to_string(&Event::Message { message }) {
+						Ok(line) => println!("{}", line),
+						Err(e) => eprintln!("Error writing message: {}", e),
+					},
+					false => println!("{}", SimplifiedUI::format_message(&message)),
+				}
+			}
+			TaskData::ConnectionLost => {
+				eprintln!("Connection lost");
+				break ExitCode::FAILURE;
+			}
+			// such as file chunks, which aren't shown
+			_ => (),
+		}
+	};
+
+	let mut connection = connection.lock().await;
+	for channel in connection.channels() {
+		connection.remove_channel(&channel).await;
+	}
+	connection.close().await;
+	status
+}
+
+pub fn channels(settings: &Settings, args: &[&str]) -> ExitCode {
+	let json = match args {
+		[] => false,
+		["--json"] => true,
+		_ => return usage_error(CHANNELS_USAGE),
+	};
+	let subscriptions = match Subscriptions::open(&settings.DATA_DIRECTORY) {
+		Ok(subscriptions) => subscriptions,
+		Err(e) => {
+			eprintln!("Error reading subscriptions: {}", e);
+			return ExitCode::FAILURE;
+		}
+	};
+
+	for subscription in subscriptions.list() {
+		match json {
+			true => match serde_json::to_string(subscription) {
+				Ok(line) => println!("{}", line),
+				Err(e) => {
+					eprintln!("Error writing channel: {}", e);
+					return ExitCode::FAILURE;
+				}
+			},
+			false => {
+				let favourite = if subscription.favourite {
+					" (favourite)"
+				} else {
+					""
+				};
+				let muted = if subscription.muted { " (muted)" } else { "" };
+				println!("{}{}{}", subscription.channel, favourite, muted);
+			}
+		}
+	}
+	ExitCode::SUCCESS
+}
+
+// each check is printed as it's done
+pub async fn check(settings: &Settings) -> ExitCode {
+	// the settings were read by now, or this wouldn't run
+	let mut ok = report("settings file", Ok(()));
+
+	let data_directory = Path::new(&**settings.DATA_DIRECTORY);
+	let res = match data_directory.exists() {
+		true if data_directory.is_dir() => Ok(()),
+		true => Err(format!("{} isn't a directory", data_directory.display())),
+		// made on the first start
+		false => Ok(()),
+	};
+	ok &= report("data directory", res);
+
+	let res = Url::parse(&settings.APPSYNC_PUBLISH_URL).map(|_| ());
+	ok &= report("publish url", res.map_err(|e| e.to_string()));
+	let res = Url::parse(&settings.APPSYNC_WEBSOCKET_URL).map(|_| ());
+	ok &= report("websocket url", res.map_err(|e| e.to_string()));
+
+	if settings.UI.contains(&UIKind::Web) {
+		let res = match settings.WEB_ADDRESS.parse::<SocketAddr>() {
+			Ok(address) if address.ip().is_loopback() => Ok(()),
+			Ok(_) => Err("not a loopback address".to_string()),
+			Err(e) => Err(e.to_string()),
+		};
+		ok &= report("web address", res);
+	}
+
+	let auth = authenticator(settings);
+	let res = match auth.authenticate() {
+		true => Ok(()),
+		false => Err("rejected".to_string()),
+	};
+	ok &= report("authentication", res);
+
+	let receiver = AppSyncMessageReceiver::new(&settings.APPSYNC_WEBSOCKET_URL, auth);
+	let res = match tokio::time::timeout(CONNECT_TIMEOUT, receiver.listen(TaskQueue::new())).await {
+		Ok(Ok(connection)) => {
+			connection.lock().await.close().await;
+			Ok(())
+		}
+		Ok(Err(e)) => Err(e.to_string()),
+		Err(_) => Err("timed out".to_string()),
+	};
+	ok &= report("websocket connection", res);
+
+	if !settings.BACKFILL_URL.is_empty() {
+		let backfill = HttpBackfillProvider::new(&settings.BACKFILL_URL);
+		let query = BackfillQuery::Since {
+			channel: CHECK_CHANNEL.into(),
+			since: timestamp_now(),
+		};
+		let res = match tokio::time::timeout(CONNECT_TIMEOUT, backfill.fetch(&query)).await {
+			Ok(res) => res.map(|_| ()).map_err(|e| e.to_string()),
+			Err(_) => Err("timed out".to_string()),
+		};
+		ok &= report("history service", res);
+	}
+
+	match ok {
+		true => ExitCode::SUCCESS,
+		false => ExitCode::FAILURE,
+	}
+}
+
+pub fn config(settings: &Settings, args: &[&str]) -> ExitCode {
+	if args != ["show"] {
+		return usage_error("usage: config show");
+	}
+
+	for (name, value) in settings.entries() {
+		match SECRET_SETTINGS.contains(&name) && !value.is_empty() {
+			true => println!("{}=<redacted>", name),
+			false => println!("{}={}", name, value),
+		}
+	}
+	ExitCode::SUCCESS
+}
+
+fn authenticator(settings: &Settings) -> Arc<AppSyncAPIAuthenticator> {
+	Arc::new(AppSyncAPIAuthenticator::new(
+		&settings.APPSYNC_HTTP_DOMAIN,
+		&settings.APPSYNC_API_KEY,
+	))
+}
+
+// returns whether it passed
+fn report(name: &str, res: Result<(), String>) -> bool {
+	match res {
+		Ok(()) => {
+			println!("ok      {}", name);
+			true
+		}
+		Err(e) => {
+			println!("failed  {}: {}", name, e);
+			false
+		}
+	}
+}
+
+impl Flags {
+	fn parse(args: &[&str]) -> Result<Self, String> {
+		let mut flags = Self::default();
+		let mut args = args.iter();
+
+		while let Some(arg) = args.next() {
+			let mut value = |flag: &str| {
+				args.next()
+					.map(|value| Box::from(*value))
+					.ok_or_else(|| format!("{} needs a value", flag))
+			};
+			match *arg {
+				"--channel" => flags.channels.push(value(arg)?),
+				"--as" => flags.sender = Some(value(arg)?),
+				"--json" => flags.json = true,
+				// the rest is text, even if it looks like a flag
+				"--" => {
+					flags
+						.words
+						.extend(args.by_ref().map(|word| word.to_string()));
+				}
+				flag if flag.starts_with("--") => return Err(format!("unknown flag: {}", flag)),
+				word => flags.words.push(word.to_string()),
+			}
+		}
+		Ok(flags)
+	}
+}
@@ -0,0 +1,373 @@
+/*
+ * Channels can be end-to-end encrypted with a 256-bit key, derived either from
+ * a passphrase shared by the channel's members (Argon2id, salted with the
+ * channel name) or from an X25519 exchange with a single peer (HKDF-SHA256).
+ * An encrypted message keeps its id, sender, channel and timestamps in the
+ * clear for routing, and carries everything else as an Encrypted payload:
+ * the whole message, as it's sent on the wire, sealed with XChaCha20-Poly1305,
+ * bound to those clear fields as associated data.
+*/
+use std::{collections::HashMap, fmt};
+
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD as base64_engine, Engine as _};
+use chacha20poly1305::{
+	aead::{Aead, AeadCore, KeyInit, Payload},
+	XChaCha20Poly1305, XNonce,
+};
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::{
+	attachment::AttachmentError,
+	message::{Message, MessageKind},
+	message_sender::MessageSendError,
+};
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 24;
+const KEY_CONTEXT: &str = "desktop-messenger channel key";
+
+pub type ChannelKey = [u8; KEY_SIZE];
+
+#[derive(Debug)]
+pub enum EncryptionError {
+	KeyDerivationError(String),
+	BadPublicKey(String),
+	EncryptionFailed,
+	DecryptionFailed,
+	MissingKey(String),
+}
+
+pub struct ChannelEncryption {
+	keys: HashMap<Box<str>, ChannelKey>,
+	// for deriving keys with peers; only lives as long as the process
+	exchange_secret: StaticSecret,
+}
+
+impl ChannelEncryption {
+	pub fn new() -> Self {
+		Self {
+			keys: HashMap::new(),
+			exchange_secret: StaticSecret::random_from_rng(OsRng),
+		}
+	}
+
+	pub fn public_key(&self) -> String {
+		base64_engine.encode(PublicKey::from(&self.exchange_secret).as_bytes())
+	}
+
+	pub fn is_encrypted(&self, channel: &str) -> bool {
+		self.keys.contains_key(channel)
+	}
+
+	pub fn set_passphrase(
+		&mut self,
+		channel: &str,
+		passphrase: &str,
+	) -> Result<(), EncryptionError> {
+		let salt = format!("{}:{}", KEY_CONTEXT, channel);
+		let key = passphrase_key(passphrase, salt.as_bytes())?;
+		self.keys.insert(channel.into(), key);
+		Ok(())
+	}
+
+	pub fn set_peer_key(
+		&mut self,
+		channel: &str,
+		peer_public_key: &str,
+	) -> Result<(), EncryptionError> {
+		let bad_key = || EncryptionError::BadPublicKey(peer_public_key.to_string());
+		let peer_bytes: [u8; 32] = base64_engine
+			.decode(peer_public_key)
+			.map_err(|_| bad_key())?
+			.try_into()
+			.map_err(|_| bad_key())?;
+
+		let shared = self
+			.exchange_secret
+			.diffie_hellman(&PublicKey::from(peer_bytes));
+		let mut key = [0u8; KEY_SIZE];
+		let info = format!("{}:{}", KEY_CONTEXT, channel);
+		Hkdf::<Sha256>::new(None, shared.as_bytes())
+			.expand(info.as_bytes(), &mut key)
+			.map_err(|e| EncryptionError::KeyDerivationError(e.to_string()))?;
+
+		self.keys.insert(channel.into(), key);
+		Ok(())
+	}
+
+	pub fn clear_key(&mut self, channel: &str) -> bool {
+		self.keys.remove(channel).is_some()
+	}
+
+	// messages in channels without a key are returned as they are
+	pub fn encrypt(&self, message: &Message) -> Result<Message, EncryptionError> {
+		let key = match self.keys.get(&message.channel) {
+			Some(key) => key,
+			None => return Ok(message.clone()),
+		};
+
+		// without the fields only kept locally, such as reactions
+		let plaintext = message.to_wire().into_bytes();
+		let payload = seal(key, &plaintext, &Self::associated_data(message))?;
+
+		let mut encrypted = message.clone();
+		encrypted.reply_to = None;
+		encrypted.reactions.clear();
+		encrypted.read_by.clear();
+		// the signature is checked on the decrypted message
+		encrypted.public_key = None;
+		encrypted.signature = None;
+		encrypted.kind = MessageKind::Encrypted {
+			payload: base64_engine.encode(payload).into(),
+		};
+		Ok(encrypted)
+	}
+
+	/*
+	 * returns the original message, marked as encrypted. Messages that weren't
+	 * encrypted are returned as they are
+	 */
+	pub fn decrypt(&self, message: &Message) -> Result<Message, EncryptionError> {
+		let payload = match &message.kind {
+			MessageKind::Encrypted { payload } => payload,
+			_ => return Ok(message.clone()),
+		};
+		let key = self.key(&message.channel)?;
+		let payload = base64_engine
+			.decode(payload.as_bytes())
+			.map_err(|_| EncryptionError::DecryptionFailed)?;
+
+		let aad = Self::associated_data(message);
+		let plaintext = open(key, &payload, &aad)?;
+
+		let mut decrypted = std::str::from_utf8(&plaintext)
+			.map_err(|_| EncryptionError::DecryptionFailed)
+			.and_then(|plaintext| {
+				Message::from_wire(plaintext).map_err(|_| EncryptionError::DecryptionFailed)
+			})?;
+		if Self::associated_data(&decrypted) != aad {
+			return Err(EncryptionError::DecryptionFailed);
+		}
+		decrypted.received_at = message.received_at;
+		decrypted.encrypted = true;
+		Ok(decrypted)
+	}
+
+	// for file transfers, which are sealed as a whole before being split into chunks
+	pub fn encrypt_file(
+		&self,
+		channel: &str,
+		file_name: &str,
+		data: &[u8],
+	) -> Result<Vec<u8>, EncryptionError> {
+		let aad = format!("{}|{}", channel, file_name);
+		seal(self.key(channel)?, data, &aad)
+	}
+
+	pub fn decrypt_file(
+		&self,
+		channel: &str,
+		file_name: &str,
+		data: &[u8],
+	) -> Result<Vec<u8>, EncryptionError> {
+		let aad = format!("{}|{}", channel, file_name);
+		open(self.key(channel)?, data, &aad)
+	}
+
+	fn key(&self, channel: &str) -> Result<&ChannelKey, EncryptionError> {
+		self.keys
+			.get(channel)
+			.ok_or_else(|| EncryptionError::MissingKey(channel.to_string()))
+	}
+
+	fn associated_data(message: &Message) -> String {
+		format!("{}|{}|{}", message.id, message.sender, message.channel)
+	}
+}
+
+pub fn passphrase_key(passphrase: &str, salt: &[u8]) -> Result<ChannelKey, EncryptionError> {
+	let mut key = [0u8; KEY_SIZE];
+	Argon2::default()
+		.hash_password_into(passphrase.as_bytes(), salt, &mut key)
+		.map_err(|e| EncryptionError::KeyDerivationError(e.to_string()))?;
+	Ok(key)
+}
+
+// returns the random nonce followed by the ciphertext
+pub fn seal(key: &ChannelKey, plaintext: &[u8], aad: &str) -> Result<Vec<u8>, EncryptionError> {
+	let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+	let ciphertext = XChaCha20Poly1305::new(key.into())
+		.encrypt(
+			&nonce,
+			Payload {
+				msg: plaintext,
+				aad: aad.as_bytes(),
+			},
+		)
+		.map_err(|_| EncryptionError::EncryptionFailed)?;
+
+	let mut sealed = nonce.to_vec();
+	sealed.extend(ciphertext);
+	Ok(sealed)
+}
+
+pub fn open(key: &ChannelKey, sealed: &[u8], aad: &str) -> Result<Vec<u8>, EncryptionError> {
+	if sealed.len() < NONCE_SIZE {
+		return Err(EncryptionError::DecryptionFailed);
+	}
+	let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+
+	XChaCha20Poly1305::new(key.into())
+		.decrypt(
+			XNonce::from_slice(nonce),
+			Payload {
+				msg: ciphertext,
+				aad: aad.as_bytes(),
+			},
+		)
+		.map_err(|_| EncryptionError::DecryptionFailed)
+}
+
+impl std::error::Error for EncryptionError {}
+
+impl fmt::Display for EncryptionError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::KeyDerivationError(e) => write!(f, "Key Derivation Error: {}", e),
+			Self::BadPublicKey(key) => write!(f, "Bad public key: {}", key),
+			Self::EncryptionFailed => write!(f, "Encryption failed"),
+			Self::DecryptionFailed => write!(f, "Decryption failed"),
+			Self::MissingKey(channel) => write!(f, "No key for encrypted channel: {}", channel),
+		}
+	}
+}
+
+impl From<EncryptionError> for MessageSendError {
+	fn from(error: EncryptionError) -> Self {
+		Self::EncryptionError(error.to_string())
+	}
+}
+
+impl From<EncryptionError> for AttachmentError {
+	fn from(error: EncryptionError) -> Self {
+		Self::EncryptionError(error.to_string())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// two peers sharing a key for the channel, which is faster than a passphrase
+	fn peers(channel: &str) -> (ChannelEncryption, ChannelEncryption) {
+		let (mut alice, mut bob) = (ChannelEncryption::new(), ChannelEncryption::new());
+		alice.set_peer_key(channel, &bob.public_key()).unwrap();
+		bob.set_peer_key(channel, &alice.public_key()).unwrap();
+		(alice, bob)
+	}
+
+	fn reply(sender: &str, channel: &str) -> Message {
+		let mut message = Message::text(sender, channel, "hello");
+		message.reply_to = Some(uuid::Uuid::new_v4());
+		message.sequence = Some(7);
+		message
+	}
+
+	fn tampered_payload(message: &mut Message) {
+		let MessageKind::Encrypted { payload } = &message.kind else {
+			panic!("not encrypted");
+		};
+		let mut payload = base64_engine.decode(payload.as_bytes()).unwrap();
+		*payload.last_mut().unwrap() ^= 1;
+		message.kind = MessageKind::Encrypted {
+			payload: base64_engine.encode(payload).into(),
+		};
+	}
+
+	#[test]
+	fn round_trip() {
+		let (alice, bob) = peers("dm");
+		let message = reply("alice", "dm");
+
+		let encrypted = alice.encrypt(&message).unwrap();
+		assert!(matches!(encrypted.kind, MessageKind::Encrypted { .. }));
+		assert_eq!(encrypted.reply_to, None);
+		assert!(!encrypted.to_wire().contains("hello"));
+
+		let decrypted = bob.decrypt(&encrypted).unwrap();
+		assert!(decrypted.encrypted);
+		assert_eq!(decrypted.id, message.id);
+		assert_eq!(decrypted.reply_to, message.reply_to);
+		assert_eq!(decrypted.sequence, message.sequence);
+		assert!(matches!(
+			decrypted.kind,
+			MessageKind::Text { ref contents, .. } if &**contents == "hello"
+		));
+	}
+
+	#[test]
+	fn unencrypted_channels_are_left_alone() {
+		let (alice, _) = peers("dm");
+		let message = Message::text("alice", "general", "hello");
+
+		let sent = alice.encrypt(&message).unwrap();
+		assert!(matches!(sent.kind, MessageKind::Text { .. }));
+		assert!(!alice.decrypt(&sent).unwrap().encrypted);
+	}
+
+	#[test]
+	fn tampered_ciphertext_fails() {
+		let (alice, bob) = peers("dm");
+		let mut encrypted = alice.encrypt(&reply("alice", "dm")).unwrap();
+		tampered_payload(&mut encrypted);
+
+		assert!(matches!(
+			bob.decrypt(&encrypted),
+			Err(EncryptionError::DecryptionFailed)
+		));
+	}
+
+	#[test]
+	fn changed_sender_fails() {
+		let (alice, bob) = peers("dm");
+		let mut encrypted = alice.encrypt(&reply("alice", "dm")).unwrap();
+		encrypted.sender = "mallory".into();
+
+		assert!(matches!(
+			bob.decrypt(&encrypted),
+			Err(EncryptionError::DecryptionFailed)
+		));
+	}
+
+	#[test]
+	fn other_keys_fail() {
+		let (alice, _) = peers("dm");
+		let (_, eve) = peers("dm");
+		let encrypted = alice.encrypt(&reply("alice", "dm")).unwrap();
+
+		assert!(matches!(
+			eve.decrypt(&encrypted),
+			Err(EncryptionError::DecryptionFailed)
+		));
+		assert!(matches!(
+			ChannelEncryption::new().decrypt(&encrypted),
+			Err(EncryptionError::MissingKey(_))
+		));
+	}
+
+	#[test]
+	fn files_round_trip_and_are_bound_to_their_name() {
+		let (alice, bob) = peers("dm");
+		let sealed = alice.encrypt_file("dm", "notes.txt", b"contents").unwrap();
+
+		assert_eq!(
+			bob.decrypt_file("dm", "notes.txt", &sealed).unwrap(),
+			b"contents"
+		);
+		assert!(bob.decrypt_file("dm", "other.txt", &sealed).is_err());
+	}
+}
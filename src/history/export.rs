@@ -0,0 +1,314 @@
+/*
+ * Transcripts of stored messages, for handing over outside the client:
+ * - JSON Lines, one envelope per line, which can be imported back
+ * - a plain text log
+ * - a self-contained HTML page
+ * Times are shown in UTC, so transcripts read the same wherever they're opened.
+*/
+use std::{fmt, str::FromStr};
+
+use chrono::{DateTime, NaiveDate};
+
+use super::HistoryError;
+use crate::message::{Message, MessageKind, Timestamp, Verification};
+
+const DAY_MILLISECONDS: Timestamp = 24 * 60 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExportFormat {
+	JsonLines,
+	Text,
+	Html,
+}
+
+// a channel (or all of them) and a time range, from inclusive and to exclusive
+#[derive(Debug, Default)]
+pub struct ExportRange {
+	pub channel: Option<Box<str>>,
+	pub from: Option<Timestamp>,
+	pub to: Option<Timestamp>,
+}
+
+impl ExportFormat {
+	pub fn export(&self, messages: &[Message]) -> String {
+		match self {
+			Self::JsonLines => messages
+				.iter()
+				.map(|message| serde_json::to_string(message).unwrap() + "\n")
+				.collect(),
+			Self::Text => messages
+				.iter()
+				.map(|message| format_line(message) + "\n")
+				.collect(),
+			Self::Html => html_page(messages),
+		}
+	}
+}
+
+impl ExportRange {
+	/*
+	 * parses "[channel] [from] [to]" as typed by users, with dates as
+	 * YYYY-MM-DD (to includes the whole day) and "*" standing for every channel
+	 */
+	pub fn parse(args: &[&str]) -> Option<Self> {
+		let mut args = args.iter().filter(|arg| !arg.is_empty());
+		let channel = match args.next() {
+			Some(&"*") | None => None,
+			Some(channel) => Some((*channel).into()),
+		};
+		let from = match args.next() {
+			Some(date) => Some(parse_date(date)?),
+			None => None,
+		};
+		let to = match args.next() {
+			Some(date) => Some(parse_date(date)? + DAY_MILLISECONDS),
+			None => None,
+		};
+
+		Some(Self { channel, from, to })
+	}
+}
+
+// returns the messages in JSON Lines, skipping empty lines
+pub fn parse_json_lines(text: &str) -> Result<Vec<Message>, HistoryError> {
+	text.lines()
+		.enumerate()
+		.filter(|(_, line)| !line.trim().is_empty())
+		.map(|(index, line)| {
+			serde_json::from_str(line)
+				.map_err(|e| HistoryError::StorageError(format!("line {}: {}", index + 1, e)))
+		})
+		.collect()
+}
+
+fn parse_date(date: &str) -> Option<Timestamp> {
+	let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+	let millis = date.and_hms_opt(0, 0, 0)?.and_utc().timestamp_millis();
+	millis.try_into().ok()
+}
+
+fn format_time(message: &Message) -> String {
+	message
+		.sent_at
+		.or(message.received_at)
+		.and_then(|time| DateTime::from_timestamp_millis(time as i64))
+		.map_or_else(
+			|| "unknown time".to_string(),
+			|time| time.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+		)
+}
+
+fn contents(message: &Message) -> String {
+	match &message.kind {
+		MessageKind::Text {
+			contents,
+			edited: false,
+		} => contents.to_string(),
+		MessageKind::Text {
+			contents,
+			edited: true,
+		} => format!("{} (edited)", contents),
+		MessageKind::Deleted => "(message deleted)".to_string(),
+		MessageKind::Encrypted { .. } => "(encrypted, could not decrypt)".to_string(),
+		kind => format!("<{} message>", kind.name()),
+	}
+}
+
+// what a transcript reader should know about the message beyond its contents
+fn notes(message: &Message) -> Vec<String> {
+	let mut notes = Vec::new();
+	if let Some(reply_to) = message.reply_to {
+		notes.push(format!("in reply to {}", reply_to));
+	}
+	for (emoji, senders) in &message.reactions {
+		let senders: Vec<&str> = senders.iter().map(|sender| &**sender).collect();
+		notes.push(format!("{} from {}", emoji, senders.join(", ")));
+	}
+	if !message.read_by.is_empty() {
+		let readers: Vec<&str> = message.read_by.iter().map(|reader| &**reader).collect();
+		notes.push(format!("read by {}", readers.join(", ")));
+	}
+	if message.encrypted {
+		notes.push("end-to-end encrypted".to_string());
+	}
+	notes.push(
+		match message.verification {
+			Verification::Unsigned => "unsigned",
+			Verification::BadSignature => "invalid signature",
+			Verification::KeyChanged => "signed with a changed key",
+			Verification::Trusted => "signed",
+			Verification::Verified => "signed, verified",
+		}
+		.to_string(),
+	);
+	notes
+}
+
+fn format_line(message: &Message) -> String {
+	format!(
+		"[{}] [{}] {} ({}): {} [{}]",
+		format_time(message),
+		message.channel,
+		message.sender,
+		message.id,
+		contents(message),
+		notes(message).join("; ")
+	)
+}
+
+fn html_page(messages: &[Message]) -> String {
+	let rows: String = messages
+		.iter()
+		.map(|message| {
+			format!(
+				"<tr id=\"{}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td class=\"notes\">{}</td></tr>\n",
+				message.id,
+				escape_html(&format_time(message)),
+				escape_html(&message.channel),
+				escape_html(&message.sender),
+				escape_html(&contents(message)),
+				escape_html(&notes(message).join("; "))
+			)
+		})
+		.collect();
+
+	format!(
+		"<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>Message transcript</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; width: 100%; }}
+th, td {{ border: 1px solid #ccc; padding: 4px 8px; text-align: left; vertical-align: top; }}
+th {{ background: #eee; }}
+td {{ white-space: pre-wrap; }}
+.notes {{ color: #666; font-size: smaller; }}
+</style>
+</head>
+<body>
+<h1>Message transcript</h1>
+<p>{} messages</p>
+<table>
+<tr><th>Time</th><th>Channel</th><th>Sender</th><th>Message</th><th>Notes</th></tr>
+{}</table>
+</body>
+</html>
+",
+		messages.len(),
+		rows
+	)
+}
+
+fn escape_html(text: &str) -> String {
+	text.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+		.replace('\'', "&#39;")
+}
+
+impl FromStr for ExportFormat {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"jsonl" => Ok(Self::JsonLines),
+			"text" | "txt" => Ok(Self::Text),
+			"html" => Ok(Self::Html),
+			other => Err(format!("unknown export format: {}", other)),
+		}
+	}
+}
+
+impl fmt::Display for ExportFormat {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::JsonLines => write!(f, "jsonl"),
+			Self::Text => write!(f, "text"),
+			Self::Html => write!(f, "html"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn message(sender: &str, contents: &str) -> Message {
+		let mut message = Message::text(sender, "general", contents);
+		// 2024-01-02 03:04:05 UTC
+		message.sent_at = Some(1_704_164_645_000);
+		message
+	}
+
+	#[test]
+	fn html_is_escaped() {
+		let message = message("<b>mallory</b>", "<script>alert('hi') & \"bye\"</script>");
+		let page = ExportFormat::Html.export(&[message]);
+
+		assert!(!page.contains("<script>"));
+		assert!(!page.contains("<b>"));
+		assert!(
+			page.contains("&lt;script&gt;alert(&#39;hi&#39;) &amp; &quot;bye&quot;&lt;/script&gt;")
+		);
+		assert!(page.contains("&lt;b&gt;mallory&lt;/b&gt;"));
+	}
+
+	#[test]
+	fn text_lines_show_time_and_notes() {
+		let mut message = message("alice", "hello");
+		message.reactions.insert("👍".into(), ["bob".into()].into());
+		let text = ExportFormat::Text.export(&[message.clone()]);
+
+		assert_eq!(
+			text,
+			format!(
+				"[2024-01-02 03:04:05 UTC] [general] alice ({}): hello [👍 from bob; unsigned]\n",
+				message.id
+			)
+		);
+	}
+
+	#[test]
+	fn json_lines_read_back() {
+		let messages = [message("alice", "one"), message("bob", "two\nlines")];
+		let exported = ExportFormat::JsonLines.export(&messages);
+		assert_eq!(exported.lines().count(), 2);
+
+		let imported = parse_json_lines(&format!("\n{}\n", exported)).unwrap();
+		assert_eq!(imported.len(), 2);
+		assert_eq!(imported[1].id, messages[1].id);
+		assert!(matches!(
+			&imported[1].kind,
+			MessageKind::Text { contents, .. } if &**contents == "two\nlines"
+		));
+	}
+
+	#[test]
+	fn bad_lines_are_reported_by_number() {
+		let exported = ExportFormat::JsonLines.export(&[message("alice", "one")]);
+		let error = parse_json_lines(&format!("{}not json\n", exported)).unwrap_err();
+		assert!(error.to_string().contains("line 2"));
+	}
+
+	#[test]
+	fn ranges_are_parsed() {
+		let range = ExportRange::parse(&["general", "2024-01-02", "2024-01-02"]).unwrap();
+		assert_eq!(range.channel.as_deref(), Some("general"));
+		assert_eq!(range.from, Some(1_704_153_600_000));
+		assert_eq!(range.to, Some(1_704_153_600_000 + DAY_MILLISECONDS));
+
+		let range = ExportRange::parse(&["*"]).unwrap();
+		assert!(range.channel.is_none() && range.from.is_none() && range.to.is_none());
+		assert!(ExportRange::parse(&["general", "yesterday"]).is_none());
+	}
+
+	#[test]
+	fn formats_are_parsed() {
+		assert!(matches!("jsonl".parse(), Ok(ExportFormat::JsonLines)));
+		assert!(matches!("txt".parse(), Ok(ExportFormat::Text)));
+		assert!("pdf".parse::<ExportFormat>().is_err());
+	}
+}
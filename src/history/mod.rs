@@ -0,0 +1,68 @@
+use std::fmt;
+
+use uuid::Uuid;
+
+use crate::message::{Message, Timestamp};
+use export::ExportRange;
+use retention::RetentionPolicy;
+
+#[derive(Debug)]
+pub enum HistoryError {
+	AmbiguousId(String),
+	StorageError(String),
+}
+
+pub trait HistoryStore {
+	// storing a message whose id is already known replaces the stored copy
+	fn store(&mut self, message: &Message) -> Result<(), HistoryError>;
+	fn get(&self, id: Uuid) -> Result<Option<Message>, HistoryError>;
+	// looks a message up by its full id, or by a prefix matching a single id
+	fn find(&self, id: &str) -> Result<Option<Message>, HistoryError>;
+	// the whole thread the message belongs to, from its root, ordered by time
+	fn thread(&self, id: Uuid) -> Result<Vec<Message>, HistoryError>;
+	// the channel's latest messages, ordered by time
+	fn channel_history(&self, channel: &str, limit: usize) -> Result<Vec<Message>, HistoryError>;
+	// the latest text messages containing the text, ordered by time
+	fn search(&self, text: &str, limit: usize) -> Result<Vec<Message>, HistoryError>;
+	// every message in the range, ordered by time
+	fn messages_in(&self, range: &ExportRange) -> Result<Vec<Message>, HistoryError>;
+	// every channel with messages in history
+	fn channels(&self) -> Result<Vec<Box<str>>, HistoryError>;
+	/*
+	 * deletes the channel's messages sent before the given time, and all but
+	 * its latest keep_latest messages. Returns how many were deleted
+	 */
+	fn purge(
+		&mut self,
+		channel: &str,
+		before: Option<Timestamp>,
+		keep_latest: Option<usize>,
+	) -> Result<usize, HistoryError>;
+	fn retention_policies(&self) -> Result<Vec<(Box<str>, RetentionPolicy)>, HistoryError>;
+	fn set_retention_policy(
+		&mut self,
+		channel: &str,
+		policy: RetentionPolicy,
+	) -> Result<(), HistoryError>;
+}
+
+impl std::error::Error for HistoryError {}
+
+impl fmt::Display for HistoryError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::AmbiguousId(id) => write!(f, "More than one message matches id: {}", id),
+			Self::StorageError(e) => write!(f, "History Storage Error: {}", e),
+		}
+	}
+}
+
+impl From<std::io::Error> for HistoryError {
+	fn from(error: std::io::Error) -> Self {
+		Self::StorageError(error.to_string())
+	}
+}
+
+pub mod export;
+pub mod retention;
+pub mod sqlite;
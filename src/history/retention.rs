@@ -0,0 +1,165 @@
+/*
+ * How long messages are kept in history, per channel: up to a maximum age
+ * and/or a maximum count, or not at all for disappearing channels, whose
+ * messages are only shown. Policies are written as comma separated terms,
+ * such as "30d,1000" (a month, and at most a thousand messages), "12h",
+ * "disappearing", or "keep" to keep everything.
+*/
+use std::{collections::HashMap, fmt, str::FromStr, time::Duration};
+
+pub const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+	pub max_age: Option<Duration>,
+	pub max_count: Option<usize>,
+	pub disappearing: bool,
+}
+
+// the policy set for each channel, and the one for all others
+#[derive(Default)]
+pub struct RetentionPolicies {
+	pub default: RetentionPolicy,
+	pub channels: HashMap<Box<str>, RetentionPolicy>,
+}
+
+impl RetentionPolicy {
+	pub fn keeps_everything(&self) -> bool {
+		*self == Self::default()
+	}
+}
+
+impl RetentionPolicies {
+	pub fn policy(&self, channel: &str) -> RetentionPolicy {
+		self.channels.get(channel).copied().unwrap_or(self.default)
+	}
+}
+
+impl FromStr for RetentionPolicy {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut policy = Self::default();
+
+		for term in s.split(',').map(str::trim).filter(|term| !term.is_empty()) {
+			if term == "keep" {
+				continue;
+			}
+			if term == "disappearing" {
+				policy.disappearing = true;
+				continue;
+			}
+
+			let bad_term = || format!("bad retention term: {}", term);
+			let unit = match term.chars().last() {
+				Some('d') => Some(24 * 60 * 60),
+				Some('h') => Some(60 * 60),
+				Some('m') => Some(60),
+				_ => None,
+			};
+			match unit {
+				Some(unit) => {
+					let amount: u64 = term[..term.len() - 1].parse().map_err(|_| bad_term())?;
+					// in range even once in milliseconds, as ages are compared to timestamps
+					let seconds = amount
+						.checked_mul(unit)
+						.filter(|seconds| seconds.checked_mul(1000).is_some())
+						.ok_or_else(|| format!("retention age too long: {}", term))?;
+					policy.max_age = Some(Duration::from_secs(seconds));
+				}
+				None => policy.max_count = Some(term.parse().map_err(|_| bad_term())?),
+			}
+		}
+
+		Ok(policy)
+	}
+}
+
+impl fmt::Display for RetentionPolicy {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let mut terms = Vec::new();
+		if self.disappearing {
+			terms.push("disappearing".to_string());
+		}
+		if let Some(max_age) = self.max_age {
+			let seconds = max_age.as_secs();
+			terms.push(match seconds {
+				s if s % (24 * 60 * 60) == 0 => format!("{}d", s / (24 * 60 * 60)),
+				s if s % (60 * 60) == 0 => format!("{}h", s / (60 * 60)),
+				s => format!("{}m", s / 60),
+			});
+		}
+		if let Some(max_count) = self.max_count {
+			terms.push(max_count.to_string());
+		}
+
+		match terms.is_empty() {
+			true => write!(f, "keep"),
+			false => write!(f, "{}", terms.join(",")),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const DAY: u64 = 24 * 60 * 60;
+
+	#[test]
+	fn policies_are_parsed() {
+		let policy: RetentionPolicy = "30d, 1000".parse().unwrap();
+		assert_eq!(policy.max_age, Some(Duration::from_secs(30 * DAY)));
+		assert_eq!(policy.max_count, Some(1000));
+		assert!(!policy.disappearing);
+
+		let policy: RetentionPolicy = "12h,disappearing".parse().unwrap();
+		assert_eq!(policy.max_age, Some(Duration::from_secs(12 * 60 * 60)));
+		assert!(policy.disappearing);
+
+		assert!("keep"
+			.parse::<RetentionPolicy>()
+			.unwrap()
+			.keeps_everything());
+		assert!("".parse::<RetentionPolicy>().unwrap().keeps_everything());
+	}
+
+	#[test]
+	fn bad_terms_are_rejected() {
+		for policy in ["forever", "30x", "d", "-1", "1.5d", "30d,soon"] {
+			assert!(policy.parse::<RetentionPolicy>().is_err(), "{}", policy);
+		}
+	}
+
+	#[test]
+	fn ages_too_long_for_timestamps_are_rejected() {
+		let days = u64::MAX / DAY;
+		assert!(format!("{}d", days).parse::<RetentionPolicy>().is_err());
+		assert!(format!("{}d", u64::MAX).parse::<RetentionPolicy>().is_err());
+		assert!("36500d".parse::<RetentionPolicy>().is_ok());
+	}
+
+	#[test]
+	fn policies_are_written_as_they_are_parsed() {
+		for policy in ["keep", "disappearing", "30d", "12h", "90m", "1000", "7d,50"] {
+			let parsed: RetentionPolicy = policy.parse().unwrap();
+			assert_eq!(parsed.to_string(), policy);
+		}
+		let parsed: RetentionPolicy = "48h".parse().unwrap();
+		assert_eq!(parsed.to_string(), "2d");
+	}
+
+	#[test]
+	fn channels_fall_back_to_the_default() {
+		let mut policies = RetentionPolicies {
+			default: "30d".parse().unwrap(),
+			..Default::default()
+		};
+		policies
+			.channels
+			.insert("secret".into(), "disappearing".parse().unwrap());
+
+		assert!(policies.policy("secret").disappearing);
+		assert_eq!(policies.policy("general"), policies.default);
+	}
+}
@@ -0,0 +1,458 @@
+/*
+ * Messages are stored as their JSON envelope, along with the columns they are
+ * looked up by, so that envelopes written by older versions keep decoding the
+ * same way they do from the network. The timestamp column is the local time
+ * (see Message::local_time) rather than the time the sender claims. Text
+ * contents are indexed separately for full-text search.
+ * The schema is changed only by appending to MIGRATIONS: the database records
+ * how many were applied (in user_version), and the rest run when it's opened.
+ * Given a key, the whole database file is encrypted with SQLCipher, and a
+ * database that was created unencrypted is encrypted when first opened so.
+*/
+use std::path::{Path, PathBuf};
+
+use rusqlite::{params, Connection, DatabaseName, OptionalExtension};
+use uuid::Uuid;
+
+use super::{export::ExportRange, retention::RetentionPolicy, HistoryError, HistoryStore};
+use crate::message::{Message, MessageKind, Timestamp};
+
+const MIGRATIONS: [&str; 3] = [
+	"
+	CREATE TABLE messages (
+		id TEXT PRIMARY KEY NOT NULL,
+		channel TEXT NOT NULL,
+		sender TEXT NOT NULL,
+		timestamp INTEGER,
+		reply_to TEXT,
+		envelope TEXT NOT NULL
+	);
+	CREATE INDEX messages_by_channel ON messages (channel, timestamp);
+	CREATE INDEX messages_by_reply_to ON messages (reply_to);
+	CREATE VIRTUAL TABLE messages_fts USING fts5 (id UNINDEXED, contents);
+	",
+	"
+	CREATE TABLE retention_policies (
+		channel TEXT PRIMARY KEY NOT NULL,
+		policy TEXT NOT NULL
+	);
+	",
+	"
+	UPDATE messages SET timestamp = COALESCE(json_extract(envelope, '$.received_at'), timestamp);
+	",
+];
+
+pub struct SqliteHistory {
+	connection: Connection,
+}
+
+impl SqliteHistory {
+	// an empty key leaves the database unencrypted
+	pub fn open(path: &Path, key: &str) -> Result<Self, HistoryError> {
+		let mut connection = Self::connect(path, key)?;
+		if !key.is_empty()
+			&& !Self::readable(&connection)
+			&& Self::readable(&Connection::open(path)?)
+		{
+			drop(connection);
+			Self::encrypt_existing(path, key)?;
+			connection = Self::connect(path, key)?;
+		}
+
+		// purged messages are overwritten, rather than left in free pages
+		connection.pragma_update(None, "secure_delete", true)?;
+		Self::migrate(&mut connection)?;
+		Ok(Self { connection })
+	}
+
+	fn connect(path: &Path, key: &str) -> Result<Connection, HistoryError> {
+		let connection = Connection::open(path)?;
+		if !key.is_empty() {
+			connection.pragma_update(None, "key", key)?;
+		}
+		Ok(connection)
+	}
+
+	fn readable(connection: &Connection) -> bool {
+		connection
+			.query_row("SELECT COUNT(*) FROM sqlite_master", [], |row| {
+				row.get::<_, i64>(0)
+			})
+			.is_ok()
+	}
+
+	fn encrypt_existing(path: &Path, key: &str) -> Result<(), HistoryError> {
+		let mut encrypted_path = PathBuf::from(path);
+		encrypted_path.set_extension("encrypted");
+		let _ = std::fs::remove_file(&encrypted_path);
+
+		let connection = Connection::open(path)?;
+		connection.execute(
+			"ATTACH DATABASE ?1 AS encrypted KEY ?2",
+			params![encrypted_path.to_string_lossy(), key],
+		)?;
+		connection.query_row("SELECT sqlcipher_export('encrypted')", [], |_| Ok(()))?;
+		let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+		connection.pragma_update(
+			Some(DatabaseName::Attached("encrypted")),
+			"user_version",
+			version,
+		)?;
+		connection.execute("DETACH DATABASE encrypted", [])?;
+		drop(connection);
+
+		std::fs::rename(&encrypted_path, path)?;
+		Ok(())
+	}
+
+	fn migrate(connection: &mut Connection) -> Result<(), HistoryError> {
+		let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+		if version > MIGRATIONS.len() {
+			return Err(HistoryError::StorageError(format!(
+				"history database is from a newer version (schema {})",
+				version
+			)));
+		}
+
+		for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+			let transaction = connection.transaction()?;
+			transaction.execute_batch(migration)?;
+			transaction.pragma_update(None, "user_version", index + 1)?;
+			transaction.commit()?;
+		}
+		Ok(())
+	}
+
+	// ids are stored without hyphens, so prefixes typed by users match directly
+	fn id_column(id: &Uuid) -> String {
+		id.simple().to_string()
+	}
+
+	fn query_messages(
+		&self,
+		sql: &str,
+		params: impl rusqlite::Params,
+	) -> Result<Vec<Message>, HistoryError> {
+		let mut statement = self.connection.prepare(sql)?;
+		let envelopes = statement.query_map(params, |row| row.get::<_, String>(0))?;
+
+		let mut messages = Vec::new();
+		for envelope in envelopes {
+			messages.push(serde_json::from_str(&envelope?)?);
+		}
+		Ok(messages)
+	}
+
+	fn root_of(&self, id: Uuid) -> Result<String, HistoryError> {
+		// bounded by the message count, in case of a reply cycle forged by a misbehaving client
+		let root = self.connection.query_row(
+			"WITH RECURSIVE ancestors (id, depth) AS (
+				SELECT ?1, 0
+				UNION
+				SELECT messages.reply_to, ancestors.depth + 1
+				FROM messages JOIN ancestors ON messages.id = ancestors.id
+				WHERE messages.reply_to IN (SELECT id FROM messages)
+					AND ancestors.depth < (SELECT COUNT(*) FROM messages)
+			)
+			SELECT id FROM ancestors ORDER BY depth DESC LIMIT 1",
+			params![Self::id_column(&id)],
+			|row| row.get(0),
+		)?;
+		Ok(root)
+	}
+}
+
+impl HistoryStore for SqliteHistory {
+	fn store(&mut self, message: &Message) -> Result<(), HistoryError> {
+		let id = Self::id_column(&message.id);
+		let transaction = self.connection.transaction()?;
+
+		transaction.execute(
+			"INSERT OR REPLACE INTO messages (id, channel, sender, timestamp, reply_to, envelope)
+			VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+			params![
+				id,
+				&*message.channel,
+				&*message.sender,
+				message.local_time(),
+				message.reply_to.as_ref().map(Self::id_column),
+				serde_json::to_string(message)?,
+			],
+		)?;
+
+		transaction.execute("DELETE FROM messages_fts WHERE id = ?1", params![id])?;
+		if let MessageKind::Text { contents, .. } = &message.kind {
+			transaction.execute(
+				"INSERT INTO messages_fts (id, contents) VALUES (?1, ?2)",
+				params![id, &**contents],
+			)?;
+		}
+
+		transaction.commit()?;
+		Ok(())
+	}
+
+	fn get(&self, id: Uuid) -> Result<Option<Message>, HistoryError> {
+		let envelope: Option<String> = self
+			.connection
+			.query_row(
+				"SELECT envelope FROM messages WHERE id = ?1",
+				params![Self::id_column(&id)],
+				|row| row.get(0),
+			)
+			.optional()?;
+
+		match envelope {
+			Some(envelope) => Ok(Some(serde_json::from_str(&envelope)?)),
+			None => Ok(None),
+		}
+	}
+
+	fn find(&self, id: &str) -> Result<Option<Message>, HistoryError> {
+		if let Ok(id) = Uuid::parse_str(id) {
+			return self.get(id);
+		}
+
+		let prefix = id.to_lowercase().replace(['%', '_'], "");
+		let mut matches = self.query_messages(
+			"SELECT envelope FROM messages WHERE id LIKE ?1 || '%' LIMIT 2",
+			params![prefix],
+		)?;
+
+		match matches.len() {
+			0 => Ok(None),
+			1 => Ok(matches.pop()),
+			_ => Err(HistoryError::AmbiguousId(id.to_string())),
+		}
+	}
+
+	fn thread(&self, id: Uuid) -> Result<Vec<Message>, HistoryError> {
+		let root = self.root_of(id)?;
+		self.query_messages(
+			"WITH RECURSIVE thread (id) AS (
+				SELECT ?1
+				UNION
+				SELECT messages.id FROM messages JOIN thread ON messages.reply_to = thread.id
+			)
+			SELECT envelope FROM messages WHERE id IN thread ORDER BY timestamp",
+			params![root],
+		)
+	}
+
+	fn channel_history(&self, channel: &str, limit: usize) -> Result<Vec<Message>, HistoryError> {
+		let mut messages = self.query_messages(
+			"SELECT envelope FROM messages WHERE channel = ?1 ORDER BY timestamp DESC LIMIT ?2",
+			params![channel, limit],
+		)?;
+		messages.reverse();
+		Ok(messages)
+	}
+
+	fn search(&self, text: &str, limit: usize) -> Result<Vec<Message>, HistoryError> {
+		// searched as a phrase, so user input can't be taken for query syntax
+		let phrase = format!("\"{}\"", text.replace('"', "\"\""));
+		let mut messages = self.query_messages(
+			"SELECT messages.envelope
+			FROM messages_fts JOIN messages ON messages.id = messages_fts.id
+			WHERE messages_fts MATCH ?1
+			ORDER BY messages.timestamp DESC LIMIT ?2",
+			params![phrase, limit],
+		)?;
+		messages.reverse();
+		Ok(messages)
+	}
+
+	fn channels(&self) -> Result<Vec<Box<str>>, HistoryError> {
+		let mut statement = self
+			.connection
+			.prepare("SELECT DISTINCT channel FROM messages ORDER BY channel")?;
+		let channels = statement.query_map([], |row| row.get::<_, String>(0))?;
+		Ok(channels
+			.map(|channel| channel.map(Box::from))
+			.collect::<Result<_, _>>()?)
+	}
+
+	fn purge(
+		&mut self,
+		channel: &str,
+		before: Option<Timestamp>,
+		keep_latest: Option<usize>,
+	) -> Result<usize, HistoryError> {
+		let transaction = self.connection.transaction()?;
+		let purged = transaction.execute(
+			"DELETE FROM messages WHERE channel = ?1 AND (
+				(?2 IS NOT NULL AND timestamp < ?2)
+				OR (?3 IS NOT NULL AND id NOT IN (
+					SELECT id FROM messages WHERE channel = ?1 ORDER BY timestamp DESC LIMIT ?3
+				))
+			)",
+			params![channel, before, keep_latest],
+		)?;
+		if purged > 0 {
+			transaction.execute(
+				"DELETE FROM messages_fts WHERE id NOT IN (SELECT id FROM messages)",
+				[],
+			)?;
+		}
+		transaction.commit()?;
+		Ok(purged)
+	}
+
+	fn retention_policies(&self) -> Result<Vec<(Box<str>, RetentionPolicy)>, HistoryError> {
+		let mut statement = self
+			.connection
+			.prepare("SELECT channel, policy FROM retention_policies")?;
+		let rows = statement.query_map([], |row| {
+			Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+		})?;
+
+		let mut policies = Vec::new();
+		for row in rows {
+			let (channel, policy) = row?;
+			let policy = policy.parse().map_err(HistoryError::StorageError)?;
+			policies.push((channel.into(), policy));
+		}
+		Ok(policies)
+	}
+
+	fn set_retention_policy(
+		&mut self,
+		channel: &str,
+		policy: RetentionPolicy,
+	) -> Result<(), HistoryError> {
+		self.connection.execute(
+			"INSERT OR REPLACE INTO retention_policies (channel, policy) VALUES (?1, ?2)",
+			params![channel, policy.to_string()],
+		)?;
+		Ok(())
+	}
+
+	fn messages_in(&self, range: &ExportRange) -> Result<Vec<Message>, HistoryError> {
+		self.query_messages(
+			"SELECT envelope FROM messages
+			WHERE (?1 IS NULL OR channel = ?1)
+				AND (?2 IS NULL OR timestamp >= ?2)
+				AND (?3 IS NULL OR timestamp < ?3)
+			ORDER BY timestamp",
+			params![range.channel.as_deref(), range.from, range.to],
+		)
+	}
+}
+
+impl From<rusqlite::Error> for HistoryError {
+	fn from(error: rusqlite::Error) -> Self {
+		Self::StorageError(error.to_string())
+	}
+}
+
+impl From<serde_json::Error> for HistoryError {
+	fn from(error: serde_json::Error) -> Self {
+		Self::StorageError(error.to_string())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// a database file of its own, removed when dropped
+	struct TestDatabase {
+		path: PathBuf,
+	}
+
+	impl TestDatabase {
+		fn new() -> Self {
+			let path = std::env::temp_dir().join(format!("history-test-{}.db", Uuid::new_v4()));
+			Self { path }
+		}
+
+		fn open(&self, key: &str) -> Result<SqliteHistory, HistoryError> {
+			SqliteHistory::open(&self.path, key)
+		}
+	}
+
+	impl Drop for TestDatabase {
+		fn drop(&mut self) {
+			std::fs::remove_file(&self.path).unwrap_or(());
+		}
+	}
+
+	// received at the given time, while the sender claims another
+	fn message(contents: &str, received_at: Timestamp) -> Message {
+		let mut message = Message::text("alice", "general", contents);
+		message.sent_at = Some(1);
+		message.received_at = Some(received_at);
+		message
+	}
+
+	fn contents(messages: &[Message]) -> Vec<String> {
+		messages
+			.iter()
+			.map(|message| match &message.kind {
+				MessageKind::Text { contents, .. } => contents.to_string(),
+				kind => kind.name().to_string(),
+			})
+			.collect()
+	}
+
+	#[test]
+	fn messages_are_ordered_and_purged_by_local_time() {
+		let database = TestDatabase::new();
+		let mut history = database.open("").unwrap();
+		for (contents, received_at) in [("third", 3000), ("first", 1000), ("second", 2000)] {
+			history.store(&message(contents, received_at)).unwrap();
+		}
+
+		let messages = history.channel_history("general", 10).unwrap();
+		assert_eq!(contents(&messages), ["first", "second", "third"]);
+
+		assert_eq!(history.purge("general", Some(2000), None).unwrap(), 1);
+		assert_eq!(history.purge("general", None, Some(1)).unwrap(), 1);
+		let messages = history.channel_history("general", 10).unwrap();
+		assert_eq!(contents(&messages), ["third"]);
+		// purged messages can't be found by search either
+		assert!(history.search("second", 10).unwrap().is_empty());
+	}
+
+	#[test]
+	fn retention_policies_are_kept() {
+		let database = TestDatabase::new();
+		let mut history = database.open("").unwrap();
+		let policy: RetentionPolicy = "7d,50".parse().unwrap();
+		history.set_retention_policy("general", policy).unwrap();
+
+		let history = database.open("").unwrap();
+		assert_eq!(
+			history.retention_policies().unwrap(),
+			[("general".into(), policy)]
+		);
+	}
+
+	#[test]
+	fn keyed_databases_are_encrypted() {
+		let database = TestDatabase::new();
+		let mut history = database.open("key").unwrap();
+		history.store(&message("hello", 1000)).unwrap();
+		drop(history);
+
+		let file = std::fs::read(&database.path).unwrap();
+		assert!(!file.windows(5).any(|window| window == b"hello"));
+		assert!(database.open("").is_err());
+		assert!(database.open("other key").is_err());
+		let history = database.open("key").unwrap();
+		assert_eq!(history.channel_history("general", 10).unwrap().len(), 1);
+	}
+
+	#[test]
+	fn unencrypted_databases_are_encrypted_when_opened_with_a_key() {
+		let database = TestDatabase::new();
+		let mut history = database.open("").unwrap();
+		history.store(&message("hello", 1000)).unwrap();
+		drop(history);
+
+		let history = database.open("key").unwrap();
+		assert_eq!(history.channel_history("general", 10).unwrap().len(), 1);
+		drop(history);
+		assert!(database.open("").is_err());
+	}
+}
@@ -0,0 +1,78 @@
+use std::path::Path;
+
+use base64::{engine::general_purpose::STANDARD as base64_engine, Engine as _};
+use ed25519_dalek::SigningKey;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::encryption::{self, EncryptionError};
+
+use super::IdentityError;
+
+const SALT_SIZE: usize = 16;
+
+/*
+ * an identity key as stored on disk, or exported to move it between machines:
+ * the secret key sealed with a key derived from a passphrase, bound to the
+ * public key it belongs to
+ */
+#[derive(Serialize, Deserialize)]
+pub struct KeyFile {
+	public_key: Box<str>,
+	salt: Box<str>,
+	sealed_key: Box<str>,
+}
+
+impl KeyFile {
+	pub fn seal(signing_key: &SigningKey, passphrase: &str) -> Result<Self, IdentityError> {
+		let public_key = base64_engine.encode(signing_key.verifying_key().as_bytes());
+		let mut salt = [0u8; SALT_SIZE];
+		OsRng.fill_bytes(&mut salt);
+
+		let key = encryption::passphrase_key(passphrase, &salt)?;
+		let sealed_key = encryption::seal(&key, &signing_key.to_bytes(), &public_key)?;
+
+		Ok(Self {
+			public_key: public_key.into(),
+			salt: base64_engine.encode(salt).into(),
+			sealed_key: base64_engine.encode(sealed_key).into(),
+		})
+	}
+
+	pub fn open(&self, passphrase: &str) -> Result<SigningKey, IdentityError> {
+		let bad_file = || IdentityError::BadKey("malformed key file".to_string());
+		let salt = base64_engine
+			.decode(self.salt.as_bytes())
+			.map_err(|_| bad_file())?;
+		let sealed_key = base64_engine
+			.decode(self.sealed_key.as_bytes())
+			.map_err(|_| bad_file())?;
+
+		let key = encryption::passphrase_key(passphrase, &salt)?;
+		let secret: [u8; 32] = encryption::open(&key, &sealed_key, &self.public_key)
+			.map_err(|_| IdentityError::BadPassphrase)?
+			.try_into()
+			.map_err(|_| bad_file())?;
+
+		let signing_key = SigningKey::from_bytes(&secret);
+		if base64_engine.encode(signing_key.verifying_key().as_bytes()) != *self.public_key {
+			return Err(bad_file());
+		}
+		Ok(signing_key)
+	}
+
+	pub fn read(path: &Path) -> Result<Self, IdentityError> {
+		Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+	}
+
+	pub fn write(&self, path: &Path) -> Result<(), IdentityError> {
+		std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+		Ok(())
+	}
+}
+
+impl From<EncryptionError> for IdentityError {
+	fn from(error: EncryptionError) -> Self {
+		Self::BadKey(error.to_string())
+	}
+}
@@ -0,0 +1,444 @@
+/*
+ * Every client holds an Ed25519 identity key, kept in the data directory
+ * sealed with a passphrase, and signs everything it sends with it. Received
+ * messages are checked against the TrustStore, which trusts the first key
+ * seen for each sender until the user verifies its fingerprint, and flags any
+ * later change of key.
+*/
+use std::{
+	fmt,
+	path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD as base64_engine, Engine as _};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand_core::OsRng;
+
+use crate::{
+	attachment::{sha256_hex, FileChunk},
+	message::{Message, Verification},
+};
+
+use key_file::KeyFile;
+use trust_store::{TrustStore, TrustUpdate};
+
+const IDENTITY_FILE: &str = "identity.key";
+
+#[derive(Debug)]
+pub enum IdentityError {
+	StorageError(String),
+	BadKey(String),
+	BadPassphrase,
+	UnknownContact(String),
+	FingerprintMismatch(String),
+}
+
+pub struct Keyring {
+	key_path: PathBuf,
+	// kept to re-seal the identity key when another one is imported
+	passphrase: Box<str>,
+	signing_key: SigningKey,
+	trust_store: TrustStore,
+}
+
+// key management, available both as UI commands and as command line subcommands
+#[derive(Debug)]
+pub enum IdentityCommand {
+	ShowFingerprint,
+	ExportIdentity {
+		path: Box<str>,
+		passphrase: Box<str>,
+	},
+	ImportIdentity {
+		path: Box<str>,
+		passphrase: Box<str>,
+	},
+	ListContacts,
+	VerifyContact {
+		sender: Box<str>,
+		fingerprint: Box<str>,
+	},
+	RevokeContact(Box<str>),
+}
+
+// groups the start of the key's hash, for users to compare out of band
+pub fn fingerprint(public_key: &str) -> String {
+	let hash = sha256_hex(public_key.as_bytes());
+	hash.as_bytes()[..32]
+		.chunks(4)
+		.map(|group| String::from_utf8_lossy(group).into_owned())
+		.collect::<Vec<_>>()
+		.join(" ")
+}
+
+impl Keyring {
+	// whether there's an identity key yet, or open will generate one
+	pub fn exists(data_directory: &str) -> bool {
+		Path::new(data_directory).join(IDENTITY_FILE).exists()
+	}
+
+	// loads the identity key, generating one on first run, and the trust store
+	pub fn open(data_directory: &str, passphrase: &str) -> Result<Self, IdentityError> {
+		let data_directory = PathBuf::from(data_directory);
+		std::fs::create_dir_all(&data_directory)?;
+
+		let key_path = data_directory.join(IDENTITY_FILE);
+		let signing_key = match key_path.exists() {
+			true => KeyFile::read(&key_path)?.open(passphrase)?,
+			false => {
+				let signing_key = SigningKey::generate(&mut OsRng);
+				KeyFile::seal(&signing_key, passphrase)?.write(&key_path)?;
+				eprintln!("Generated a new identity key in {}", key_path.display());
+				signing_key
+			}
+		};
+
+		Ok(Self {
+			key_path,
+			passphrase: passphrase.into(),
+			signing_key,
+			trust_store: TrustStore::open(&data_directory)?,
+		})
+	}
+
+	// returns what to show the user
+	pub fn execute(&mut self, command: IdentityCommand) -> Result<String, IdentityError> {
+		match command {
+			IdentityCommand::ShowFingerprint => {
+				Ok(format!("Fingerprint: {}", fingerprint(&self.public_key())))
+			}
+			IdentityCommand::ExportIdentity { path, passphrase } => {
+				KeyFile::seal(&self.signing_key, &passphrase)?.write(Path::new(&*path))?;
+				Ok(format!("Exported identity to {}", path))
+			}
+			IdentityCommand::ImportIdentity { path, passphrase } => {
+				let signing_key = KeyFile::read(Path::new(&*path))?.open(&passphrase)?;
+				KeyFile::seal(&signing_key, &self.passphrase)?.write(&self.key_path)?;
+				self.signing_key = signing_key;
+				Ok(format!(
+					"Imported identity, fingerprint: {}",
+					fingerprint(&self.public_key())
+				))
+			}
+			IdentityCommand::ListContacts => {
+				let contacts: Vec<String> = self
+					.trust_store
+					.contacts()
+					.map(|(sender, trusted)| {
+						let status = match trusted.verified {
+							true => "verified",
+							false => "trusted on first use",
+						};
+						format!(
+							"{}: {} ({})",
+							sender,
+							fingerprint(&trusted.public_key),
+							status
+						)
+					})
+					.collect();
+				match contacts.is_empty() {
+					true => Ok("No known contacts".to_string()),
+					false => Ok(contacts.join("\n")),
+				}
+			}
+			IdentityCommand::VerifyContact {
+				sender,
+				fingerprint,
+			} => {
+				self.trust_store.verify(&sender, &fingerprint)?;
+				Ok(format!("Verified the key of {}", sender))
+			}
+			IdentityCommand::RevokeContact(sender) => match self.trust_store.revoke(&sender)? {
+				true => Ok(format!("Revoked the key of {}", sender)),
+				false => Err(IdentityError::UnknownContact(sender.to_string())),
+			},
+		}
+	}
+
+	pub fn public_key(&self) -> String {
+		base64_engine.encode(self.signing_key.verifying_key().as_bytes())
+	}
+
+	pub fn sign(&self, message: &mut Message) {
+		message.public_key = Some(self.public_key().into());
+		message.signature = Some(self.signature(&message.signed_bytes()));
+		message.verification = Verification::Verified;
+	}
+
+	pub fn sign_file_chunk(&self, chunk: &mut FileChunk) {
+		chunk.public_key = Some(self.public_key().into());
+		chunk.signature = Some(self.signature(&chunk.signed_bytes()));
+	}
+
+	/*
+	 * checks the signature, and the key against the one trusted for the sender.
+	 * Fails only when a newly trusted key couldn't be saved, in which case it
+	 * is still trusted until exiting
+	 */
+	pub fn verify(&mut self, message: &Message) -> Result<Verification, IdentityError> {
+		let signed = message.signed_bytes();
+		self.check(
+			&message.sender,
+			&message.public_key,
+			&message.signature,
+			&signed,
+		)
+	}
+
+	pub fn verify_file_chunk(&mut self, chunk: &FileChunk) -> Result<Verification, IdentityError> {
+		let signed = chunk.signed_bytes();
+		self.check(&chunk.sender, &chunk.public_key, &chunk.signature, &signed)
+	}
+
+	// whether the sender is known to sign, so anything unsigned from them is spoofed
+	pub fn has_key_for(&self, sender: &str) -> bool {
+		self.trust_store.contains(sender)
+	}
+
+	fn signature(&self, signed: &[u8]) -> Box<str> {
+		let signature = self.signing_key.sign(signed);
+		base64_engine.encode(signature.to_bytes()).into()
+	}
+
+	fn check(
+		&mut self,
+		sender: &str,
+		public_key: &Option<Box<str>>,
+		signature: &Option<Box<str>>,
+		signed: &[u8],
+	) -> Result<Verification, IdentityError> {
+		let (public_key, signature) = match (public_key, signature) {
+			(Some(public_key), Some(signature)) => (public_key, signature),
+			_ => return Ok(Verification::Unsigned),
+		};
+
+		if !Self::signature_valid(signed, public_key, signature) {
+			return Ok(Verification::BadSignature);
+		}
+
+		Ok(match self.trust_store.observe(sender, public_key)? {
+			TrustUpdate::Verified => Verification::Verified,
+			TrustUpdate::Trusted => Verification::Trusted,
+			TrustUpdate::KeyChanged => Verification::KeyChanged,
+		})
+	}
+
+	fn signature_valid(signed: &[u8], public_key: &str, signature: &str) -> bool {
+		let public_key = base64_engine
+			.decode(public_key.as_bytes())
+			.ok()
+			.and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+			.and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok());
+		let signature = base64_engine
+			.decode(signature.as_bytes())
+			.ok()
+			.and_then(|bytes| Signature::from_slice(&bytes).ok());
+
+		match (public_key, signature) {
+			(Some(public_key), Some(signature)) => public_key.verify(signed, &signature).is_ok(),
+			_ => false,
+		}
+	}
+}
+
+impl IdentityCommand {
+	pub fn parse(command: &str, args: &[&str]) -> Option<Self> {
+		// the last argument may contain spaces, such as in fingerprints & passphrases
+		let rest = |from: usize| {
+			args.get(from..)
+				.map(|rest| rest.join(" "))
+				.filter(|rest| !rest.is_empty())
+				.map(Box::from)
+		};
+
+		match command {
+			"fingerprint" => Some(Self::ShowFingerprint),
+			"export_identity" => Some(Self::ExportIdentity {
+				path: (*args.first()?).into(),
+				passphrase: rest(1)?,
+			}),
+			"import_identity" => Some(Self::ImportIdentity {
+				path: (*args.first()?).into(),
+				passphrase: rest(1)?,
+			}),
+			"contacts" => Some(Self::ListContacts),
+			"verify" => Some(Self::VerifyContact {
+				sender: (*args.first()?).into(),
+				fingerprint: rest(1)?,
+			}),
+			"revoke" => Some(Self::RevokeContact((*args.first()?).into())),
+			_ => None,
+		}
+	}
+}
+
+impl std::error::Error for IdentityError {}
+
+impl fmt::Display for IdentityError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::StorageError(e) => write!(f, "Identity Storage Error: {}", e),
+			Self::BadKey(e) => write!(f, "Bad key: {}", e),
+			Self::BadPassphrase => write!(f, "Wrong passphrase for identity key"),
+			Self::UnknownContact(sender) => write!(f, "No key known for: {}", sender),
+			Self::FingerprintMismatch(sender) => {
+				write!(f, "Fingerprint doesn't match the key of: {}", sender)
+			}
+		}
+	}
+}
+
+impl From<std::io::Error> for IdentityError {
+	fn from(error: std::io::Error) -> Self {
+		Self::StorageError(error.to_string())
+	}
+}
+
+impl From<serde_json::Error> for IdentityError {
+	fn from(error: serde_json::Error) -> Self {
+		Self::StorageError(error.to_string())
+	}
+}
+
+pub mod key_file;
+pub mod trust_store;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::message::MessageKind;
+
+	// a keyring in a data directory of its own, removed when dropped
+	struct TestKeyring {
+		directory: PathBuf,
+		keyring: Keyring,
+	}
+
+	impl TestKeyring {
+		fn new() -> Self {
+			let directory =
+				std::env::temp_dir().join(format!("identity-test-{}", uuid::Uuid::new_v4()));
+			let keyring = Keyring::open(directory.to_str().unwrap(), "passphrase").unwrap();
+			Self { directory, keyring }
+		}
+	}
+
+	impl Drop for TestKeyring {
+		fn drop(&mut self) {
+			std::fs::remove_dir_all(&self.directory).unwrap_or(());
+		}
+	}
+
+	fn signed(keyring: &Keyring, sender: &str, contents: &str) -> Message {
+		let mut message = Message::text(sender, "general", contents);
+		keyring.sign(&mut message);
+		message
+	}
+
+	#[test]
+	fn first_key_is_trusted_then_verified() {
+		let alice = TestKeyring::new();
+		let mut bob = TestKeyring::new();
+
+		let message = signed(&alice.keyring, "alice", "hello");
+		assert!(!bob.keyring.has_key_for("alice"));
+		assert_eq!(bob.keyring.verify(&message).unwrap(), Verification::Trusted);
+		assert!(bob.keyring.has_key_for("alice"));
+
+		let fingerprint = fingerprint(&alice.keyring.public_key());
+		bob.keyring
+			.execute(IdentityCommand::VerifyContact {
+				sender: "alice".into(),
+				fingerprint: fingerprint.into(),
+			})
+			.unwrap();
+		let message = signed(&alice.keyring, "alice", "again");
+		assert_eq!(
+			bob.keyring.verify(&message).unwrap(),
+			Verification::Verified
+		);
+	}
+
+	#[test]
+	fn tampered_message_has_bad_signature() {
+		let alice = TestKeyring::new();
+		let mut bob = TestKeyring::new();
+
+		let mut message = signed(&alice.keyring, "alice", "hello");
+		message.kind = MessageKind::Text {
+			contents: "goodbye".into(),
+			edited: false,
+		};
+		assert_eq!(
+			bob.keyring.verify(&message).unwrap(),
+			Verification::BadSignature
+		);
+		// a bad signature doesn't make the key trusted
+		assert!(!bob.keyring.has_key_for("alice"));
+	}
+
+	#[test]
+	fn another_key_for_a_known_sender_is_flagged() {
+		let alice = TestKeyring::new();
+		let mallory = TestKeyring::new();
+		let mut bob = TestKeyring::new();
+
+		bob.keyring
+			.verify(&signed(&alice.keyring, "alice", "hello"))
+			.unwrap();
+		let spoofed = signed(&mallory.keyring, "alice", "send me the keys");
+		assert_eq!(
+			bob.keyring.verify(&spoofed).unwrap(),
+			Verification::KeyChanged
+		);
+	}
+
+	#[test]
+	fn unsigned_messages_are_reported_as_such() {
+		let mut bob = TestKeyring::new();
+		let message = Message::text("alice", "general", "hello");
+		assert_eq!(
+			bob.keyring.verify(&message).unwrap(),
+			Verification::Unsigned
+		);
+	}
+
+	#[test]
+	fn file_chunks_are_checked_like_messages() {
+		let alice = TestKeyring::new();
+		let mallory = TestKeyring::new();
+		let mut bob = TestKeyring::new();
+
+		let mut chunks =
+			FileChunk::split_file("alice", "general", "a.txt", b"data", false).unwrap();
+		alice.keyring.sign_file_chunk(&mut chunks[0]);
+		assert_eq!(
+			bob.keyring.verify_file_chunk(&chunks[0]).unwrap(),
+			Verification::Trusted
+		);
+
+		let mut renamed = chunks[0].clone();
+		renamed.file_name = "b.txt".into();
+		assert_eq!(
+			bob.keyring.verify_file_chunk(&renamed).unwrap(),
+			Verification::BadSignature
+		);
+
+		mallory.keyring.sign_file_chunk(&mut chunks[0]);
+		assert_eq!(
+			bob.keyring.verify_file_chunk(&chunks[0]).unwrap(),
+			Verification::KeyChanged
+		);
+	}
+
+	#[test]
+	fn wrong_passphrase_is_rejected() {
+		let alice = TestKeyring::new();
+		let directory = alice.directory.to_str().unwrap();
+		assert!(matches!(
+			Keyring::open(directory, "not the passphrase"),
+			Err(IdentityError::BadPassphrase)
+		));
+	}
+}
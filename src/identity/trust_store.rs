@@ -0,0 +1,104 @@
+use std::{
+	collections::BTreeMap,
+	path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{fingerprint, IdentityError};
+
+const TRUST_STORE_FILE: &str = "trusted_keys.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrustedKey {
+	pub public_key: Box<str>,
+	// whether the user compared the fingerprint, rather than trusting it on first use
+	pub verified: bool,
+}
+
+pub enum TrustUpdate {
+	Trusted,
+	Verified,
+	KeyChanged,
+}
+
+// the key trusted for each sender name, saved as JSON in the data directory
+pub struct TrustStore {
+	path: PathBuf,
+	keys: BTreeMap<Box<str>, TrustedKey>,
+}
+
+impl TrustStore {
+	pub fn open(data_directory: &Path) -> Result<Self, IdentityError> {
+		let path = data_directory.join(TRUST_STORE_FILE);
+		let keys = match path.exists() {
+			true => serde_json::from_str(&std::fs::read_to_string(&path)?)?,
+			false => BTreeMap::new(),
+		};
+
+		Ok(Self { path, keys })
+	}
+
+	// trusts the sender's key if it is the first seen for them
+	pub fn observe(
+		&mut self,
+		sender: &str,
+		public_key: &str,
+	) -> Result<TrustUpdate, IdentityError> {
+		match self.keys.get(sender) {
+			Some(trusted) if *trusted.public_key != *public_key => Ok(TrustUpdate::KeyChanged),
+			Some(trusted) if trusted.verified => Ok(TrustUpdate::Verified),
+			Some(_) => Ok(TrustUpdate::Trusted),
+			None => {
+				let trusted = TrustedKey {
+					public_key: public_key.into(),
+					verified: false,
+				};
+				self.keys.insert(sender.into(), trusted);
+				self.save()?;
+				Ok(TrustUpdate::Trusted)
+			}
+		}
+	}
+
+	pub fn verify(
+		&mut self,
+		sender: &str,
+		expected_fingerprint: &str,
+	) -> Result<(), IdentityError> {
+		let trusted = self
+			.keys
+			.get_mut(sender)
+			.ok_or_else(|| IdentityError::UnknownContact(sender.to_string()))?;
+
+		let normalize = |s: &str| s.split_whitespace().collect::<String>().to_lowercase();
+		if normalize(&fingerprint(&trusted.public_key)) != normalize(expected_fingerprint) {
+			return Err(IdentityError::FingerprintMismatch(sender.to_string()));
+		}
+
+		trusted.verified = true;
+		self.save()
+	}
+
+	pub fn contains(&self, sender: &str) -> bool {
+		self.keys.contains_key(sender)
+	}
+
+	pub fn contacts(&self) -> impl Iterator<Item = (&Box<str>, &TrustedKey)> {
+		self.keys.iter()
+	}
+
+	// forgets the sender's key, so the next one seen for them is trusted again
+	pub fn revoke(&mut self, sender: &str) -> Result<bool, IdentityError> {
+		let revoked = self.keys.remove(sender).is_some();
+		if revoked {
+			self.save()?;
+		}
+		Ok(revoked)
+	}
+
+	fn save(&self) -> Result<(), IdentityError> {
+		std::fs::write(&self.path, serde_json::to_string_pretty(&self.keys)?)?;
+		Ok(())
+	}
+}
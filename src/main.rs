@@ -1,33 +1,282 @@
+mod attachment;
 mod authenticator;
+mod backfill;
+mod chunking;
+mod cli;
+mod encryption;
+mod history;
+mod identity;
 mod message;
 mod message_receiver;
 mod message_sender;
 mod messenger;
+mod presence;
+mod sequence;
 mod settings;
+mod subscriptions;
 mod task_queue;
 mod ui_connector;
+mod unread;
 
-use std::sync::Arc;
+use std::{io::IsTerminal, path::Path, process::ExitCode, sync::Arc};
 
+use attachment::local_directory::LocalDirectoryBlobStore;
 use authenticator::Authenticator;
+use backfill::http_backfill_provider::HttpBackfillProvider;
+use history::sqlite::SqliteHistory;
+use identity::{IdentityCommand, Keyring};
 use message_receiver::appsync_message_receiver::AppSyncMessageReceiver;
 use message_sender::appsync_message_sender::AppSyncMessageSender;
+use sequence::SequenceCounter;
 use settings::Settings;
+use subscriptions::Subscriptions;
 
 use crate::authenticator::appsync_api_authenticator::AppSyncAPIAuthenticator;
 use crate::messenger::Messenger;
+#[cfg(unix)]
+use crate::ui_connector::daemon::DaemonUI;
+use crate::ui_connector::fan_out::FanOutUI;
+use crate::ui_connector::json_lines::JsonLinesUI;
 use crate::ui_connector::simplified::SimplifiedUI;
+use crate::ui_connector::terminal::TerminalUI;
+use crate::ui_connector::transcript::TranscriptUI;
+use crate::ui_connector::web::WebUI;
+use crate::ui_connector::{UIConnector, UIKind};
+
+const HISTORY_FILE: &str = "history.db";
+const HISTORY_SERVICE_ADDRESS: &str = "127.0.0.1:8090";
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
+	// the stand-in history service runs on its own, without any settings
+	let args: Vec<String> = std::env::args().skip(1).collect();
+	let args: Vec<&str> = args.iter().map(String::as_str).collect();
+	if args.first() == Some(&"history_service") {
+		let address = args.get(1).copied().unwrap_or(HISTORY_SERVICE_ADDRESS);
+		if let Err(err) = backfill::service::serve(address).await {
+			println!("error running history service: {}", err);
+			return ExitCode::FAILURE;
+		}
+		return ExitCode::SUCCESS;
+	}
+
 	let settings = Settings::from_env_file(".env.local");
-	match settings {
-		Ok(settings) => run_client(settings).await,
-		Err(err) => println!("error reading settings: {}", err),
+	let settings = match settings {
+		Ok(settings) => settings,
+		Err(err) => {
+			println!("error reading settings: {}", err);
+			return ExitCode::FAILURE;
+		}
+	};
+
+	// these don't need the identity
+	match args.split_first() {
+		Some((&"check", [])) => return cli::check(&settings).await,
+		Some((&"config", args)) => return cli::config(&settings, args),
+		Some((&"channels", args)) => return cli::channels(&settings, args),
+		// the daemon's client only needs to know where to find it
+		#[cfg(unix)]
+		Some((&"ctl", args)) => {
+			let res = match args.split_first() {
+				Some((method, params)) => {
+					ui_connector::daemon::ctl(&settings.DATA_DIRECTORY, method, params).await
+				}
+				None => return cli::usage_error("usage: ctl <method> [key=value | key:=json]..."),
+			};
+			// on stderr, as stdout is for results
+			if let Err(err) = res {
+				eprintln!("error: {}", err);
+				return ExitCode::FAILURE;
+			}
+			return ExitCode::SUCCESS;
+		}
+		_ => (),
+	}
+
+	let passphrase = match identity_passphrase(&settings) {
+		Ok(passphrase) => passphrase,
+		Err(err) => {
+			println!("error reading identity passphrase: {}", err);
+			return ExitCode::FAILURE;
+		}
+	};
+	let keyring = Keyring::open(&settings.DATA_DIRECTORY, &passphrase);
+	let mut keyring = match keyring {
+		Ok(keyring) => keyring,
+		Err(err) => {
+			println!("error opening identity: {}", err);
+			return ExitCode::FAILURE;
+		}
+	};
+
+	// key management commands can be run without starting the client
+	match args.split_first() {
+		None => {
+			let mut ui_connector = FanOutUI::new();
+			for kind in settings.UI.iter() {
+				ui_connector = match kind {
+					UIKind::Simplified => ui_connector.with(SimplifiedUI::new(
+						&settings.DATA_DIRECTORY,
+						&settings.NICKNAME,
+					)),
+					UIKind::Terminal => ui_connector.with(TerminalUI::new(&settings.NICKNAME)),
+					UIKind::JsonLines => ui_connector.with(JsonLinesUI::new(&settings.NICKNAME)),
+					UIKind::Web => ui_connector.with(
+						WebUI::new(
+							&settings.WEB_ADDRESS,
+							&settings.NICKNAME,
+							&settings.WEB_PAGE_DIRECTORY,
+						)
+						.with_shared_terminal(settings.UI.iter().any(UIKind::draws_on_terminal)),
+					),
+				};
+			}
+			let ui_connector = with_transcript(&settings, ui_connector);
+			run_client(settings, keyring, passphrase, ui_connector).await
+		}
+		// headless, for clients to attach to
+		#[cfg(unix)]
+		Some((&"daemon", [])) => {
+			let ui_connector =
+				FanOutUI::new().with(DaemonUI::new(&settings.DATA_DIRECTORY, &settings.NICKNAME));
+			let ui_connector = with_transcript(&settings, ui_connector);
+			run_client(settings, keyring, passphrase, ui_connector).await
+		}
+		Some((&"send", args)) => cli::send(&settings, &keyring, args).await,
+		Some((&"listen", args)) => cli::listen(&settings, &mut keyring, args).await,
+		Some((command, args)) => match IdentityCommand::parse(command, args) {
+			Some(command) => match keyring.execute(command) {
+				Ok(output) => {
+					println!("{}", output);
+					ExitCode::SUCCESS
+				}
+				Err(err) => {
+					println!("error: {}", err);
+					ExitCode::FAILURE
+				}
+			},
+			None => cli::usage_error(&format!("unknown command: {}", command)),
+		},
+	}
+}
+
+fn with_transcript(settings: &Settings, ui_connector: FanOutUI) -> FanOutUI {
+	match settings.TRANSCRIPT_FILE.is_empty() {
+		true => ui_connector,
+		false => ui_connector.with(TranscriptUI::new(&settings.TRANSCRIPT_FILE)),
+	}
+}
+
+/*
+ * asked for only on a terminal, as otherwise stdin belongs to a script or a
+ * frontend, such as the JSON Lines UI's, whose first line isn't a passphrase
+ */
+fn identity_passphrase(settings: &Settings) -> Result<String, String> {
+	if !settings.IDENTITY_PASSPHRASE.is_empty() {
+		return Ok(settings.IDENTITY_PASSPHRASE.to_string());
+	}
+	if !std::io::stdin().is_terminal() {
+		return Err("IDENTITY_PASSPHRASE is needed when stdin isn't a terminal".to_string());
+	}
+
+	let passphrase = read_hidden("Identity passphrase: ").map_err(|e| e.to_string())?;
+	if passphrase.is_empty() {
+		return Err("the passphrase can't be empty".to_string());
+	}
+	// a mistyped passphrase would seal a new key for good
+	if !Keyring::exists(&settings.DATA_DIRECTORY) {
+		let repeated = read_hidden("Repeat the passphrase: ").map_err(|e| e.to_string())?;
+		if repeated != passphrase {
+			return Err("the passphrases don't match".to_string());
+		}
 	}
+	Ok(passphrase)
 }
 
-async fn run_client(settings: Settings) {
+// a line from the terminal without echoing it, prompted on stderr to keep stdout for the UI
+fn read_hidden(prompt: &str) -> std::io::Result<String> {
+	eprint!("{}", prompt);
+	let stdin = std::io::stdin();
+
+	#[cfg(unix)]
+	let echoing = {
+		use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg};
+		let echoing = tcgetattr(&stdin)?;
+		let mut hidden = echoing.clone();
+		hidden.local_flags.remove(LocalFlags::ECHO);
+		tcsetattr(&stdin, SetArg::TCSANOW, &hidden)?;
+		echoing
+	};
+
+	let mut line = String::new();
+	let res = stdin.read_line(&mut line);
+
+	#[cfg(unix)]
+	nix::sys::termios::tcsetattr(&stdin, nix::sys::termios::SetArg::TCSANOW, &echoing)?;
+	// the newline typed wasn't echoed
+	eprintln!();
+
+	res?;
+	Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+// the output of the key command, such as a secret manager's, or else the identity passphrase
+fn history_key(settings: &Settings, passphrase: String) -> Result<String, String> {
+	if settings.HISTORY_KEY_COMMAND.is_empty() {
+		return Ok(passphrase);
+	}
+
+	let output = std::process::Command::new("sh")
+		.arg("-c")
+		.arg(&**settings.HISTORY_KEY_COMMAND)
+		.output()
+		.map_err(|e| e.to_string())?;
+	if !output.status.success() {
+		return Err(format!("history key command failed: {}", output.status));
+	}
+	Ok(String::from_utf8_lossy(&output.stdout)
+		.trim_end_matches(['\r', '\n'])
+		.to_string())
+}
+
+async fn run_client<TUI: UIConnector>(
+	settings: Settings,
+	keyring: Keyring,
+	passphrase: String,
+	ui_connector: TUI,
+) -> ExitCode {
+	let history_key = match history_key(&settings, passphrase) {
+		Ok(history_key) => history_key,
+		Err(err) => {
+			println!("error getting history key: {}", err);
+			return ExitCode::FAILURE;
+		}
+	};
+	let history_path = Path::new(&**settings.DATA_DIRECTORY).join(HISTORY_FILE);
+	let history = match SqliteHistory::open(&history_path, &history_key) {
+		Ok(history) => history,
+		Err(err) => {
+			println!("error opening history: {}", err);
+			return ExitCode::FAILURE;
+		}
+	};
+
+	let sequences = match SequenceCounter::open(&settings.DATA_DIRECTORY) {
+		Ok(sequences) => sequences,
+		Err(err) => {
+			println!("error reading message sequences: {}", err);
+			return ExitCode::FAILURE;
+		}
+	};
+
+	let subscriptions = match Subscriptions::open(&settings.DATA_DIRECTORY) {
+		Ok(subscriptions) => subscriptions,
+		Err(err) => {
+			println!("error reading subscriptions: {}", err);
+			return ExitCode::FAILURE;
+		}
+	};
+
 	let auth = Arc::new(AppSyncAPIAuthenticator::new(
 		&settings.APPSYNC_HTTP_DOMAIN,
 		&settings.APPSYNC_API_KEY,
@@ -45,8 +294,22 @@ async fn run_client(settings: Settings) {
 			&settings.APPSYNC_PUBLISH_URL,
 			Arc::clone(&auth) as Arc<dyn Authenticator + Send + Sync>,
 		),
-		SimplifiedUI::new(),
-	);
+		ui_connector,
+		LocalDirectoryBlobStore::new(&settings.DOWNLOAD_DIRECTORY),
+		history,
+		keyring,
+	)
+	.with_read_receipts(settings.SEND_READ_RECEIPTS)
+	.with_retention(settings.HISTORY_RETENTION)
+	.with_sequences(sequences)
+	.with_subscriptions(subscriptions);
+	if !settings.BACKFILL_URL.is_empty() {
+		messenger =
+			messenger.with_backfill(Arc::new(HttpBackfillProvider::new(&settings.BACKFILL_URL)));
+	}
 
-	messenger.start().await;
+	match messenger.start().await {
+		true => ExitCode::SUCCESS,
+		false => ExitCode::FAILURE,
+	}
 }
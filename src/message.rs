@@ -1,8 +1,532 @@
+/*
+ * Message is the envelope of everything published to a channel. On the wire it
+ * is a flat JSON object with a "kind" tag, going through WireMessage so that:
+ * - events from older clients, which only carry sender, channel & contents,
+ *   still decode (as text messages with schema version 0, and an id derived
+ *   from their contents, so the same event always gets the same one)
+ * - unknown fields are ignored, and unknown kinds decode as MessageKind::Unknown
+ *   rather than failing, so newer clients can add both
+ * - what is only known locally (received_at, reactions, read_by, encrypted and
+ *   verification) is neither sent nor taken from peers
+ * Locally, in history and to UIs, messages go through LocalMessage instead,
+ * which is the wire format along with those fields.
+*/
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	time::{SystemTime, UNIX_EPOCH},
+};
+
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const SCHEMA_VERSION: u32 = 1;
+// for the ids of legacy events, which come without one
+const LEGACY_ID_NAMESPACE: Uuid = Uuid::from_u128(0x6d1c_2f4e_8b0a_4c53_9e71_3a5d_0f2b_7c19);
+
+// milliseconds since the UNIX epoch
+pub type Timestamp = u64;
+// senders who reacted to a message, by emoji
+pub type Reactions = BTreeMap<Box<str>, BTreeSet<Box<str>>>;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "LocalMessage", into = "LocalMessage")]
 pub struct Message {
+	pub id: Uuid,
+	pub schema_version: u32,
 	pub sender: Box<str>,
 	pub channel: Box<str>,
-	pub contents: Box<str>,
+	pub sent_at: Option<Timestamp>,
+	pub received_at: Option<Timestamp>,
+	pub reply_to: Option<Uuid>,
+	// counts the sender's messages in the channel, so receivers can tell when they missed some
+	pub sequence: Option<u64>,
+	pub kind: MessageKind,
+	// collected locally from Reaction and ReadReceipt messages targeting this one
+	pub reactions: Reactions,
+	pub read_by: BTreeSet<Box<str>>,
+	// whether the message was decrypted on receipt
+	pub encrypted: bool,
+	// the sender's identity key and signature, see identity/mod.rs
+	pub public_key: Option<Box<str>>,
+	pub signature: Option<Box<str>>,
+	// checked locally on receipt
+	pub verification: Verification,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verification {
+	#[default]
+	Unsigned,
+	BadSignature,
+	// signed by a different key than the one trusted for the sender
+	KeyChanged,
+	// signed by the key first seen for the sender, which the user didn't verify yet
+	Trusted,
+	Verified,
+}
+
+#[derive(Debug, Clone)]
+pub enum MessageKind {
+	Text {
+		contents: Box<str>,
+		edited: bool,
+	},
+	Edit {
+		target: Uuid,
+		contents: Box<str>,
+	},
+	Delete {
+		target: Uuid,
+	},
+	Reaction {
+		target: Uuid,
+		emoji: Box<str>,
+		removed: bool,
+	},
+	ReadReceipt {
+		target: Uuid,
+	},
+	// sealed by an end-to-end encrypted channel, see encryption.rs
+	Encrypted {
+		payload: Box<str>,
+	},
+	// ephemeral, never stored in history
+	Presence {
+		online: bool,
+	},
+	Typing {
+		active: bool,
+	},
+	// left in history in place of a deleted message
+	Deleted,
+	Unknown {
+		kind: Box<str>,
+	},
+}
+
+// a message as published, see Message::to_wire and Message::from_wire
+#[derive(Serialize, Deserialize)]
+pub struct WireMessage {
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	id: Option<Uuid>,
+	#[serde(default)]
+	schema_version: u32,
+	sender: Box<str>,
+	channel: Box<str>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	sent_at: Option<Timestamp>,
+	#[serde(skip)]
+	received_at: Option<Timestamp>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	reply_to: Option<Uuid>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	sequence: Option<u64>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	kind: Option<Box<str>>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	contents: Option<Box<str>>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	target: Option<Uuid>,
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	edited: bool,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	emoji: Option<Box<str>>,
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	removed: bool,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	active: Option<bool>,
+	#[serde(skip)]
+	reactions: Reactions,
+	#[serde(skip)]
+	read_by: BTreeSet<Box<str>>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	payload: Option<Box<str>>,
+	#[serde(skip)]
+	encrypted: bool,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	public_key: Option<Box<str>>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	signature: Option<Box<str>>,
+	#[serde(skip)]
+	verification: Option<Verification>,
+}
+
+// the wire format along with what is known locally, as kept in history
+#[derive(Serialize, Deserialize)]
+struct LocalMessage {
+	#[serde(flatten)]
+	wire: WireMessage,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	received_at: Option<Timestamp>,
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	reactions: Reactions,
+	#[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+	read_by: BTreeSet<Box<str>>,
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	encrypted: bool,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	verification: Option<Verification>,
+}
+
+pub fn timestamp_now() -> Timestamp {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|duration| duration.as_millis() as Timestamp)
+		.unwrap_or(0)
+}
+
+impl Message {
+	pub fn new(sender: &str, channel: &str, kind: MessageKind) -> Self {
+		Self {
+			id: Uuid::new_v4(),
+			schema_version: SCHEMA_VERSION,
+			sender: sender.into(),
+			channel: channel.into(),
+			sent_at: Some(timestamp_now()),
+			received_at: None,
+			reply_to: None,
+			sequence: None,
+			kind,
+			reactions: Reactions::new(),
+			read_by: BTreeSet::new(),
+			encrypted: false,
+			public_key: None,
+			signature: None,
+			verification: Verification::Unsigned,
+		}
+	}
+
+	pub fn text(sender: &str, channel: &str, contents: &str) -> Self {
+		Self::new(
+			sender,
+			channel,
+			MessageKind::Text {
+				contents: contents.into(),
+				edited: false,
+			},
+		)
+	}
+
+	/*
+	 * when the message reached us, or was sent by us, by our own clock. Unlike
+	 * sent_at, which the sender sets, this can be relied on for ordering and
+	 * purging
+	 */
+	pub fn local_time(&self) -> Option<Timestamp> {
+		self.received_at.or(self.sent_at)
+	}
+
+	pub fn short_id(&self) -> String {
+		short_id(&self.id)
+	}
+
+	// the event published for the message, without what is only known locally
+	pub fn to_wire(&self) -> String {
+		serde_json::to_string(&WireMessage::from(self.clone())).unwrap()
+	}
+
+	/*
+	 * what the signature covers: a fixed list of fields in a fixed order, so
+	 * that a receiver rebuilds the same bytes whatever fields it doesn't know.
+	 * Fields added later aren't covered, and neither are those of kinds the
+	 * receiver doesn't know, which then fail to verify
+	 */
+	pub fn signed_bytes(&self) -> Vec<u8> {
+		let wire = WireMessage::from(self.clone());
+		serde_json::to_vec(&(
+			wire.id,
+			wire.schema_version,
+			&wire.sender,
+			&wire.channel,
+			wire.sent_at,
+			wire.reply_to,
+			wire.sequence,
+			&wire.kind,
+			&wire.contents,
+			wire.target,
+			wire.edited,
+			&wire.emoji,
+			wire.removed,
+			wire.active,
+			&wire.payload,
+			&wire.public_key,
+		))
+		.unwrap()
+	}
+
+	pub fn from_wire(event: &str) -> Result<Self, String> {
+		let wire: WireMessage = serde_json::from_str(event).map_err(|e| e.to_string())?;
+		Self::try_from(wire)
+	}
+
+	/*
+	 * applies an edit, delete, reaction or read receipt to the message it
+	 * targets. Returns false if the change isn't allowed or wouldn't change
+	 * anything: anyone may react to or read a text message, but only its sender
+	 * may edit or delete it
+	 */
+	pub fn apply(&mut self, change: &Message) -> bool {
+		if change.kind.target() != Some(self.id) {
+			return false;
+		}
+
+		if let MessageKind::Reaction { emoji, removed, .. } = &change.kind {
+			return match self.kind {
+				MessageKind::Text { .. } => self.apply_reaction(&change.sender, emoji, *removed),
+				_ => false,
+			};
+		}
+
+		if let MessageKind::ReadReceipt { .. } = change.kind {
+			return match self.kind {
+				MessageKind::Text { .. } if change.sender != self.sender => {
+					self.read_by.insert(change.sender.clone())
+				}
+				_ => false,
+			};
+		}
+
+		if change.sender != self.sender {
+			return false;
+		}
+
+		let new_kind = match (&self.kind, &change.kind) {
+			(MessageKind::Text { contents, edited }, MessageKind::Edit { contents: new, .. }) => {
+				if *edited && contents == new {
+					return false;
+				}
+				MessageKind::Text {
+					contents: new.clone(),
+					edited: true,
+				}
+			}
+			(MessageKind::Text { .. }, MessageKind::Delete { .. }) => {
+				self.reactions.clear();
+				MessageKind::Deleted
+			}
+			_ => return false,
+		};
+
+		self.kind = new_kind;
+		true
+	}
+
+	fn apply_reaction(&mut self, sender: &str, emoji: &str, removed: bool) -> bool {
+		if removed {
+			let senders = match self.reactions.get_mut(emoji) {
+				Some(senders) => senders,
+				None => return false,
+			};
+			let changed = senders.remove(sender);
+			if senders.is_empty() {
+				self.reactions.remove(emoji);
+			}
+			changed
+		} else {
+			self.reactions
+				.entry(emoji.into())
+				.or_default()
+				.insert(sender.into())
+		}
+	}
+}
+
+// enough of the id for users to tell messages apart and refer to them
+pub fn short_id(id: &Uuid) -> String {
+	id.simple().to_string()[..8].to_string()
+}
+
+impl MessageKind {
+	pub fn name(&self) -> &str {
+		match self {
+			Self::Text { .. } => "text",
+			Self::Edit { .. } => "edit",
+			Self::Delete { .. } => "delete",
+			Self::Reaction { .. } => "reaction",
+			Self::ReadReceipt { .. } => "read",
+			Self::Encrypted { .. } => "encrypted",
+			Self::Presence { .. } => "presence",
+			Self::Typing { .. } => "typing",
+			Self::Deleted => "deleted",
+			Self::Unknown { kind } => kind,
+		}
+	}
+
+	pub fn is_ephemeral(&self) -> bool {
+		matches!(self, Self::Presence { .. } | Self::Typing { .. })
+	}
+
+	// the id of the message an edit, delete, reaction or read receipt refers to
+	pub fn target(&self) -> Option<Uuid> {
+		match self {
+			Self::Edit { target, .. }
+			| Self::Delete { target }
+			| Self::Reaction { target, .. }
+			| Self::ReadReceipt { target } => Some(*target),
+			_ => None,
+		}
+	}
+}
+
+impl TryFrom<WireMessage> for Message {
+	type Error = String;
+
+	fn try_from(wire: WireMessage) -> Result<Self, Self::Error> {
+		let missing = |field: &str| format!("missing field `{}`", field);
+		let id = wire.id.unwrap_or_else(|| wire.legacy_id());
+
+		let kind = match wire.kind.as_deref().unwrap_or("text") {
+			"text" => MessageKind::Text {
+				contents: wire.contents.ok_or_else(|| missing("contents"))?,
+				edited: wire.edited,
+			},
+			"edit" => MessageKind::Edit {
+				target: wire.target.ok_or_else(|| missing("target"))?,
+				contents: wire.contents.ok_or_else(|| missing("contents"))?,
+			},
+			"delete" => MessageKind::Delete {
+				target: wire.target.ok_or_else(|| missing("target"))?,
+			},
+			"reaction" => MessageKind::Reaction {
+				target: wire.target.ok_or_else(|| missing("target"))?,
+				emoji: wire.emoji.ok_or_else(|| missing("emoji"))?,
+				removed: wire.removed,
+			},
+			"presence" => MessageKind::Presence {
+				online: wire.active.unwrap_or(true),
+			},
+			"typing" => MessageKind::Typing {
+				active: wire.active.unwrap_or(true),
+			},
+			"read" => MessageKind::ReadReceipt {
+				target: wire.target.ok_or_else(|| missing("target"))?,
+			},
+			"encrypted" => MessageKind::Encrypted {
+				payload: wire.payload.ok_or_else(|| missing("payload"))?,
+			},
+			"deleted" => MessageKind::Deleted,
+			other => MessageKind::Unknown { kind: other.into() },
+		};
+
+		Ok(Self {
+			id,
+			schema_version: wire.schema_version,
+			sender: wire.sender,
+			channel: wire.channel,
+			sent_at: wire.sent_at,
+			received_at: wire.received_at,
+			reply_to: wire.reply_to,
+			sequence: wire.sequence,
+			kind,
+			reactions: wire.reactions,
+			read_by: wire.read_by,
+			encrypted: wire.encrypted,
+			public_key: wire.public_key,
+			signature: wire.signature,
+			verification: wire.verification.unwrap_or_default(),
+		})
+	}
+}
+
+impl WireMessage {
+	// the same for every delivery of the same legacy event
+	fn legacy_id(&self) -> Uuid {
+		let name = serde_json::to_vec(&(
+			&self.sender,
+			&self.channel,
+			self.sent_at,
+			&self.kind,
+			&self.contents,
+		))
+		.unwrap();
+		Uuid::new_v5(&LEGACY_ID_NAMESPACE, &name)
+	}
+}
+
+impl TryFrom<LocalMessage> for Message {
+	type Error = String;
+
+	fn try_from(local: LocalMessage) -> Result<Self, Self::Error> {
+		let mut message = Self::try_from(local.wire)?;
+		message.received_at = local.received_at;
+		message.reactions = local.reactions;
+		message.read_by = local.read_by;
+		message.encrypted = local.encrypted;
+		message.verification = local.verification.unwrap_or_default();
+		Ok(message)
+	}
+}
+
+impl From<Message> for LocalMessage {
+	fn from(message: Message) -> Self {
+		LocalMessage {
+			received_at: message.received_at,
+			reactions: message.reactions.clone(),
+			read_by: message.read_by.clone(),
+			encrypted: message.encrypted,
+			verification: Some(message.verification)
+				.filter(|verification| *verification != Verification::Unsigned),
+			wire: WireMessage::from(message),
+		}
+	}
+}
+
+impl From<Message> for WireMessage {
+	fn from(message: Message) -> Self {
+		let mut wire = WireMessage {
+			id: Some(message.id),
+			schema_version: message.schema_version,
+			sender: message.sender,
+			channel: message.channel,
+			sent_at: message.sent_at,
+			received_at: None,
+			reply_to: message.reply_to,
+			sequence: message.sequence,
+			kind: Some(message.kind.name().into()),
+			contents: None,
+			target: None,
+			edited: false,
+			emoji: None,
+			removed: false,
+			active: None,
+			reactions: Reactions::new(),
+			read_by: BTreeSet::new(),
+			payload: None,
+			encrypted: false,
+			public_key: message.public_key,
+			signature: message.signature,
+			verification: None,
+		};
+
+		match message.kind {
+			MessageKind::Text { contents, edited } => {
+				wire.contents = Some(contents);
+				wire.edited = edited;
+			}
+			MessageKind::Edit { target, contents } => {
+				wire.target = Some(target);
+				wire.contents = Some(contents);
+			}
+			MessageKind::Delete { target } | MessageKind::ReadReceipt { target } => {
+				wire.target = Some(target)
+			}
+			MessageKind::Reaction {
+				target,
+				emoji,
+				removed,
+			} => {
+				wire.target = Some(target);
+				wire.emoji = Some(emoji);
+				wire.removed = removed;
+			}
+			MessageKind::Presence { online: active } | MessageKind::Typing { active } => {
+				wire.active = Some(active)
+			}
+			MessageKind::Encrypted { payload } => wire.payload = Some(payload),
+			MessageKind::Deleted | MessageKind::Unknown { .. } => (),
+		}
+
+		wire
+	}
 }
@@ -7,6 +7,7 @@ use std::{
 use async_trait::async_trait;
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD as base64_engine, Engine as _};
 use futures_util::{sink::SinkExt, stream::SplitSink, StreamExt};
+use serde::Deserialize;
 use serde_json::{json, Value};
 use tokio::{net::TcpStream, sync::Mutex, task::JoinHandle};
 use tokio_tungstenite::{
@@ -21,8 +22,10 @@ use tokio_tungstenite::{
 use uuid::Uuid;
 
 use crate::{
+	attachment::FileChunk,
 	authenticator::Authenticator,
-	message::Message,
+	chunking::{ChunkAssembler, EventChunk},
+	message::{timestamp_now, Message, WireMessage},
 	task_queue::{TaskData, TaskQueue},
 };
 
@@ -32,12 +35,21 @@ type Auth = dyn Authenticator + Send + Sync;
 type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
 type WebSocketHolder = Arc<Mutex<SplitSink<WebSocket, WebSocketMessage>>>;
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum IncomingEvent {
+	File(FileChunk),
+	Chunk(EventChunk),
+	Message(WireMessage),
+}
+
 pub struct AppSyncOpenConnection {
 	websocket_send: WebSocketHolder,
 	authenticator: Arc<Auth>,
 	channels_ids: HashMap<Box<str>, Box<str>>,
 	task_queue: TaskQueue,
 	listener_handle: JoinHandle<()>,
+	chunk_assembler: ChunkAssembler,
 }
 
 pub struct AppSyncMessageReceiver {
@@ -46,6 +58,7 @@ pub struct AppSyncMessageReceiver {
 }
 
 impl AppSyncMessageReceiver {
+	#[allow(clippy::borrowed_box)]
 	pub fn new(uri: &Box<str>, authenticator: Arc<Auth>) -> Self {
 		Self {
 			authenticator,
@@ -96,6 +109,7 @@ impl MessageReceiver for AppSyncMessageReceiver {
 }
 
 impl AppSyncOpenConnection {
+	#[allow(clippy::new_ret_no_self, clippy::partialeq_to_none)]
 	pub async fn new(
 		task_queue: TaskQueue,
 		websocket: WebSocket,
@@ -109,16 +123,22 @@ impl AppSyncOpenConnection {
 			channels_ids: HashMap::new(),
 			task_queue,
 			listener_handle: tokio::task::spawn(async {}),
+			chunk_assembler: ChunkAssembler::new(),
 		}));
 		let weak_copy = Arc::downgrade(&result);
+		let mut task_queue = result.lock().await.task_queue.clone();
 
 		result.lock().await.listener_handle = tokio::task::spawn(async move {
 			while let Some(received_message) = receive.next().await {
 				match received_message {
 					Ok(message_base) => {
 						if let WebSocketMessage::Text(message) = message_base {
-							let result =
-								Self::handle_incoming_message(weak_copy.clone(), message).await;
+							let result = Self::handle_incoming_message(
+								weak_copy.clone(),
+								&mut task_queue,
+								message,
+							)
+							.await;
 							if result == None {
 								break;
 							}
@@ -127,6 +147,7 @@ impl AppSyncOpenConnection {
 					Err(_) => break,
 				}
 			}
+			task_queue.push(TaskData::ConnectionLost).await;
 		});
 
 		result
@@ -140,36 +161,102 @@ impl AppSyncOpenConnection {
 		let _ = websocket.lock().await.send(message).await;
 	}
 
+	/*
+	 * returns None only once the connection is gone, a bad frame is skipped.
+	 * Problems go through the task queue, to be shown by the UI
+	 */
 	async fn handle_incoming_message(
 		connection: Weak<Mutex<AppSyncOpenConnection>>,
+		task_queue: &mut TaskQueue,
 		message_raw: Utf8Bytes,
 	) -> Option<()> {
-		let message_value: Value = serde_json::from_str(message_raw.as_str()).ok()?;
-		let message_obj = message_value.as_object()?;
+		let malformed = |e: String, event: &str| {
+			TaskData::Error(
+				format!("Skipping malformed event ({}): {}", e, truncated(event)).into(),
+			)
+		};
 
-		// ignore any non-data messages for the meanwhile
-		if message_obj.get("type")? != "data" {
-			return Some(());
-		}
+		let event = match Self::parse_frame(message_raw.as_str()) {
+			Ok(Some(event)) => event,
+			Ok(None) => return Some(()),
+			Err(e) => {
+				task_queue.push(malformed(e, &message_raw)).await;
+				return Some(());
+			}
+		};
 
-		let message: Message = serde_json::from_str(message_obj.get("event")?.as_str()?).ok()?;
+		let connection = connection.upgrade()?;
+		let mut connection = connection.lock().await;
 
-		match connection.upgrade() {
-			Some(connection) => {
-				connection
-					.lock()
-					.await
-					.receive_message(message.clone())
-					.await
+		let message: Message = match event {
+			IncomingEvent::Message(wire) => match Message::try_from(wire) {
+				Ok(message) => message,
+				Err(e) => {
+					task_queue.push(malformed(e, &message_raw)).await;
+					return Some(());
+				}
+			},
+			IncomingEvent::File(chunk) => {
+				connection.receive_file_chunk(chunk).await;
+				return Some(());
+			}
+			IncomingEvent::Chunk(chunk) => {
+				for discarded in connection.chunk_assembler.discard_expired() {
+					task_queue.push(TaskData::Error(discarded.into())).await;
+				}
+				match connection.chunk_assembler.insert(chunk) {
+					Ok(Some(event)) => match Message::from_wire(&event) {
+						Ok(message) => message,
+						Err(e) => {
+							task_queue.push(malformed(e, &event)).await;
+							return Some(());
+						}
+					},
+					Ok(None) => return Some(()),
+					Err(e) => {
+						task_queue.push(TaskData::Error(e.into())).await;
+						return Some(());
+					}
+				}
 			}
-			None => return None,
 		};
 
+		connection.receive_message(message).await;
+
 		Some(())
 	}
+
+	// None for frames other than data, which are ignored for the meanwhile
+	fn parse_frame(frame: &str) -> Result<Option<IncomingEvent>, String> {
+		let message_value: Value = serde_json::from_str(frame).map_err(|e| e.to_string())?;
+		let message_obj = message_value.as_object().ok_or("not an object")?;
+
+		if message_obj.get("type").and_then(Value::as_str) != Some("data") {
+			return Ok(None);
+		}
+
+		let event = message_obj
+			.get("event")
+			.and_then(Value::as_str)
+			.ok_or("no event")?;
+		serde_json::from_str(event)
+			.map(Some)
+			.map_err(|e| e.to_string())
+	}
+}
+
+// enough of a frame to tell what it was, without flooding the output
+fn truncated(frame: &str) -> &str {
+	const SHOWN: usize = 200;
+
+	match frame.char_indices().nth(SHOWN) {
+		Some((end, _)) => &frame[..end],
+		None => frame,
+	}
 }
 
 impl Drop for AppSyncOpenConnection {
+	#[allow(clippy::map_clone)]
 	fn drop(&mut self) {
 		let ids: Vec<Box<str>> = self.channels_ids.values().map(|id| id.clone()).collect();
 		let websocket = Arc::clone(&self.websocket_send);
@@ -223,15 +310,29 @@ impl OpenConnection for AppSyncOpenConnection {
 		}
 	}
 
+	#[allow(clippy::map_clone)]
 	fn channels(&self) -> Vec<Box<str>> {
 		self.channels_ids.keys().map(|k| k.clone()).collect()
 	}
 
-	async fn receive_message(&mut self, message: Message) {
+	async fn receive_message(&mut self, mut message: Message) {
+		message.received_at = Some(timestamp_now());
 		self.task_queue
 			.push(TaskData::ReceiveMessage(message))
 			.await
 	}
+
+	async fn receive_file_chunk(&mut self, chunk: FileChunk) {
+		self.task_queue
+			.push(TaskData::ReceiveFileChunk(chunk))
+			.await
+	}
+
+	async fn close(&mut self) {
+		// stopped first, so closing isn't taken for losing the connection
+		self.listener_handle.abort();
+		let _ = self.websocket_send.lock().await.close().await;
+	}
 }
 
 impl From<tokio_tungstenite::tungstenite::http::Error> for MessageReceiverError {
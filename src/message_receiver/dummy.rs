@@ -6,7 +6,8 @@ use tokio::sync::Mutex;
 use tokio::task::JoinHandle;
 
 use super::{MessageReceiver, MessageReceiverError, OpenConnection, OpenConnectionHolder};
-use crate::message::Message;
+use crate::attachment::FileChunk;
+use crate::message::{timestamp_now, Message};
 use crate::task_queue::{TaskData, TaskQueue};
 
 pub struct DummyOpenConnection {
@@ -18,6 +19,7 @@ pub struct DummyOpenConnection {
 pub struct DummyMessageReceiver {}
 
 impl DummyOpenConnection {
+	#[allow(clippy::new_ret_no_self, clippy::redundant_field_names)]
 	pub async fn new(task_queue: TaskQueue) -> OpenConnectionHolder {
 		let result = Arc::new(Mutex::new(Self {
 			task_queue: task_queue,
@@ -29,21 +31,12 @@ impl DummyOpenConnection {
 		result.lock().await.loop_handle = tokio::task::spawn(async move {
 			let duration = Duration::from_secs(3);
 			let mut interval = tokio::time::interval(duration);
-			let message = Message {
-				sender: "dummy".into(),
-				channel: "dummy".into(),
-				contents: "Hello, Dummy!".into(),
-			};
-
 			loop {
 				interval.tick().await;
 				match weak_copy.upgrade() {
 					Some(connection) => {
-						connection
-							.lock()
-							.await
-							.receive_message(message.clone())
-							.await
+						let message = Message::text("dummy", "dummy", "Hello, Dummy!");
+						connection.lock().await.receive_message(message).await
 					}
 					None => return,
 				};
@@ -74,11 +67,22 @@ impl OpenConnection for DummyOpenConnection {
 		self.channels.clone()
 	}
 
-	async fn receive_message(&mut self, message: Message) {
+	async fn receive_message(&mut self, mut message: Message) {
+		message.received_at = Some(timestamp_now());
 		self.task_queue
 			.push(TaskData::ReceiveMessage(message))
 			.await
 	}
+
+	async fn receive_file_chunk(&mut self, chunk: FileChunk) {
+		self.task_queue
+			.push(TaskData::ReceiveFileChunk(chunk))
+			.await
+	}
+
+	async fn close(&mut self) {
+		self.loop_handle.abort();
+	}
 }
 
 impl DummyMessageReceiver {
@@ -3,7 +3,7 @@ use std::{fmt, sync::Arc};
 use async_trait::async_trait;
 use tokio::sync::Mutex;
 
-use crate::{message::Message, task_queue::TaskQueue};
+use crate::{attachment::FileChunk, message::Message, task_queue::TaskQueue};
 
 #[derive(Debug)]
 pub enum MessageReceiverError {
@@ -16,6 +16,9 @@ pub trait OpenConnection {
 	async fn remove_channel(&mut self, channel: &str);
 	fn channels(&self) -> Vec<Box<str>>;
 	async fn receive_message(&mut self, message: Message);
+	async fn receive_file_chunk(&mut self, chunk: FileChunk);
+	// stops receiving, and closes the connection to the server
+	async fn close(&mut self);
 }
 
 pub type OpenConnectionHolder = Arc<Mutex<dyn OpenConnection>>;
@@ -39,4 +42,5 @@ impl fmt::Display for MessageReceiverError {
 }
 
 pub mod appsync_message_receiver;
+#[allow(dead_code)]
 pub mod dummy;
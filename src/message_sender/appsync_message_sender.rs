@@ -5,10 +5,18 @@ use std::sync::Arc;
 use async_trait::async_trait;
 
 use super::{MessageSendError, MessageSender};
-use crate::{authenticator::Authenticator, message::Message};
+use crate::{
+	attachment::FileChunk,
+	authenticator::Authenticator,
+	chunking::{ChunkingError, EventChunk, MAX_EVENT_SIZE},
+	message::Message,
+};
 
 type Auth = dyn Authenticator + Sync + Send;
 
+// AppSync accepts at most 5 events per publish request
+const MAX_EVENTS_PER_REQUEST: usize = 5;
+
 pub struct AppSyncMessageSender {
 	uri: Box<str>,
 	auth: Arc<Auth>,
@@ -16,6 +24,7 @@ pub struct AppSyncMessageSender {
 }
 
 impl AppSyncMessageSender {
+	#[allow(clippy::borrowed_box)]
 	pub fn new(uri: &Box<str>, auth: Arc<Auth>) -> Self {
 		let client = Client::new();
 		Self {
@@ -25,17 +34,20 @@ impl AppSyncMessageSender {
 		}
 	}
 
-	fn message_to_body(message: Message) -> serde_json::Value {
-		let event = serde_json::to_string(&message).unwrap();
+	fn message_to_events(message: &Message) -> Result<Vec<String>, ChunkingError> {
+		let event = message.to_wire();
+		EventChunk::split(event, MAX_EVENT_SIZE)
+	}
 
+	fn events_to_body(channel: &str, events: &[String]) -> serde_json::Value {
 		json!({
-			"channel": message.channel,
-			"events": [event],
+			"channel": channel,
+			"events": events,
 		})
 	}
 
-	fn build_message(&self, message: Message) -> RequestBuilder {
-		let body = Self::message_to_body(message);
+	fn build_message(&self, channel: &str, events: &[String]) -> RequestBuilder {
+		let body = Self::events_to_body(channel, events);
 
 		let mut request_builder = self
 			.client
@@ -48,25 +60,43 @@ impl AppSyncMessageSender {
 
 		request_builder.json(&body)
 	}
-}
 
-#[async_trait]
-impl MessageSender for AppSyncMessageSender {
-	async fn send_text_message(&self, message: Message) -> Result<(), MessageSendError> {
-		let request = self.build_message(message);
+	async fn publish(&self, channel: &str, events: &[String]) -> Result<(), MessageSendError> {
+		for batch in events.chunks(MAX_EVENTS_PER_REQUEST) {
+			let request = self.build_message(channel, batch);
 
-		let response = request.send().await?;
+			let response = request.send().await?;
 
-		if !response.status().is_success() {
-			return Err(MessageSendError::SendFailed(response.text().await?));
+			if !response.status().is_success() {
+				return Err(MessageSendError::SendFailed(response.text().await?));
+			}
 		}
 
 		Ok(())
 	}
 }
 
+#[async_trait]
+impl MessageSender for AppSyncMessageSender {
+	async fn send_text_message(&self, message: Message) -> Result<(), MessageSendError> {
+		let events = Self::message_to_events(&message)?;
+		self.publish(&message.channel, &events).await
+	}
+
+	async fn send_file_chunk(&self, chunk: FileChunk) -> Result<(), MessageSendError> {
+		let event = serde_json::to_string(&chunk).unwrap();
+		self.publish(&chunk.channel, &[event]).await
+	}
+}
+
 impl From<reqwest::Error> for MessageSendError {
 	fn from(error: reqwest::Error) -> Self {
 		Self::HTTPError(error.to_string())
 	}
 }
+
+impl From<ChunkingError> for MessageSendError {
+	fn from(error: ChunkingError) -> Self {
+		Self::PayloadTooLarge(error.to_string())
+	}
+}
@@ -1,7 +1,7 @@
 use async_trait::async_trait;
 
 use super::{MessageSendError, MessageSender};
-use crate::message::Message;
+use crate::{attachment::FileChunk, message::Message};
 
 pub struct DummyMessageSender {}
 
@@ -17,4 +17,14 @@ impl MessageSender for DummyMessageSender {
 		println!("Sending message: {:?}", message);
 		Ok(())
 	}
+
+	async fn send_file_chunk(&self, chunk: FileChunk) -> Result<(), MessageSendError> {
+		println!(
+			"Sending chunk {}/{} of file {}",
+			chunk.chunk_index + 1,
+			chunk.chunk_count,
+			chunk.file_name
+		);
+		Ok(())
+	}
 }
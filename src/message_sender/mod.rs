@@ -2,17 +2,20 @@ use std::fmt;
 
 use async_trait::async_trait;
 
-use crate::message::Message;
+use crate::{attachment::FileChunk, message::Message};
 
 #[derive(Debug)]
 pub enum MessageSendError {
 	HTTPError(String),
 	SendFailed(String),
+	PayloadTooLarge(String),
+	EncryptionError(String),
 }
 
 #[async_trait]
 pub trait MessageSender {
 	async fn send_text_message(&self, message: Message) -> Result<(), MessageSendError>;
+	async fn send_file_chunk(&self, chunk: FileChunk) -> Result<(), MessageSendError>;
 }
 
 impl std::error::Error for MessageSendError {}
@@ -22,9 +25,12 @@ impl fmt::Display for MessageSendError {
 		match self {
 			Self::HTTPError(e) => write!(f, "HTTP Error: {}", e),
 			Self::SendFailed(e) => write!(f, "Message Send Failed: {}", e),
+			Self::PayloadTooLarge(e) => write!(f, "Message Too Large: {}", e),
+			Self::EncryptionError(e) => write!(f, "Encryption Error: {}", e),
 		}
 	}
 }
 
 pub mod appsync_message_sender;
+#[allow(dead_code)]
 pub mod dummy;
@@ -1,85 +1,1056 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use tokio::task::JoinSet;
+use uuid::Uuid;
+
+use crate::attachment::{
+	sha256_hex, AttachmentError, BlobStore, FileAssembler, FileChunk, ReceivedFile,
+	TransferDirection,
+};
 use crate::authenticator::Authenticator;
+use crate::backfill::{BackfillProvider, BackfillQuery};
+use crate::encryption::ChannelEncryption;
+use crate::history::export::{parse_json_lines, ExportFormat, ExportRange};
+use crate::history::retention::{RetentionPolicies, RetentionPolicy, PURGE_INTERVAL};
+use crate::history::{HistoryError, HistoryStore};
+use crate::identity::{IdentityError, Keyring};
+use crate::message::{timestamp_now, Message, MessageKind, Verification};
 use crate::message_receiver::{MessageReceiver, OpenConnectionHolder};
-use crate::message_sender::MessageSender;
-use crate::task_queue::{TaskData, TaskQueue};
-use crate::ui_connector::UIConnector;
+use crate::message_sender::{MessageSendError, MessageSender};
+use crate::presence::{Activity, PresenceTracker, HEARTBEAT_INTERVAL};
+use crate::sequence::{GapDetector, SequenceCounter};
+use crate::subscriptions::Subscriptions;
+use crate::task_queue::{HistoryLookup, TaskData, TaskQueue};
+use crate::ui_connector::{ConnectionState, UIConnector};
+use crate::unread::UnreadTracker;
 
 pub struct Messenger<
 	TAuth: Authenticator,
 	TReceiver: MessageReceiver,
 	TSender: MessageSender,
 	TUI: UIConnector,
+	TBlobs: BlobStore,
+	THistory: HistoryStore,
 > {
 	authenticator: Arc<TAuth>,
 	message_receiver: TReceiver,
 	message_sender: TSender,
 	ui_connector: TUI,
+	blob_store: TBlobs,
+	history: THistory,
 	task_queue: TaskQueue,
+	file_assembler: FileAssembler,
+	presence: PresenceTracker,
+	// who presence and typing notifications are sent as, once the UI knows
+	identity: Option<Box<str>>,
+	last_heartbeat: Option<Instant>,
+	unread: UnreadTracker,
+	send_read_receipts: bool,
+	encryption: ChannelEncryption,
+	keyring: Keyring,
+	retention: RetentionPolicies,
+	last_purge: Option<Instant>,
+	/*
+	 * messages in disappearing channels, which are shown but never stored,
+	 * with when they were, remembered for a while to not show them twice
+	 */
+	disappeared: HashMap<Uuid, Instant>,
+	// messages are sent without sequence numbers when there is no counter
+	sequences: Option<SequenceCounter>,
+	gaps: GapDetector,
+	backfill: Option<Arc<Backfill>>,
+	// requests to the backfill provider, run off the main loop as the service may be slow
+	backfill_tasks: JoinSet<()>,
+	subscriptions: Subscriptions,
 }
 
+type Backfill = dyn BackfillProvider + Send + Sync;
+
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_SEARCH_RESULTS: usize = 50;
+// long enough for a message to be delivered again after a reconnection or backfill
+const DISAPPEARED_MEMORY: Duration = Duration::from_secs(60 * 60);
+
 impl<
 		TAuth: Authenticator,
 		TReceiver: MessageReceiver,
 		TSender: MessageSender,
 		TUI: UIConnector,
-	> Messenger<TAuth, TReceiver, TSender, TUI>
+		TBlobs: BlobStore,
+		THistory: HistoryStore,
+	> Messenger<TAuth, TReceiver, TSender, TUI, TBlobs, THistory>
 {
+	#[allow(clippy::needless_return)]
 	pub fn new(
 		authenticator: Arc<TAuth>,
 		message_receiver: TReceiver,
 		message_sender: TSender,
 		ui_connector: TUI,
+		blob_store: TBlobs,
+		history: THistory,
+		keyring: Keyring,
 	) -> Self {
 		return Messenger {
 			authenticator,
 			message_receiver,
 			message_sender,
 			ui_connector,
+			blob_store,
+			history,
 			task_queue: TaskQueue::new(),
+			file_assembler: FileAssembler::new(),
+			presence: PresenceTracker::new(),
+			identity: None,
+			last_heartbeat: None,
+			unread: UnreadTracker::new(),
+			send_read_receipts: false,
+			encryption: ChannelEncryption::new(),
+			keyring,
+			retention: RetentionPolicies::default(),
+			last_purge: None,
+			disappeared: HashMap::new(),
+			sequences: None,
+			gaps: GapDetector::new(),
+			backfill: None,
+			backfill_tasks: JoinSet::new(),
+			subscriptions: Subscriptions::new(),
 		};
 	}
 
-	pub async fn start(&mut self) {
-		println!("Starting Server");
+	pub fn with_sequences(mut self, sequences: SequenceCounter) -> Self {
+		self.sequences = Some(sequences);
+		self
+	}
+
+	pub fn with_subscriptions(mut self, subscriptions: Subscriptions) -> Self {
+		self.subscriptions = subscriptions;
+		self
+	}
+
+	pub fn with_backfill(mut self, backfill: Arc<Backfill>) -> Self {
+		self.backfill = Some(backfill);
+		self
+	}
+
+	pub fn with_retention(mut self, default_policy: RetentionPolicy) -> Self {
+		self.retention.default = default_policy;
+		self
+	}
+
+	pub fn with_read_receipts(mut self, send_read_receipts: bool) -> Self {
+		self.send_read_receipts = send_read_receipts;
+		self
+	}
+
+	// returns false when it couldn't start, such as when it couldn't connect
+	pub async fn start(&mut self) -> bool {
+		eprintln!("Starting Server");
 		if !self.authenticator.authenticate() {
-			println!("Authentication Failed!");
-			return;
+			eprintln!("Authentication Failed!");
+			return false;
+		}
+
+		match self.history.retention_policies() {
+			Ok(policies) => self.retention.channels.extend(policies),
+			Err(e) => eprintln!("Error reading retention policies: {}", e),
 		}
 
 		let connect_result = self.message_receiver.listen(self.task_queue.clone()).await;
 		match connect_result {
 			Ok(connection) => {
 				self.ui_connector.start(self.task_queue.clone());
+				self.ui_connector
+					.connection_changed(ConnectionState::Connected);
+				self.restore_subscriptions(&connection).await;
+				let ticker = Self::start_ticker(self.task_queue.clone());
+				let signals = Self::start_signal_listener(self.task_queue.clone());
 
 				self.handle_tasks(&connection).await;
 
-				println!("Shutting down server...");
+				ticker.abort();
+				signals.abort();
+				self.shutdown(&connection).await;
+				true
+			}
+			Err(e) => {
+				eprintln!("Could not connect: {}", e);
+				false
+			}
+		}
+	}
+
+	async fn restore_subscriptions(&mut self, connection: &OpenConnectionHolder) {
+		let channels: Vec<Box<str>> = self
+			.subscriptions
+			.list()
+			.iter()
+			.map(|subscription| subscription.channel.clone())
+			.collect();
+		if channels.is_empty() {
+			return;
+		}
+
+		for channel in channels {
+			self.subscribe(connection, &channel).await;
+		}
+		self.ui_connector
+			.channels_changed(self.subscriptions.list().to_vec());
+	}
+
+	async fn subscribe(&mut self, connection: &OpenConnectionHolder, channel: &str) {
+		connection.lock().await.add_channel(channel).await;
+		// announce ourselves in the new channel on the next tick
+		self.last_heartbeat = None;
+		self.backfill_channel(channel).await;
+	}
+
+	fn subscriptions_changed(&mut self, res: std::io::Result<bool>) {
+		match res {
+			Ok(true) => self
+				.ui_connector
+				.channels_changed(self.subscriptions.list().to_vec()),
+			Ok(false) => (),
+			Err(e) => self
+				.ui_connector
+				.error(format!("Error saving subscriptions: {}", e)),
+		}
+	}
+
+	// expiring presence and sending heartbeats are driven by a periodic task
+	fn start_ticker(mut task_queue: TaskQueue) -> tokio::task::JoinHandle<()> {
+		tokio::task::spawn(async move {
+			let mut interval = tokio::time::interval(TICK_INTERVAL);
+			loop {
+				interval.tick().await;
+				task_queue.push(TaskData::Tick).await;
 			}
-			Err(e) => println!("Could not connect: {}", e),
+		})
+	}
+
+	// SIGINT and SIGTERM exit the same way the exit command does
+	fn start_signal_listener(mut task_queue: TaskQueue) -> tokio::task::JoinHandle<()> {
+		tokio::task::spawn(async move {
+			shutdown_signal().await;
+			task_queue.push(TaskData::Exit).await;
+		})
+	}
+
+	/*
+	 * every way of exiting ends here: what's queued to be sent is sent, then
+	 * we go offline, unsubscribe and close the connection, and the UI is
+	 * waited for to finish, such as to restore the terminal
+	 */
+	async fn shutdown(&mut self, connection: &OpenConnectionHolder) {
+		self.flush_outbox(connection).await;
+		// the sent messages are recorded, which the client's timeouts keep from taking long
+		while self.backfill_tasks.join_next().await.is_some() {}
+		self.send_presence(connection, false).await;
+
+		{
+			// the saved subscriptions are kept, to subscribe again on the next start
+			let mut connection = connection.lock().await;
+			for channel in connection.channels() {
+				connection.remove_channel(&channel).await;
+			}
+			connection.close().await;
 		}
+
+		self.ui_connector.shutdown().await;
+		eprintln!("Shutting down server...");
 	}
 
 	async fn handle_tasks(&mut self, connection: &OpenConnectionHolder) {
 		loop {
 			let task = self.task_queue.pop().await;
-			match task {
-				TaskData::SendMessage(message) => {
-					let res = self.message_sender.send_text_message(message).await;
-					if let Err(e) = res {
-						println!("Error sending message: {}", e);
+			if let TaskData::Exit = task {
+				break;
+			}
+			self.handle_task(task, connection).await;
+		}
+	}
+
+	// what's still waiting to be sent when exiting is sent first
+	async fn flush_outbox(&mut self, connection: &OpenConnectionHolder) {
+		while let Some(task) = self.task_queue.try_pop().await {
+			if task.is_outgoing() {
+				self.handle_task(task, connection).await;
+			}
+		}
+	}
+
+	async fn handle_task(&mut self, task: TaskData, connection: &OpenConnectionHolder) {
+		match task {
+			TaskData::SendMessage(message) => self.send_message(message).await,
+			TaskData::ReceiveMessage(message) => self.receive_message(message).await,
+			TaskData::Backfill(query) => self.backfill(query),
+			TaskData::SetIdentity(identity) => {
+				self.identity = Some(identity);
+				self.last_heartbeat = None;
+			}
+			TaskData::SetTyping { channel, active } => {
+				if let Some(identity) = &self.identity {
+					let typing = Message::new(identity, &channel, MessageKind::Typing { active });
+					self.send_ephemeral(typing).await;
+				}
+			}
+			TaskData::MarkRead(channel) => self.mark_read(&channel).await,
+			TaskData::SetChannelPassphrase {
+				channel,
+				passphrase,
+			} => {
+				let res = self.encryption.set_passphrase(&channel, &passphrase);
+				self.channel_key_set(&channel, res.map_err(|e| e.to_string()));
+			}
+			TaskData::SetChannelPeerKey {
+				channel,
+				public_key,
+			} => {
+				let res = self.encryption.set_peer_key(&channel, &public_key);
+				self.channel_key_set(&channel, res.map_err(|e| e.to_string()));
+			}
+			TaskData::ClearChannelKey(channel) => {
+				if self.encryption.clear_key(&channel) {
+					self.ui_connector
+						.channel_encryption_changed(&channel, false);
+				}
+			}
+			TaskData::ShowPublicKey => self.ui_connector.notice(format!(
+				"Key exchange public key: {}",
+				self.encryption.public_key()
+			)),
+			TaskData::Identity(command) => match self.keyring.execute(command) {
+				Ok(output) => self.ui_connector.notice(output),
+				Err(e) => self.ui_connector.error(format!("Identity error: {}", e)),
+			},
+			TaskData::Tick => self.tick(connection).await,
+			TaskData::ConnectionLost => self
+				.ui_connector
+				.connection_changed(ConnectionState::Disconnected),
+			TaskData::Error(text) => self.ui_connector.error(text.into()),
+			TaskData::Notice(text) => self.ui_connector.notice(text.into()),
+			TaskData::Reply {
+				sender,
+				reply_to,
+				contents,
+			} => match self.history.find(&reply_to) {
+				Ok(Some(parent)) => {
+					let mut message = Message::text(&sender, &parent.channel, &contents);
+					message.reply_to = Some(parent.id);
+					self.send_message(message).await;
+				}
+				Ok(None) => self
+					.ui_connector
+					.error(format!("No message with id {}", reply_to)),
+				Err(e) => self
+					.ui_connector
+					.error(format!("Error finding message: {}", e)),
+			},
+			TaskData::Edit {
+				sender,
+				target,
+				contents,
+			} => {
+				let kind = |target| MessageKind::Edit { target, contents };
+				self.send_change(&sender, &target, kind).await;
+			}
+			TaskData::Delete { sender, target } => {
+				let kind = |target| MessageKind::Delete { target };
+				self.send_change(&sender, &target, kind).await;
+			}
+			TaskData::React {
+				sender,
+				target,
+				emoji,
+				removed,
+			} => {
+				let kind = |target| MessageKind::Reaction {
+					target,
+					emoji,
+					removed,
+				};
+				self.send_change(&sender, &target, kind).await;
+			}
+			TaskData::ShowThread(id) => {
+				let res = self.find_thread(&id);
+				match res {
+					Ok(thread) if thread.is_empty() => self
+						.ui_connector
+						.error(format!("No message with id {}", id)),
+					Ok(thread) => self.ui_connector.history_received(thread),
+					Err(e) => self
+						.ui_connector
+						.error(format!("Error finding thread: {}", e)),
+				}
+			}
+			TaskData::ShowHistory { channel, limit } => {
+				let res = self.history.channel_history(&channel, limit);
+				match res {
+					Ok(messages) => self.ui_connector.history_received(messages),
+					Err(e) => self
+						.ui_connector
+						.error(format!("Error reading history: {}", e)),
+				}
+			}
+			TaskData::QueryHistory { lookup, reply } => {
+				let res = match lookup {
+					HistoryLookup::Channel { channel, limit } => {
+						self.history.channel_history(&channel, limit)
 					}
+					HistoryLookup::Thread(id) => self.find_thread(&id),
+					HistoryLookup::Search(text) => self.history.search(&text, MAX_SEARCH_RESULTS),
+				};
+				// whoever asked may have stopped waiting
+				reply.send(res.map_err(|e| e.to_string())).unwrap_or(());
+			}
+			TaskData::Search(text) => {
+				let res = self.history.search(&text, MAX_SEARCH_RESULTS);
+				match res {
+					Ok(messages) if messages.is_empty() => self
+						.ui_connector
+						.notice(format!("No messages match {}", text)),
+					Ok(messages) => self.ui_connector.history_received(messages),
+					Err(e) => self
+						.ui_connector
+						.error(format!("Error searching history: {}", e)),
 				}
-				TaskData::ReceiveMessage(message) => self.ui_connector.message_received(message),
-				TaskData::NewChannel(channel) => {
-					connection.lock().await.add_channel(&channel).await;
+			}
+			TaskData::Export {
+				format,
+				path,
+				range,
+			} => match self.export_history(format, &path, &range).await {
+				Ok(count) => self.ui_connector.notice(format!(
+					"Exported {} messages to {} as {}",
+					count, path, format
+				)),
+				Err(e) => self
+					.ui_connector
+					.error(format!("Error exporting history: {}", e)),
+			},
+			TaskData::SetRetention { channel, policy } => {
+				match self.set_retention(&channel, policy) {
+					Ok(()) => self
+						.ui_connector
+						.notice(format!("[{}] retention: {}", channel, policy)),
+					Err(e) => self
+						.ui_connector
+						.error(format!("Error setting retention for {}: {}", channel, e)),
 				}
-				TaskData::RemoveChannel(channel) => {
-					connection.lock().await.remove_channel(&channel).await;
+			}
+			TaskData::Import(path) => match self.import_history(&path).await {
+				Ok(count) => self
+					.ui_connector
+					.notice(format!("Imported {} new messages from {}", count, path)),
+				Err(e) => self
+					.ui_connector
+					.error(format!("Error importing history: {}", e)),
+			},
+			TaskData::SendFile {
+				sender,
+				channel,
+				path,
+			} => {
+				let res = self.queue_file(&sender, &channel, &path).await;
+				if let Err(e) = res {
+					self.ui_connector
+						.error(format!("Error sending file {}: {}", path, e));
 				}
-				TaskData::Exit => break,
+			}
+			TaskData::SendFileChunk(chunk) => {
+				let progress = chunk.progress(TransferDirection::Sending, chunk.chunk_index + 1);
+				let res = self.message_sender.send_file_chunk(chunk).await;
+				match res {
+					Ok(()) => self.ui_connector.transfer_progress(progress),
+					Err(e) => self
+						.ui_connector
+						.error(format!("Error sending file {}: {}", progress.file_name, e)),
+				}
+			}
+			TaskData::ReceiveFileChunk(chunk) => {
+				let res = self.receive_file_chunk(chunk).await;
+				if let Err(e) = res {
+					self.ui_connector
+						.error(format!("Error receiving file: {}", e));
+				}
+			}
+			TaskData::NewChannel(channel) => {
+				self.subscribe(connection, &channel).await;
+				let res = self.subscriptions.add(&channel);
+				self.subscriptions_changed(res);
+			}
+			TaskData::RemoveChannel(channel) => {
+				connection.lock().await.remove_channel(&channel).await;
+				let res = self.subscriptions.remove(&channel);
+				self.subscriptions_changed(res);
+			}
+			TaskData::SetFavourite { channel, favourite } => {
+				let res = self.subscriptions.set_favourite(&channel, favourite);
+				self.subscriptions_changed(res);
+			}
+			TaskData::SetMuted { channel, muted } => {
+				let res = self.subscriptions.set_muted(&channel, muted);
+				self.subscriptions_changed(res);
+			}
+			TaskData::ShowChannels => {
+				let subscriptions = self.subscriptions.list().to_vec();
+				self.ui_connector.channels_changed(subscriptions);
+			}
+			TaskData::Exit => (),
+		}
+	}
+
+	async fn send_message(&mut self, mut message: Message) {
+		if let Some(sequences) = &mut self.sequences {
+			match sequences.next(&message.sender, &message.channel) {
+				Ok(sequence) => message.sequence = Some(sequence),
+				Err(e) => self
+					.ui_connector
+					.error(format!("Error counting message sequence: {}", e)),
+			}
+		}
+		self.keyring.sign(&mut message);
+
+		let res = self.publish(&message).await;
+		self.ui_connector.message_sent(
+			&message,
+			res.as_ref().map(|_| ()).map_err(|e| e.to_string()),
+		);
+		if let Ok(outgoing) = res {
+			if let Some(backfill) = &self.backfill {
+				let backfill = Arc::clone(backfill);
+				let mut task_queue = self.task_queue.clone();
+				self.spawn_backfill(async move {
+					if let Err(e) = backfill.record(&outgoing).await {
+						let error = format!("Error recording message for backfill: {}", e);
+						task_queue.push(TaskData::Error(error.into())).await;
+					}
+				});
+			}
+			message.encrypted = self.encryption.is_encrypted(&message.channel);
+			self.record_message(message);
+		}
+	}
+
+	async fn send_ephemeral(&mut self, mut message: Message) {
+		self.keyring.sign(&mut message);
+		let res = self.publish(&message).await;
+		if let Err(e) = res {
+			self.ui_connector
+				.error(format!("Error sending notification: {}", e));
+		}
+	}
+
+	/*
+	 * messages are signed before this, so encrypted ones carry their signature
+	 * inside. Returns the message as it was sent
+	 */
+	async fn publish(&self, message: &Message) -> Result<Message, MessageSendError> {
+		let outgoing = self.encryption.encrypt(message)?;
+		self.message_sender
+			.send_text_message(outgoing.clone())
+			.await?;
+		Ok(outgoing)
+	}
+
+	async fn receive_message(&mut self, mut message: Message) {
+		// whether it was encrypted is only for us to decide
+		message.encrypted = false;
+		let mut message = match self.encryption.decrypt(&message) {
+			Ok(decrypted) => decrypted,
+			Err(e) => {
+				self.ui_connector.error(format!(
+					"Could not decrypt message {}: {}",
+					message.short_id(),
+					e
+				));
+				message
+			}
+		};
+		let verification = self.keyring.verify(&message);
+		message.verification = self.trusted(verification);
+
+		if message.kind.is_ephemeral() {
+			self.receive_ephemeral(&message);
+			return;
+		}
+
+		if let Some(sequence) = message.sequence {
+			self.detect_gap(&message, sequence).await;
+		}
+
+		// reactions & read receipts are collected locally, never taken from the sender
+		message.reactions.clear();
+		message.read_by.clear();
+
+		// a message from someone means they're done typing it
+		let was_typing = self
+			.presence
+			.remove(Activity::Typing, &message.channel, &message.sender);
+		if was_typing {
+			self.notify_presence(Activity::Typing, &message.channel);
+		}
+
+		self.record_message(message);
+	}
+
+	// whether what was received can be taken to come from the sender it names
+	fn is_authentic(&self, sender: &str, verification: Verification) -> bool {
+		match verification {
+			Verification::Trusted | Verification::Verified => true,
+			Verification::Unsigned => !self.keyring.has_key_for(sender),
+			Verification::BadSignature | Verification::KeyChanged => false,
+		}
+	}
+
+	// a key that couldn't be saved is still trusted until exiting
+	fn trusted(&mut self, verification: Result<Verification, IdentityError>) -> Verification {
+		verification.unwrap_or_else(|e| {
+			self.ui_connector
+				.error(format!("Error updating trusted keys: {}", e));
+			Verification::Trusted
+		})
+	}
+
+	async fn detect_gap(&mut self, message: &Message, sequence: u64) {
+		let gap = self
+			.gaps
+			.observe(&message.sender, &message.channel, sequence);
+		let gap = match gap {
+			Some(gap) => gap,
+			None => return,
+		};
+
+		self.ui_connector.notice(format!(
+			"[{}] missed {} messages from {}",
+			message.channel,
+			gap.end() - gap.start() + 1,
+			message.sender
+		));
+		if self.backfill.is_some() {
+			let query = BackfillQuery::Sequences {
+				channel: message.channel.clone(),
+				sender: message.sender.clone(),
+				from: *gap.start(),
+				to: *gap.end(),
 			};
+			self.task_queue.push(TaskData::Backfill(query)).await;
 		}
 	}
+
+	// asks for what was sent to the channel since the last message we have from it
+	async fn backfill_channel(&mut self, channel: &str) {
+		if self.backfill.is_none() {
+			return;
+		}
+
+		let last_seen = match self.history.channel_history(channel, 1) {
+			// by our own clock, as a sender could claim any time
+			Ok(messages) => messages.first().and_then(Message::local_time),
+			Err(e) => {
+				self.ui_connector
+					.error(format!("Error reading history: {}", e));
+				None
+			}
+		};
+		if let Some(since) = last_seen {
+			let query = BackfillQuery::Since {
+				channel: channel.into(),
+				since,
+			};
+			self.task_queue.push(TaskData::Backfill(query)).await;
+		}
+	}
+
+	// fetched messages are queued to be received like any other
+	fn backfill(&mut self, query: BackfillQuery) {
+		let backfill = match &self.backfill {
+			Some(backfill) => Arc::clone(backfill),
+			None => return,
+		};
+
+		let mut task_queue = self.task_queue.clone();
+		self.spawn_backfill(async move {
+			match backfill.fetch(&query).await {
+				Ok(messages) => {
+					for mut message in messages {
+						message.received_at = Some(timestamp_now());
+						task_queue.push(TaskData::ReceiveMessage(message)).await;
+					}
+				}
+				Err(e) => {
+					let error = format!("Error backfilling {}: {}", query.channel(), e);
+					task_queue.push(TaskData::Error(error.into())).await;
+				}
+			}
+		});
+	}
+
+	fn spawn_backfill(&mut self, task: impl Future<Output = ()> + Send + 'static) {
+		// the finished ones are only dropped
+		while self.backfill_tasks.try_join_next().is_some() {}
+		self.backfill_tasks.spawn(task);
+	}
+
+	fn channel_key_set(&mut self, channel: &str, res: Result<(), String>) {
+		match res {
+			Ok(()) => self.ui_connector.channel_encryption_changed(channel, true),
+			Err(e) => self
+				.ui_connector
+				.error(format!("Error setting key for {}: {}", channel, e)),
+		}
+	}
+
+	async fn tick(&mut self, connection: &OpenConnectionHolder) {
+		let purge_due = self
+			.last_purge
+			.is_none_or(|last| last.elapsed() >= PURGE_INTERVAL);
+		if purge_due {
+			self.last_purge = Some(Instant::now());
+			self.disappeared
+				.retain(|_, shown| shown.elapsed() < DISAPPEARED_MEMORY);
+			if let Err(e) = self.purge_history() {
+				self.ui_connector
+					.error(format!("Error purging history: {}", e));
+			}
+		}
+
+		for (activity, channel) in self.presence.expire() {
+			self.notify_presence(activity, &channel);
+		}
+
+		let heartbeat_due = self
+			.last_heartbeat
+			.is_none_or(|last| last.elapsed() >= HEARTBEAT_INTERVAL);
+		if heartbeat_due && self.identity.is_some() {
+			self.last_heartbeat = Some(Instant::now());
+			self.send_presence(connection, true).await;
+		}
+	}
+
+	async fn send_presence(&mut self, connection: &OpenConnectionHolder, online: bool) {
+		let identity = match &self.identity {
+			Some(identity) => identity.clone(),
+			None => return,
+		};
+
+		let channels = connection.lock().await.channels();
+		for channel in channels {
+			let presence = Message::new(&identity, &channel, MessageKind::Presence { online });
+			self.send_ephemeral(presence).await;
+		}
+	}
+
+	fn receive_ephemeral(&mut self, message: &Message) {
+		let (activity, active) = match message.kind {
+			MessageKind::Presence { online } => (Activity::Online, online),
+			MessageKind::Typing { active } => (Activity::Typing, active),
+			_ => return,
+		};
+
+		let changed = match active {
+			true => self
+				.presence
+				.refresh(activity, &message.channel, &message.sender),
+			false => self
+				.presence
+				.remove(activity, &message.channel, &message.sender),
+		};
+		if changed {
+			self.notify_presence(activity, &message.channel);
+		}
+	}
+
+	fn notify_presence(&mut self, activity: Activity, channel: &str) {
+		let members = self.presence.members(activity, channel);
+		match activity {
+			Activity::Online => self.ui_connector.presence_changed(channel, members),
+			Activity::Typing => self.ui_connector.typing_changed(channel, members),
+		}
+	}
+
+	async fn send_change(
+		&mut self,
+		sender: &str,
+		target: &str,
+		kind: impl FnOnce(Uuid) -> MessageKind,
+	) {
+		match self.history.find(target) {
+			Ok(Some(original)) => {
+				let change = Message::new(sender, &original.channel, kind(original.id));
+				if original.clone().apply(&change) {
+					self.send_message(change).await;
+				} else {
+					self.ui_connector.error(format!(
+						"Can't send {} for message {}",
+						change.kind.name(),
+						target
+					));
+				}
+			}
+			Ok(None) => self
+				.ui_connector
+				.error(format!("No message with id {}", target)),
+			Err(e) => self
+				.ui_connector
+				.error(format!("Error finding message: {}", e)),
+		}
+	}
+
+	/*
+	 * stores and shows a new message, or applies an edit or delete to the
+	 * message it targets. Messages already in history, such as our own echoed
+	 * back by the channel, are ignored
+	 */
+	fn record_message(&mut self, message: Message) {
+		let res = match message.kind.target() {
+			Some(target) => self.apply_change(target, &message),
+			None => self.store_new_message(message),
+		};
+
+		if let Err(e) = res {
+			self.ui_connector
+				.error(format!("Error storing message: {}", e));
+		}
+	}
+
+	fn store_new_message(&mut self, message: Message) -> Result<(), HistoryError> {
+		if self.disappeared.contains_key(&message.id) || self.history.get(message.id)?.is_some() {
+			return Ok(());
+		}
+
+		if self.retention.policy(&message.channel).disappearing {
+			self.disappeared.insert(message.id, Instant::now());
+		} else {
+			self.history.store(&message)?;
+		}
+
+		// muted channels are only kept in history
+		if self.subscriptions.is_muted(&message.channel) {
+			return Ok(());
+		}
+
+		let own_message = self.identity.as_ref() == Some(&message.sender);
+		let channel = message.channel.clone();
+		let id = message.id;
+		self.ui_connector.message_received(message);
+
+		if !own_message {
+			let unread = self.unread.message_received(&channel, id);
+			self.ui_connector.unread_changed(&channel, unread);
+		}
+		Ok(())
+	}
+
+	fn set_retention(
+		&mut self,
+		channel: &str,
+		policy: RetentionPolicy,
+	) -> Result<(), HistoryError> {
+		self.history.set_retention_policy(channel, policy)?;
+		self.retention.channels.insert(channel.into(), policy);
+		self.purge_channel(channel)?;
+		Ok(())
+	}
+
+	fn purge_history(&mut self) -> Result<(), HistoryError> {
+		for channel in self.history.channels()? {
+			self.purge_channel(&channel)?;
+		}
+		Ok(())
+	}
+
+	fn purge_channel(&mut self, channel: &str) -> Result<usize, HistoryError> {
+		let policy = self.retention.policy(channel);
+		if policy.keeps_everything() {
+			return Ok(0);
+		}
+
+		let before = policy
+			.max_age
+			.map(|max_age| timestamp_now().saturating_sub(max_age.as_millis() as u64));
+		let keep_latest = match policy.disappearing {
+			true => Some(0),
+			false => policy.max_count,
+		};
+		self.history.purge(channel, before, keep_latest)
+	}
+
+	async fn mark_read(&mut self, channel: &str) {
+		let newest = self.unread.mark_read(channel);
+		self.ui_connector
+			.unread_changed(channel, self.unread.unread(channel));
+
+		let (newest, identity) = match (newest, &self.identity) {
+			(Some(newest), Some(identity)) if self.send_read_receipts => (newest, identity),
+			_ => return,
+		};
+		let receipt = Message::new(
+			identity,
+			channel,
+			MessageKind::ReadReceipt { target: newest },
+		);
+		self.send_message(receipt).await;
+	}
+
+	/*
+	 * changes can't be trusted to come from their sender if their signature
+	 * doesn't check out, or if it's missing while the sender is known to sign
+	 */
+	fn apply_change(&mut self, target: Uuid, change: &Message) -> Result<(), HistoryError> {
+		if !self.is_authentic(&change.sender, change.verification) {
+			self.ui_connector.error(format!(
+				"Ignoring {} from {}: not signed with their key",
+				change.kind.name(),
+				change.sender
+			));
+			return Ok(());
+		}
+
+		if let Some(mut original) = self.history.get(target)? {
+			if original.apply(change) {
+				self.history.store(&original)?;
+				self.ui_connector.message_updated(original);
+			}
+		}
+		Ok(())
+	}
+
+	async fn export_history(
+		&self,
+		format: ExportFormat,
+		path: &str,
+		range: &ExportRange,
+	) -> Result<usize, HistoryError> {
+		let messages = self.history.messages_in(range)?;
+		tokio::fs::write(path, format.export(&messages)).await?;
+		Ok(messages.len())
+	}
+
+	async fn import_history(&mut self, path: &str) -> Result<usize, HistoryError> {
+		let text = tokio::fs::read_to_string(path).await?;
+		let mut imported = 0;
+
+		for mut message in parse_json_lines(&text)? {
+			// history only holds messages changes were already applied to
+			let storable = message.kind.target().is_none() && !message.kind.is_ephemeral();
+			if !storable || self.history.get(message.id)?.is_some() {
+				continue;
+			}
+			/*
+			 * anyone could have written the file, and edited messages no longer
+			 * match their signature, so nothing checked on receipt is kept
+			 */
+			message.verification = Verification::Unsigned;
+			message.encrypted = false;
+			self.history.store(&message)?;
+			imported += 1;
+		}
+
+		Ok(imported)
+	}
+
+	fn find_thread(&self, id: &str) -> Result<Vec<Message>, HistoryError> {
+		match self.history.find(id)? {
+			Some(message) => self.history.thread(message.id),
+			None => Ok(Vec::new()),
+		}
+	}
+
+	/*
+	 * chunks are queued rather than sent at once, so that a large file
+	 * doesn't hold up other tasks while it is being sent
+	 */
+	async fn queue_file(
+		&mut self,
+		sender: &str,
+		channel: &str,
+		path: &str,
+	) -> Result<(), AttachmentError> {
+		let data = tokio::fs::read(path).await?;
+		let file_name = Path::new(path)
+			.file_name()
+			.map(|name| name.to_string_lossy().into_owned())
+			.unwrap_or_else(|| path.to_string());
+
+		let encrypted = self.encryption.is_encrypted(channel);
+		let data = match encrypted {
+			true => self.encryption.encrypt_file(channel, &file_name, &data)?,
+			false => data,
+		};
+
+		for mut chunk in FileChunk::split_file(sender, channel, &file_name, &data, encrypted)? {
+			self.keyring.sign_file_chunk(&mut chunk);
+			self.task_queue.push(TaskData::SendFileChunk(chunk)).await;
+		}
+
+		Ok(())
+	}
+
+	// a chunk is checked before it's collected, so a forged one can't take up a transfer's place
+	async fn receive_file_chunk(&mut self, chunk: FileChunk) -> Result<(), AttachmentError> {
+		let verification = self.keyring.verify_file_chunk(&chunk);
+		let verification = self.trusted(verification);
+		if !self.is_authentic(&chunk.sender, verification) {
+			return Err(AttachmentError::BadChunk(format!(
+				"{} isn't signed with the key of {}",
+				chunk.file_name, chunk.sender
+			)));
+		}
+
+		let mut progress = chunk.progress(TransferDirection::Receiving, 0);
+		for discarded in self.file_assembler.discard_expired() {
+			self.ui_connector.error(discarded);
+		}
+		let (chunks_done, received) = self.file_assembler.insert(chunk)?;
+		progress.chunks_done = chunks_done;
+		self.ui_connector.transfer_progress(progress);
+
+		if let Some(ReceivedFile {
+			mut attachment,
+			mut data,
+		}) = received
+		{
+			attachment.verification = verification;
+			if attachment.encrypted {
+				let (channel, file_name) = (&attachment.channel, &attachment.file_name);
+				data = self.encryption.decrypt_file(channel, file_name, &data)?;
+				attachment.file_size = data.len();
+				attachment.sha256 = sha256_hex(&data);
+			}
+			attachment.location = self.blob_store.put(&attachment.file_name, &data).await?;
+			self.ui_connector.file_received(attachment);
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(unix)]
+pub async fn shutdown_signal() {
+	use tokio::signal::unix::{signal, SignalKind};
+
+	let mut terminate = match signal(SignalKind::terminate()) {
+		Ok(terminate) => terminate,
+		Err(_) => return interrupt_signal().await,
+	};
+	tokio::select! {
+		_ = interrupt_signal() => (),
+		_ = terminate.recv() => (),
+	}
+}
+
+#[cfg(not(unix))]
+pub async fn shutdown_signal() {
+	interrupt_signal().await
+}
+
+// waits forever if Ctrl+C can't be listened for
+async fn interrupt_signal() {
+	if tokio::signal::ctrl_c().await.is_err() {
+		std::future::pending::<()>().await
+	}
 }
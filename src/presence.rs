@@ -0,0 +1,93 @@
+/*
+ * Presence heartbeats and typing notifications are ephemeral: they are never
+ * stored, and a sender is dropped from a channel's online or typing set once
+ * nothing was heard from them for that activity's timeout.
+*/
+use std::{
+	collections::HashMap,
+	time::{Duration, Instant},
+};
+
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+pub const PRESENCE_TIMEOUT: Duration = Duration::from_secs(60);
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Activity {
+	Online,
+	Typing,
+}
+
+#[derive(Default)]
+pub struct PresenceTracker {
+	// last time each sender was seen doing the activity, per channel
+	seen: HashMap<(Activity, Box<str>), HashMap<Box<str>, Instant>>,
+}
+
+impl Activity {
+	fn timeout(&self) -> Duration {
+		match self {
+			Self::Online => PRESENCE_TIMEOUT,
+			Self::Typing => TYPING_TIMEOUT,
+		}
+	}
+}
+
+impl PresenceTracker {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	// returns whether the sender is new to the channel's set
+	pub fn refresh(&mut self, activity: Activity, channel: &str, sender: &str) -> bool {
+		self.seen
+			.entry((activity, channel.into()))
+			.or_default()
+			.insert(sender.into(), Instant::now())
+			.is_none()
+	}
+
+	// returns whether the sender was in the channel's set
+	pub fn remove(&mut self, activity: Activity, channel: &str, sender: &str) -> bool {
+		let key = (activity, channel.into());
+		let removed = match self.seen.get_mut(&key) {
+			Some(senders) => senders.remove(sender).is_some(),
+			None => false,
+		};
+
+		if self
+			.seen
+			.get(&key)
+			.is_some_and(|senders| senders.is_empty())
+		{
+			self.seen.remove(&key);
+		}
+		removed
+	}
+
+	// drops timed out senders, returning the sets that changed
+	pub fn expire(&mut self) -> Vec<(Activity, Box<str>)> {
+		let mut changed = Vec::new();
+
+		for ((activity, channel), senders) in self.seen.iter_mut() {
+			let count = senders.len();
+			senders.retain(|_, seen| seen.elapsed() < activity.timeout());
+			if senders.len() != count {
+				changed.push((*activity, channel.clone()));
+			}
+		}
+		self.seen.retain(|_, senders| !senders.is_empty());
+
+		changed
+	}
+
+	pub fn members(&self, activity: Activity, channel: &str) -> Vec<Box<str>> {
+		let mut members: Vec<Box<str>> = self
+			.seen
+			.get(&(activity, channel.into()))
+			.map(|senders| senders.keys().cloned().collect())
+			.unwrap_or_default();
+		members.sort();
+		members
+	}
+}
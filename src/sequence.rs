@@ -0,0 +1,155 @@
+/*
+ * Every stored kind of message carries a sequence number, counted per sender
+ * in each channel. The counters for what we send are saved in the data
+ * directory so they keep counting across restarts, and the receiving side
+ * reports a gap whenever a sender's number skips ahead.
+*/
+use std::{
+	collections::{BTreeMap, HashMap},
+	ops::RangeInclusive,
+	path::{Path, PathBuf},
+};
+
+const SEQUENCES_FILE: &str = "sequences.json";
+// a bigger jump is taken for a bogus number rather than a gap to fill
+const MAX_GAP: u64 = 1000;
+
+pub struct SequenceCounter {
+	path: PathBuf,
+	// the next number to send, by sender and channel
+	next: BTreeMap<Box<str>, BTreeMap<Box<str>, u64>>,
+}
+
+#[derive(Default)]
+pub struct GapDetector {
+	// the number expected next, by sender and channel
+	expected: HashMap<(Box<str>, Box<str>), u64>,
+}
+
+impl SequenceCounter {
+	pub fn open(data_directory: &str) -> std::io::Result<Self> {
+		let path = Path::new(data_directory).join(SEQUENCES_FILE);
+		let next = match path.exists() {
+			true => serde_json::from_str(&std::fs::read_to_string(&path)?)?,
+			false => BTreeMap::new(),
+		};
+
+		Ok(Self { path, next })
+	}
+
+	pub fn next(&mut self, sender: &str, channel: &str) -> std::io::Result<u64> {
+		let next = self
+			.next
+			.entry(sender.into())
+			.or_default()
+			.entry(channel.into())
+			.or_insert(1);
+		let sequence = *next;
+		// such as after a counters file edited by hand
+		*next = sequence.checked_add(1).ok_or_else(|| {
+			std::io::Error::other(format!(
+				"no sequence numbers left for {} in {}",
+				sender, channel
+			))
+		})?;
+
+		std::fs::write(&self.path, serde_json::to_string_pretty(&self.next)?)?;
+		Ok(sequence)
+	}
+}
+
+impl GapDetector {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/*
+	 * returns the numbers missed before this one, if any. Nothing is known to
+	 * be missing before the first number seen from a sender, and lower
+	 * numbers, such as backfilled ones, are left for deduplication. Numbers
+	 * more than MAX_GAP ahead are ignored
+	 */
+	pub fn observe(
+		&mut self,
+		sender: &str,
+		channel: &str,
+		sequence: u64,
+	) -> Option<RangeInclusive<u64>> {
+		let expected = self
+			.expected
+			.entry((sender.into(), channel.into()))
+			.or_insert(sequence);
+
+		if sequence.saturating_sub(*expected) > MAX_GAP {
+			return None;
+		}
+
+		let gap = match sequence > *expected {
+			true => Some(*expected..=sequence - 1),
+			false => None,
+		};
+		*expected = (*expected).max(sequence.saturating_add(1));
+		gap
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn counters_are_per_sender_and_channel_and_saved() {
+		let directory =
+			std::env::temp_dir().join(format!("sequence-test-{}", uuid::Uuid::new_v4()));
+		std::fs::create_dir_all(&directory).unwrap();
+		let data_directory = directory.to_str().unwrap();
+
+		let mut counter = SequenceCounter::open(data_directory).unwrap();
+		assert_eq!(counter.next("alice", "general").unwrap(), 1);
+		assert_eq!(counter.next("alice", "general").unwrap(), 2);
+		assert_eq!(counter.next("alice", "random").unwrap(), 1);
+		assert_eq!(counter.next("bob", "general").unwrap(), 1);
+
+		let mut reopened = SequenceCounter::open(data_directory).unwrap();
+		assert_eq!(reopened.next("alice", "general").unwrap(), 3);
+
+		reopened
+			.next
+			.get_mut("alice")
+			.unwrap()
+			.insert("general".into(), u64::MAX);
+		assert!(reopened.next("alice", "general").is_err());
+
+		std::fs::remove_dir_all(&directory).unwrap_or(());
+	}
+
+	#[test]
+	fn gaps_are_reported_once() {
+		let mut gaps = GapDetector::new();
+		// nothing is known to be missing before the first number seen
+		assert_eq!(gaps.observe("alice", "general", 5), None);
+		assert_eq!(gaps.observe("alice", "general", 6), None);
+		assert_eq!(gaps.observe("alice", "general", 9), Some(7..=8));
+		// filled in later, such as by backfill
+		assert_eq!(gaps.observe("alice", "general", 7), None);
+		assert_eq!(gaps.observe("alice", "general", 10), None);
+	}
+
+	#[test]
+	fn senders_and_channels_are_counted_apart() {
+		let mut gaps = GapDetector::new();
+		gaps.observe("alice", "general", 1);
+		assert_eq!(gaps.observe("bob", "general", 3), None);
+		assert_eq!(gaps.observe("alice", "random", 3), None);
+		assert_eq!(gaps.observe("alice", "general", 3), Some(2..=2));
+	}
+
+	#[test]
+	fn bogus_numbers_are_ignored() {
+		let mut gaps = GapDetector::new();
+		gaps.observe("alice", "general", 1);
+		assert_eq!(gaps.observe("alice", "general", MAX_GAP + 10), None);
+		assert_eq!(gaps.observe("alice", "general", u64::MAX), None);
+		assert_eq!(gaps.observe("alice", "general", 3), Some(2..=2));
+	}
+}
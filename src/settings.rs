@@ -3,6 +3,8 @@ use std::convert::Infallible;
  * Settings is meant to represent program-wide settings read at runtime from
  * an env-like file, into any types (or, at least, those convertible from string),
  * in addition to raising errors on duplicate or missing fields.
+ * Fields declared with a default (`FIELD: Type = "value"`) are optional, and
+ * the default is parsed the same way a value from the file would be.
  * This is achieved using a macro to build the struct & the reader function
 */
 use std::fmt;
@@ -10,6 +12,9 @@ use std::fs::File;
 use std::io::{self, BufRead};
 use std::path::Path;
 
+use crate::history::retention::RetentionPolicy;
+use crate::ui_connector::UIKinds;
+
 #[derive(Debug)]
 pub struct ConstStr(Box<str>);
 
@@ -38,6 +43,12 @@ impl std::ops::Deref for ConstStr {
 	}
 }
 
+impl fmt::Display for ConstStr {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
 impl std::error::Error for SettingsReadError {}
 
 impl fmt::Display for SettingsReadError {
@@ -76,6 +87,12 @@ impl From<core::str::ParseBoolError> for SettingsReadError {
 	}
 }
 
+impl From<String> for SettingsReadError {
+	fn from(error: String) -> Self {
+		Self::BadFormatting(error)
+	}
+}
+
 impl From<std::convert::Infallible> for SettingsReadError {
 	fn from(error: std::convert::Infallible) -> Self {
 		Self::BadFormatting(error.to_string())
@@ -83,7 +100,13 @@ impl From<std::convert::Infallible> for SettingsReadError {
 }
 
 macro_rules! Settings {
-	($($field:ident : $t:ty),* $(,)? ) => {
+	(@default $field:ident) => {
+		return Err(SettingsReadError::MissingField(stringify!($field).to_string()))
+	};
+	(@default $field:ident $default:literal) => {
+		$default.parse()?
+	};
+	($($field:ident : $t:ty $(= $default:literal)?),* $(,)? ) => {
 		#[derive(Debug)]
 		#[allow(non_snake_case)]
 		pub struct Settings {
@@ -141,13 +164,19 @@ macro_rules! Settings {
 				Err(SettingsReadError::UnknownField(field_name.to_string()))
 			}
 
+			// every field, with its value as it would be written in the file
+			pub fn entries(&self) -> Vec<(&'static str, String)> {
+				vec![$((stringify!($field), self.$field.to_string())),*]
+			}
+
 			fn try_fill_settings(read_values: SettingsReadValues) -> Result<Self, SettingsReadError> {
 				Ok(
 					Self {
 						$(
-							$field: read_values.$field.ok_or(
-								SettingsReadError::MissingField(stringify!($field).to_string())
-							)?,
+							$field: match read_values.$field {
+								Some(value) => value,
+								None => Settings!(@default $field $($default)?),
+							},
 						)*
 					}
 				)
@@ -156,9 +185,40 @@ macro_rules! Settings {
 	};
 }
 
+// shown as redacted, such as by `config show`, as they may hold credentials
+pub const SECRET_SETTINGS: &[&str] = &[
+	"APPSYNC_API_KEY",
+	"IDENTITY_PASSPHRASE",
+	"HISTORY_KEY_COMMAND",
+	"BACKFILL_URL",
+];
+
 Settings! {
 	APPSYNC_HTTP_DOMAIN: ConstStr,
 	APPSYNC_PUBLISH_URL: ConstStr,
 	APPSYNC_API_KEY: ConstStr,
 	APPSYNC_WEBSOCKET_URL: ConstStr,
+	DOWNLOAD_DIRECTORY: ConstStr = "downloads",
+	DATA_DIRECTORY: ConstStr = "data",
+	// asked for on startup when empty, which needs stdin to be a terminal
+	IDENTITY_PASSPHRASE: ConstStr = "",
+	// the history database is encrypted with the identity passphrase, or with this command's output
+	HISTORY_KEY_COMMAND: ConstStr = "",
+	// for channels without their own policy, see history/retention.rs
+	HISTORY_RETENTION: RetentionPolicy = "keep",
+	// a history service to fill in missed messages from, such as `desktop_messenger history_service`
+	BACKFILL_URL: ConstStr = "",
+	SEND_READ_RECEIPTS: bool = "false",
+	// the name messages are sent as, until changed with /nick
+	NICKNAME: ConstStr = "",
+	// simplified, a line at a time, terminal, full-screen, json, see ui_connector/json_lines.rs,
+	// or web, see ui_connector/web.rs. several can run at once, comma separated, such as
+	// "terminal,web", though only one of those reading the terminal
+	UI: UIKinds = "simplified",
+	// where the web UI listens, which must be a loopback address
+	WEB_ADDRESS: ConstStr = "127.0.0.1:8091",
+	// the web UI's pages, instead of the bundled one
+	WEB_PAGE_DIRECTORY: ConstStr = "",
+	// a file every message, notice and error is also appended to, when set
+	TRANSCRIPT_FILE: ConstStr = "",
 }
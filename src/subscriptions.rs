@@ -0,0 +1,129 @@
+/*
+ * The channels subscribed to, saved in the data directory whenever they change
+ * so they can be subscribed to again on the next start. Favourites are kept
+ * first, in the order they were made favourites, and the rest in the order
+ * they were added. Muted channels are still received and stored, but their
+ * messages aren't shown or counted as unread.
+*/
+use std::{
+	io,
+	path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+const SUBSCRIPTIONS_FILE: &str = "subscriptions.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscription {
+	pub channel: Box<str>,
+	#[serde(default)]
+	pub favourite: bool,
+	#[serde(default)]
+	pub muted: bool,
+}
+
+#[derive(Default)]
+pub struct Subscriptions {
+	// nothing is saved without a file
+	path: Option<PathBuf>,
+	subscriptions: Vec<Subscription>,
+}
+
+impl Subscriptions {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn open(data_directory: &str) -> io::Result<Self> {
+		let path = Path::new(data_directory).join(SUBSCRIPTIONS_FILE);
+		let subscriptions = match path.exists() {
+			true => serde_json::from_str(&std::fs::read_to_string(&path)?)?,
+			false => Vec::new(),
+		};
+
+		Ok(Self {
+			path: Some(path),
+			subscriptions,
+		})
+	}
+
+	pub fn list(&self) -> &[Subscription] {
+		&self.subscriptions
+	}
+
+	pub fn is_muted(&self, channel: &str) -> bool {
+		self.find(channel)
+			.is_some_and(|index| self.subscriptions[index].muted)
+	}
+
+	// each of these returns whether anything changed
+	pub fn add(&mut self, channel: &str) -> io::Result<bool> {
+		if self.find(channel).is_some() {
+			return Ok(false);
+		}
+
+		self.subscriptions.push(Subscription {
+			channel: channel.into(),
+			favourite: false,
+			muted: false,
+		});
+		self.save()?;
+		Ok(true)
+	}
+
+	pub fn remove(&mut self, channel: &str) -> io::Result<bool> {
+		match self.find(channel) {
+			Some(index) => {
+				self.subscriptions.remove(index);
+				self.save()?;
+				Ok(true)
+			}
+			None => Ok(false),
+		}
+	}
+
+	// moves the channel to the end of the favourites, or to the top of the rest
+	pub fn set_favourite(&mut self, channel: &str, favourite: bool) -> io::Result<bool> {
+		let index = match self.find(channel) {
+			Some(index) if self.subscriptions[index].favourite != favourite => index,
+			_ => return Ok(false),
+		};
+
+		let mut subscription = self.subscriptions.remove(index);
+		subscription.favourite = favourite;
+		let favourites = self
+			.subscriptions
+			.iter()
+			.take_while(|subscription| subscription.favourite)
+			.count();
+		self.subscriptions.insert(favourites, subscription);
+
+		self.save()?;
+		Ok(true)
+	}
+
+	pub fn set_muted(&mut self, channel: &str, muted: bool) -> io::Result<bool> {
+		match self.find(channel) {
+			Some(index) if self.subscriptions[index].muted != muted => {
+				self.subscriptions[index].muted = muted;
+				self.save()?;
+				Ok(true)
+			}
+			_ => Ok(false),
+		}
+	}
+
+	fn find(&self, channel: &str) -> Option<usize> {
+		self.subscriptions
+			.iter()
+			.position(|subscription| *subscription.channel == *channel)
+	}
+
+	fn save(&self) -> io::Result<()> {
+		if let Some(path) = &self.path {
+			std::fs::write(path, serde_json::to_string_pretty(&self.subscriptions)?)?;
+		}
+		Ok(())
+	}
+}
@@ -5,18 +5,135 @@ use std::{
 		Arc,
 	},
 };
-use tokio::sync::{watch::Sender, Mutex};
+use tokio::sync::{oneshot, watch::Sender, Mutex};
 
-use crate::message::Message;
+use crate::{
+	attachment::FileChunk,
+	backfill::BackfillQuery,
+	history::{
+		export::{ExportFormat, ExportRange},
+		retention::RetentionPolicy,
+	},
+	identity::IdentityCommand,
+	message::Message,
+};
 
 pub enum TaskData {
 	SendMessage(Message),
 	ReceiveMessage(Message),
+	Backfill(BackfillQuery),
+	Reply {
+		sender: Box<str>,
+		reply_to: Box<str>,
+		contents: Box<str>,
+	},
+	Edit {
+		sender: Box<str>,
+		target: Box<str>,
+		contents: Box<str>,
+	},
+	Delete {
+		sender: Box<str>,
+		target: Box<str>,
+	},
+	React {
+		sender: Box<str>,
+		target: Box<str>,
+		emoji: Box<str>,
+		removed: bool,
+	},
+	ShowThread(Box<str>),
+	ShowHistory {
+		channel: Box<str>,
+		limit: usize,
+	},
+	// for a UI that answers with the messages, rather than showing them
+	QueryHistory {
+		lookup: HistoryLookup,
+		reply: oneshot::Sender<Result<Vec<Message>, String>>,
+	},
+	Search(Box<str>),
+	Export {
+		format: ExportFormat,
+		path: Box<str>,
+		range: ExportRange,
+	},
+	SetRetention {
+		channel: Box<str>,
+		policy: RetentionPolicy,
+	},
+	// loads messages exported as JSON Lines, skipping those already in history
+	Import(Box<str>),
+	// the name presence and typing notifications are sent as
+	SetIdentity(Box<str>),
+	SetTyping {
+		channel: Box<str>,
+		active: bool,
+	},
+	MarkRead(Box<str>),
+	SetChannelPassphrase {
+		channel: Box<str>,
+		passphrase: Box<str>,
+	},
+	SetChannelPeerKey {
+		channel: Box<str>,
+		public_key: Box<str>,
+	},
+	ClearChannelKey(Box<str>),
+	ShowPublicKey,
+	Identity(IdentityCommand),
+	Tick,
+	// the receiver stopped getting events, and won't reconnect by itself
+	ConnectionLost,
+	// a problem found outside the messenger, such as a malformed event, for the UI to show
+	Error(Box<str>),
+	// like Error, for anything else worth telling the user
+	Notice(Box<str>),
+	SendFile {
+		sender: Box<str>,
+		channel: Box<str>,
+		path: Box<str>,
+	},
+	SendFileChunk(FileChunk),
+	ReceiveFileChunk(FileChunk),
 	NewChannel(Box<str>),
 	RemoveChannel(Box<str>),
+	SetFavourite {
+		channel: Box<str>,
+		favourite: bool,
+	},
+	SetMuted {
+		channel: Box<str>,
+		muted: bool,
+	},
+	ShowChannels,
 	Exit,
 }
 
+impl TaskData {
+	// what publishes something, and is still done when exiting
+	pub fn is_outgoing(&self) -> bool {
+		matches!(
+			self,
+			Self::SendMessage(_)
+				| Self::Reply { .. }
+				| Self::Edit { .. }
+				| Self::Delete { .. }
+				| Self::React { .. }
+				| Self::MarkRead(_)
+				| Self::SendFile { .. }
+				| Self::SendFileChunk(_)
+		)
+	}
+}
+
+// the messages a QueryHistory asks for, as ShowHistory, ShowThread and Search show them
+pub enum HistoryLookup {
+	Channel { channel: Box<str>, limit: usize },
+	Thread(Box<str>),
+	Search(Box<str>),
+}
+
 #[derive(Clone)]
 pub struct TaskQueue {
 	queue: Arc<Mutex<VecDeque<TaskData>>>,
@@ -49,4 +166,12 @@ impl TaskQueue {
 		self.size.fetch_sub(1, Ordering::AcqRel);
 		queue.pop_front().unwrap()
 	}
+
+	// None when there's nothing queued, instead of waiting
+	pub async fn try_pop(&mut self) -> Option<TaskData> {
+		let mut queue = self.queue.lock().await;
+		let task = queue.pop_front()?;
+		self.size.fetch_sub(1, Ordering::AcqRel);
+		Some(task)
+	}
 }
@@ -0,0 +1,655 @@
+/*
+ * The text commands shared by the line-based UIs, each queueing the task it
+ * stands for. Mistakes are returned as a message to show the user.
+ * A line is split into words on spaces, where a word in single or double
+ * quotes may contain spaces, and a backslash outside single quotes escapes the
+ * next character. A command may be written with a leading /, and the last
+ * argument of some commands, such as a message's text, takes the rest of the
+ * line as typed, quotes and spaces included.
+ * Once a channel is joined, lines without a leading / are sent to it as the
+ * session's nick. Until then every line is a command, so scripts can keep
+ * using the explicit forms, such as send <sender> <channel> <text>.
+*/
+use crate::{
+	history::export::{ExportFormat, ExportRange},
+	identity::IdentityCommand,
+	message::Message,
+	task_queue::{TaskData, TaskQueue},
+};
+
+const DEFAULT_HISTORY_LENGTH: usize = 20;
+// for commands whose last argument takes the rest of the line
+const REST: usize = usize::MAX;
+
+pub enum UIStatus {
+	Continue,
+	// text for the UI to show, such as help
+	Output(String),
+	Stop,
+}
+
+#[derive(Default)]
+pub struct Session {
+	// the name messages are sent as
+	pub nick: Option<Box<str>>,
+	// where lines that aren't commands are sent
+	pub channel: Option<Box<str>>,
+}
+
+pub struct CommandSpec {
+	pub name: &'static str,
+	pub arguments: &'static str,
+	pub description: &'static str,
+	min_arguments: usize,
+	max_arguments: usize,
+	// lines with this command aren't kept in history, as they hold a passphrase
+	secret: bool,
+}
+
+const fn command(
+	name: &'static str,
+	arguments: &'static str,
+	description: &'static str,
+	min_arguments: usize,
+	max_arguments: usize,
+) -> CommandSpec {
+	CommandSpec {
+		name,
+		arguments,
+		description,
+		min_arguments,
+		max_arguments,
+		secret: false,
+	}
+}
+
+const fn secret(spec: CommandSpec) -> CommandSpec {
+	CommandSpec {
+		secret: true,
+		..spec
+	}
+}
+
+pub const COMMANDS: &[CommandSpec] = &[
+	command(
+		"help",
+		"[command]",
+		"list the commands, or describe one",
+		0,
+		1,
+	),
+	command("nick", "<name>", "set the name messages are sent as", 1, 1),
+	command(
+		"join",
+		"<channel>",
+		"subscribe to a channel and send lines to it",
+		1,
+		1,
+	),
+	command("add_channel", "<channel>", "subscribe to a channel", 1, 1),
+	command(
+		"remove_channel",
+		"<channel>",
+		"unsubscribe from a channel",
+		1,
+		1,
+	),
+	command("channels", "", "list the subscribed channels", 0, 0),
+	command("favourite", "<channel>", "list a channel first", 1, 1),
+	command(
+		"unfavourite",
+		"<channel>",
+		"stop listing a channel first",
+		1,
+		1,
+	),
+	command(
+		"mute",
+		"<channel>",
+		"keep a channel's messages without showing them",
+		1,
+		1,
+	),
+	command(
+		"unmute",
+		"<channel>",
+		"show a channel's messages again",
+		1,
+		1,
+	),
+	command(
+		"send",
+		"<sender> <channel> <text>",
+		"send a message",
+		3,
+		REST,
+	),
+	command("reply", "<id> <text>", "reply to a message", 2, REST),
+	command(
+		"edit",
+		"<id> <text>",
+		"change the text of a message you sent",
+		2,
+		REST,
+	),
+	command("delete", "<id>", "delete a message you sent", 1, 1),
+	command("react", "<id> <emoji>", "react to a message", 2, 2),
+	command("unreact", "<id> <emoji>", "take back a reaction", 2, 2),
+	command("thread", "<id>", "show a message and its replies", 1, 1),
+	command(
+		"history",
+		"<channel> [count]",
+		"show a channel's latest messages",
+		1,
+		2,
+	),
+	command("search", "<text>", "search the history", 1, REST),
+	command(
+		"export",
+		"<jsonl|text|html> <path> [channel|*] [from] [to]",
+		"export the history, between dates given as YYYY-MM-DD",
+		2,
+		5,
+	),
+	command(
+		"import",
+		"<path>",
+		"import history exported as JSON Lines, shown as unsigned",
+		1,
+		1,
+	),
+	command(
+		"retention",
+		"<channel> <policy>",
+		"how long a channel's history is kept, such as 30d,1000 or keep",
+		2,
+		2,
+	),
+	command("read", "<channel>", "mark a channel as read", 1, 1),
+	secret(command(
+		"encrypt",
+		"<channel> <passphrase>",
+		"encrypt a channel with a shared passphrase",
+		2,
+		REST,
+	)),
+	command(
+		"encrypt_with",
+		"<channel> <public key>",
+		"encrypt a channel with a key exchanged with another user",
+		2,
+		2,
+	),
+	command("unencrypt", "<channel>", "stop encrypting a channel", 1, 1),
+	command("public_key", "", "show the key exchange public key", 0, 0),
+	command(
+		"typing",
+		"<channel> [stop]",
+		"tell a channel you're typing",
+		1,
+		2,
+	),
+	command(
+		"send_file",
+		"<sender> <channel> <path>",
+		"send a file",
+		3,
+		3,
+	),
+	command(
+		"fingerprint",
+		"",
+		"show your identity key's fingerprint",
+		0,
+		0,
+	),
+	secret(command(
+		"export_identity",
+		"<path> <passphrase>",
+		"save your identity key, sealed with a passphrase",
+		2,
+		REST,
+	)),
+	secret(command(
+		"import_identity",
+		"<path> <passphrase>",
+		"replace your identity key with an exported one",
+		2,
+		REST,
+	)),
+	command(
+		"contacts",
+		"",
+		"list the senders whose keys are known",
+		0,
+		0,
+	),
+	command(
+		"verify",
+		"<sender> <fingerprint>",
+		"mark a sender's key as verified",
+		2,
+		REST,
+	),
+	command("revoke", "<sender>", "forget a sender's key", 1, 1),
+	command("exit", "", "quit", 0, 0),
+];
+
+impl Session {
+	// an empty nick is none
+	pub fn new(nick: &str) -> Self {
+		Self {
+			nick: (!nick.is_empty()).then(|| nick.into()),
+			channel: None,
+		}
+	}
+
+	// presence and typing notifications are sent as the nick set in the settings
+	pub async fn start(&self, task_queue: &mut TaskQueue) {
+		if let Some(nick) = &self.nick {
+			task_queue.push(TaskData::SetIdentity(nick.clone())).await;
+		}
+	}
+
+	pub async fn set_nick(&mut self, task_queue: &mut TaskQueue, nick: Box<str>) {
+		if self.nick.as_ref() != Some(&nick) {
+			task_queue.push(TaskData::SetIdentity(nick.clone())).await;
+			self.nick = Some(nick);
+		}
+	}
+
+	pub fn sender(&self) -> Result<Box<str>, String> {
+		self.nick
+			.clone()
+			.ok_or_else(|| "Use nick first, to set the name messages are sent as".to_string())
+	}
+}
+
+impl CommandSpec {
+	pub fn usage(&self) -> String {
+		match self.arguments.is_empty() {
+			true => format!("usage: {}", self.name),
+			false => format!("usage: {} {}", self.name, self.arguments),
+		}
+	}
+}
+
+pub fn find_command(name: &str) -> Option<&'static CommandSpec> {
+	let name = name.strip_prefix('/').unwrap_or(name);
+	COMMANDS.iter().find(|spec| spec.name == name)
+}
+
+// whether a line may be kept in the command history
+pub fn is_secret(line: &str) -> bool {
+	// only the command, as the rest may be a passphrase that doesn't parse
+	next_word(line)
+		.ok()
+		.flatten()
+		.and_then(|(name, _)| find_command(&name))
+		.is_some_and(|spec| spec.secret)
+}
+
+// the next word and the text after it, or None when only spaces are left
+fn next_word(line: &str) -> Result<Option<(String, &str)>, String> {
+	let line = line.trim_start();
+	let mut word: Option<String> = None;
+	let mut quote = None;
+	let mut chars = line.char_indices();
+
+	while let Some((index, c)) = chars.next() {
+		match (c, quote) {
+			('\\', Some('"') | None) => match chars.next() {
+				Some((_, escaped)) => word.get_or_insert_default().push(escaped),
+				None => return Err("Nothing to escape at the end of the line".to_string()),
+			},
+			(c, Some(open)) if c == open => quote = None,
+			(c, Some(_)) => word.get_or_insert_default().push(c),
+			('"' | '\'', None) => {
+				quote = Some(c);
+				word.get_or_insert_default();
+			}
+			(c, None) if c.is_whitespace() => return Ok(word.map(|word| (word, &line[index..]))),
+			(c, None) => word.get_or_insert_default().push(c),
+		}
+	}
+
+	if quote.is_some() {
+		return Err("Missing a closing quote".to_string());
+	}
+	Ok(word.map(|word| (word, "")))
+}
+
+/*
+ * the arguments after the command, where one taking the rest of the line is
+ * kept as typed, rather than split into words
+ */
+fn split_arguments(spec: &CommandSpec, mut line: &str) -> Result<Vec<String>, String> {
+	let mut args = Vec::new();
+	loop {
+		if spec.max_arguments == REST && args.len() + 1 == spec.min_arguments {
+			let rest = line.trim_start();
+			if !rest.is_empty() {
+				args.push(rest.to_string());
+			}
+			return Ok(args);
+		}
+		match next_word(line)? {
+			Some((word, after)) => {
+				args.push(word);
+				line = after;
+			}
+			None => return Ok(args),
+		}
+	}
+}
+
+// the word as it would have to be typed, such as a channel name with spaces
+pub fn quote_word(word: &str) -> String {
+	let plain = !word.is_empty()
+		&& !word
+			.chars()
+			.any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\'));
+	match plain {
+		true => word.to_string(),
+		false => format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\"")),
+	}
+}
+
+fn help(command: Option<&str>) -> Result<UIStatus, String> {
+	let help = match command {
+		Some(command) => {
+			let spec =
+				find_command(command).ok_or_else(|| format!("Unknown command: {}", command))?;
+			format!("{}\n  {}", spec.usage(), spec.description)
+		}
+		None => {
+			let width = COMMANDS
+				.iter()
+				.map(|spec| spec.name.len() + spec.arguments.len())
+				.max()
+				.unwrap_or(0);
+			let lines: Vec<String> = COMMANDS
+				.iter()
+				.map(|spec| {
+					let usage = format!("{} {}", spec.name, spec.arguments);
+					format!("{:width$}  {}", usage, spec.description, width = width + 1)
+				})
+				.collect();
+			lines.join("\n")
+		}
+	};
+	Ok(UIStatus::Output(help))
+}
+
+pub async fn handle_line(
+	task_queue: &mut TaskQueue,
+	session: &mut Session,
+	line: &str,
+) -> Result<UIStatus, String> {
+	let channel = match &session.channel {
+		Some(channel) if !line.is_empty() && !line.starts_with('/') => channel.clone(),
+		_ => return handle_command(task_queue, session, line).await,
+	};
+
+	let message = Message::text(&session.sender()?, &channel, line);
+	task_queue.push(TaskData::SendMessage(message)).await;
+	Ok(UIStatus::Continue)
+}
+
+async fn handle_command(
+	task_queue: &mut TaskQueue,
+	session: &mut Session,
+	line: &str,
+) -> Result<UIStatus, String> {
+	let (command, line) = match next_word(line)? {
+		Some(command) => command,
+		None => return Ok(UIStatus::Continue),
+	};
+	let spec =
+		find_command(&command).ok_or_else(|| format!("Unknown command: {} (try help)", command))?;
+	let args = split_arguments(spec, line)?;
+	if args.len() < spec.min_arguments || args.len() > spec.max_arguments {
+		return Err(spec.usage());
+	}
+
+	let arg = |index: usize| -> Box<str> { args.get(index).map_or("", String::as_str).into() };
+
+	match spec.name {
+		"help" => return help(args.first().map(String::as_str)),
+		"nick" => session.set_nick(task_queue, arg(0)).await,
+		"join" => {
+			task_queue.push(TaskData::NewChannel(arg(0))).await;
+			session.channel = Some(arg(0));
+		}
+		"add_channel" => task_queue.push(TaskData::NewChannel(arg(0))).await,
+		"remove_channel" => {
+			if session.channel.as_ref() == Some(&arg(0)) {
+				session.channel = None;
+			}
+			task_queue.push(TaskData::RemoveChannel(arg(0))).await
+		}
+		"channels" => task_queue.push(TaskData::ShowChannels).await,
+		"favourite" | "unfavourite" => {
+			let task = TaskData::SetFavourite {
+				channel: arg(0),
+				favourite: spec.name == "favourite",
+			};
+			task_queue.push(task).await;
+		}
+		"mute" | "unmute" => {
+			let task = TaskData::SetMuted {
+				channel: arg(0),
+				muted: spec.name == "mute",
+			};
+			task_queue.push(task).await;
+		}
+		"send" => {
+			// the sender is only for this message, the nick is kept
+			let message = Message::text(&arg(0), &arg(1), &arg(2));
+			task_queue.push(TaskData::SendMessage(message)).await;
+		}
+		"reply" => {
+			let task = TaskData::Reply {
+				sender: session.sender()?,
+				reply_to: arg(0),
+				contents: arg(1),
+			};
+			task_queue.push(task).await;
+		}
+		"edit" => {
+			let task = TaskData::Edit {
+				sender: session.sender()?,
+				target: arg(0),
+				contents: arg(1),
+			};
+			task_queue.push(task).await;
+		}
+		"delete" => {
+			let task = TaskData::Delete {
+				sender: session.sender()?,
+				target: arg(0),
+			};
+			task_queue.push(task).await;
+		}
+		"react" | "unreact" => {
+			let task = TaskData::React {
+				sender: session.sender()?,
+				target: arg(0),
+				emoji: arg(1),
+				removed: spec.name == "unreact",
+			};
+			task_queue.push(task).await;
+		}
+		"thread" => task_queue.push(TaskData::ShowThread(arg(0))).await,
+		"history" => {
+			let limit = match args.get(1) {
+				Some(count) => count
+					.parse()
+					.map_err(|_| format!("Not a number of messages: {}", count))?,
+				None => DEFAULT_HISTORY_LENGTH,
+			};
+			let task = TaskData::ShowHistory {
+				channel: arg(0),
+				limit,
+			};
+			task_queue.push(task).await;
+		}
+		"export" => {
+			let range: Vec<&str> = args[2..].iter().map(String::as_str).collect();
+			let task = match (arg(0).parse::<ExportFormat>(), ExportRange::parse(&range)) {
+				(Ok(format), Some(range)) => TaskData::Export {
+					format,
+					path: arg(1),
+					range,
+				},
+				(Err(e), _) => return Err(e),
+				(_, None) => return Err("Dates should be given as YYYY-MM-DD".to_string()),
+			};
+			task_queue.push(task).await;
+		}
+		"retention" => {
+			let task = TaskData::SetRetention {
+				channel: arg(0),
+				policy: arg(1).parse()?,
+			};
+			task_queue.push(task).await;
+		}
+		"import" => task_queue.push(TaskData::Import(arg(0))).await,
+		"search" => task_queue.push(TaskData::Search(arg(0))).await,
+		"read" => task_queue.push(TaskData::MarkRead(arg(0))).await,
+		"encrypt" => {
+			let task = TaskData::SetChannelPassphrase {
+				channel: arg(0),
+				passphrase: arg(1),
+			};
+			task_queue.push(task).await;
+		}
+		"encrypt_with" => {
+			let task = TaskData::SetChannelPeerKey {
+				channel: arg(0),
+				public_key: arg(1),
+			};
+			task_queue.push(task).await;
+		}
+		"unencrypt" => task_queue.push(TaskData::ClearChannelKey(arg(0))).await,
+		"public_key" => task_queue.push(TaskData::ShowPublicKey).await,
+		"typing" => {
+			let active = match args.get(1).map(String::as_str) {
+				None => true,
+				Some("stop") => false,
+				Some(_) => return Err(spec.usage()),
+			};
+			let task = TaskData::SetTyping {
+				channel: arg(0),
+				active,
+			};
+			task_queue.push(task).await;
+		}
+		"send_file" => {
+			let task = TaskData::SendFile {
+				sender: arg(0),
+				channel: arg(1),
+				path: arg(2),
+			};
+			task_queue.push(task).await;
+		}
+		"exit" => return Ok(UIStatus::Stop),
+		_ => {
+			let args: Vec<&str> = args.iter().map(String::as_str).collect();
+			let identity_command =
+				IdentityCommand::parse(spec.name, &args).ok_or_else(|| spec.usage())?;
+			task_queue.push(TaskData::Identity(identity_command)).await
+		}
+	}
+
+	Ok(UIStatus::Continue)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn words(mut line: &str) -> Result<Vec<String>, String> {
+		let mut words = Vec::new();
+		while let Some((word, after)) = next_word(line)? {
+			words.push(word);
+			line = after;
+		}
+		Ok(words)
+	}
+
+	fn arguments(command: &str, line: &str) -> Result<Vec<String>, String> {
+		split_arguments(find_command(command).unwrap(), line)
+	}
+
+	#[test]
+	fn words_are_split_on_spaces_outside_quotes() {
+		assert_eq!(words("  a  b ").unwrap(), ["a", "b"]);
+		assert_eq!(words(r#""a b" 'c d'"#).unwrap(), ["a b", "c d"]);
+		assert_eq!(words(r#"a"b c"d"#).unwrap(), ["ab cd"]);
+		assert_eq!(words(r#"a\ b \"c"#).unwrap(), ["a b", "\"c"]);
+		// nothing is escaped within single quotes
+		assert_eq!(words(r#"'a\b'"#).unwrap(), [r"a\b"]);
+		assert_eq!(words(r#""" ''"#).unwrap(), ["", ""]);
+		assert!(words("   ").unwrap().is_empty());
+	}
+
+	#[test]
+	fn unfinished_words_fail() {
+		assert!(words(r#""a b"#).is_err());
+		assert!(words("'a").is_err());
+		assert!(words(r"a\").is_err());
+	}
+
+	#[test]
+	fn the_rest_of_the_line_is_kept_as_typed() {
+		assert_eq!(
+			arguments("send", r#"me "my channel"   it's  "quoted" \n"#).unwrap(),
+			["me", "my channel", r#"it's  "quoted" \n"#]
+		);
+		assert_eq!(
+			arguments("encrypt", r#"team  a "secret" passphrase"#).unwrap(),
+			["team", r#"a "secret" passphrase"#]
+		);
+		assert_eq!(arguments("search", "  two words").unwrap(), ["two words"]);
+		// too few arguments are left for the usage to be shown
+		assert_eq!(arguments("reply", "abcd1234 ").unwrap(), ["abcd1234"]);
+	}
+
+	#[test]
+	fn other_arguments_are_split_into_words() {
+		assert_eq!(
+			arguments("send_file", r#"me general "a file.txt""#).unwrap(),
+			["me", "general", "a file.txt"]
+		);
+		assert_eq!(
+			arguments("react", "abcd1234 👍").unwrap(),
+			["abcd1234", "👍"]
+		);
+	}
+
+	#[test]
+	fn secret_commands_are_recognised() {
+		assert!(is_secret("/encrypt team passphrase"));
+		assert!(is_secret("  export_identity key.json \"unfinished"));
+		assert!(is_secret("/import_identity"));
+		assert!(!is_secret("/send general encrypt"));
+		assert!(!is_secret("\"unfinished"));
+		assert!(!is_secret(""));
+	}
+
+	#[test]
+	fn quoted_words_read_back_the_same() {
+		for word in [
+			"plain",
+			"two words",
+			"",
+			r#"a "quote""#,
+			r"back\slash",
+			"it's",
+		] {
+			assert_eq!(words(&quote_word(word)).unwrap(), [word]);
+		}
+		assert_eq!(quote_word("plain"), "plain");
+	}
+}
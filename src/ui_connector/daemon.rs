@@ -0,0 +1,643 @@
+/*
+ * Runs the client headless, controlled over a Unix socket in the data
+ * directory by any number of attached clients at once, such as `ctl` below.
+ * Only the user running it may connect.
+ *
+ * The protocol is JSON-RPC 2.0, with one JSON object per line each way.
+ * The methods are the commands of the JSON Lines UI, see json_lines.rs, taking
+ * their fields as named params, along with:
+ *   watch      streams events to this client, as notifications of the
+ *              "event" method whose params are the JSON Lines UI's events,
+ *              returns {"version": 1}
+ *   shutdown   stops the daemon
+ * and exit detaches the client calling it. history, thread and search return
+ * {"messages": [...]}, oldest first, rather than a history event. For example:
+ *   -> {"jsonrpc": "2.0", "id": 1, "method": "send", "params": {"channel": "general", "text": "hi"}}
+ *   <- {"jsonrpc": "2.0", "id": 1, "result": {"message_id": "..."}}
+ * Each client has its own nick, starting as the one in the settings, though
+ * presence is sent as whichever nick was set last.
+ * A client reading events too slowly skips those it missed, and is told how
+ * many with an error event.
+*/
+use std::{
+	os::unix::fs::PermissionsExt,
+	path::{Path, PathBuf},
+	sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::{
+	io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+	net::{UnixListener, UnixStream},
+	sync::{
+		broadcast::{self, error::RecvError},
+		oneshot,
+	},
+	task::{JoinHandle, JoinSet},
+};
+
+use crate::{
+	attachment::{Attachment, TransferProgress},
+	message::Message,
+	subscriptions::Subscription,
+	task_queue::{HistoryLookup, TaskData, TaskQueue},
+};
+
+use super::{
+	commands::Session,
+	json_lines::{Event, JsonLinesUI, Request, PROTOCOL_VERSION},
+	ConnectionState, UIConnector,
+};
+
+pub const SOCKET_FILE: &str = "messenger.sock";
+// events kept for each watching client, before it starts missing them
+const EVENT_BUFFER: usize = 1024;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// a command that was understood but can't be done, such as sending without a nick
+const COMMAND_FAILED: i64 = -32000;
+
+#[derive(Deserialize)]
+struct RpcRequest {
+	// none for notifications, which aren't answered
+	id: Option<Value>,
+	method: String,
+	#[serde(default)]
+	params: Option<Value>,
+}
+
+#[derive(Serialize)]
+struct RpcResponse {
+	jsonrpc: &'static str,
+	id: Value,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	result: Option<Value>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	error: Option<RpcError>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RpcError {
+	code: i64,
+	message: String,
+}
+
+#[derive(Serialize)]
+struct RpcNotification<'a> {
+	jsonrpc: &'static str,
+	method: &'static str,
+	params: &'a Event<'a>,
+}
+
+// what's left to do after answering a client
+enum ClientStatus {
+	Continue,
+	Watch,
+	Detach,
+}
+
+pub struct DaemonUI {
+	socket_path: PathBuf,
+	nick: Box<str>,
+	// each event as a line ready to write, for the clients watching
+	events: broadcast::Sender<Arc<str>>,
+	listener_task: Option<JoinHandle<()>>,
+}
+
+impl DaemonUI {
+	// the nick may be empty, for clients to set with the nick method
+	pub fn new(data_directory: &str, nick: &str) -> Self {
+		let (events, _) = broadcast::channel(EVENT_BUFFER);
+		Self {
+			socket_path: Path::new(data_directory).join(SOCKET_FILE),
+			nick: nick.into(),
+			events,
+			listener_task: None,
+		}
+	}
+
+	// there may be no one watching, in which case the event is dropped
+	fn emit(&self, event: &Event) {
+		match notification(event) {
+			Ok(line) => {
+				self.events.send(line.into()).unwrap_or(0);
+			}
+			Err(e) => eprintln!("Error writing event: {}", e),
+		}
+	}
+
+	async fn listen(
+		listener: UnixListener,
+		task_queue: TaskQueue,
+		events: broadcast::Sender<Arc<str>>,
+		nick: Box<str>,
+	) {
+		// dropped along with this task, which stops the clients
+		let mut clients = JoinSet::new();
+		loop {
+			tokio::select! {
+				accepted = listener.accept() => match accepted {
+					Ok((stream, _)) => {
+						let client = Self::serve_client(
+							stream,
+							task_queue.clone(),
+							events.subscribe(),
+							Session::new(&nick),
+						);
+						clients.spawn(client);
+					}
+					Err(e) => eprintln!("Error accepting a client: {}", e),
+				},
+				// forget the clients that detached
+				Some(_) = clients.join_next() => (),
+			}
+		}
+	}
+
+	async fn serve_client(
+		stream: UnixStream,
+		mut task_queue: TaskQueue,
+		mut events: broadcast::Receiver<Arc<str>>,
+		mut session: Session,
+	) {
+		let (reader, mut writer) = stream.into_split();
+		let mut lines = BufReader::new(reader).lines();
+		let mut watching = false;
+
+		loop {
+			tokio::select! {
+				line = lines.next_line() => {
+					let line = match line {
+						Ok(Some(line)) => line,
+						Ok(None) | Err(_) => break,
+					};
+					if line.trim().is_empty() {
+						continue;
+					}
+
+					let (response, status) =
+						Self::handle_request(&mut task_queue, &mut session, &line).await;
+					if let Some(response) = response {
+						if write_line(&mut writer, &response).await.is_err() {
+							break;
+						}
+					}
+					match status {
+						ClientStatus::Continue => (),
+						// only events from now on
+						ClientStatus::Watch if !watching => {
+							events = events.resubscribe();
+							watching = true;
+						}
+						ClientStatus::Watch => (),
+						ClientStatus::Detach => break,
+					}
+				}
+				event = events.recv(), if watching => {
+					let line = match event {
+						Ok(line) => line.to_string(),
+						Err(RecvError::Lagged(missed)) => {
+							let text = format!("Missed {} events, reading too slowly", missed);
+							match notification(&Event::Error { text }) {
+								Ok(line) => line,
+								Err(_) => continue,
+							}
+						}
+						Err(RecvError::Closed) => break,
+					};
+					if write_line(&mut writer, &line).await.is_err() {
+						break;
+					}
+				}
+			}
+		}
+	}
+
+	// the response is none for notifications
+	async fn handle_request(
+		task_queue: &mut TaskQueue,
+		session: &mut Session,
+		line: &str,
+	) -> (Option<String>, ClientStatus) {
+		let request: RpcRequest = match serde_json::from_str(line) {
+			Ok(request) => request,
+			Err(e) if e.is_data() => {
+				let error = rpc_error(INVALID_REQUEST, format!("Invalid request: {}", e));
+				return (response(Some(Value::Null), error), ClientStatus::Continue);
+			}
+			Err(e) => {
+				let error = rpc_error(PARSE_ERROR, format!("Parse error: {}", e));
+				return (response(Some(Value::Null), error), ClientStatus::Continue);
+			}
+		};
+
+		let (result, status) =
+			Self::call(task_queue, session, request.method, request.params).await;
+		(response(request.id, result), status)
+	}
+
+	async fn call(
+		task_queue: &mut TaskQueue,
+		session: &mut Session,
+		method: String,
+		params: Option<Value>,
+	) -> (Result<Value, RpcError>, ClientStatus) {
+		match method.as_str() {
+			"watch" => {
+				let result = serde_json::json!({ "version": PROTOCOL_VERSION });
+				return (Ok(result), ClientStatus::Watch);
+			}
+			"shutdown" => {
+				task_queue.push(TaskData::Exit).await;
+				return (Ok(Value::Null), ClientStatus::Continue);
+			}
+			_ => (),
+		}
+
+		let mut params = match params {
+			Some(Value::Object(params)) => params,
+			None => Map::new(),
+			Some(_) => {
+				let error = rpc_error(INVALID_PARAMS, "Params should be an object".to_string());
+				return (error, ClientStatus::Continue);
+			}
+		};
+		params.insert("command".to_string(), Value::String(method.clone()));
+		let request = match Request::deserialize(Value::Object(params)) {
+			Ok(request) => request,
+			// serde's own wording for a tag it doesn't know
+			Err(e) if e.to_string().starts_with("unknown variant") => {
+				let error = rpc_error(METHOD_NOT_FOUND, format!("Unknown method: {}", method));
+				return (error, ClientStatus::Continue);
+			}
+			Err(e) => {
+				let error = rpc_error(INVALID_PARAMS, format!("Invalid params: {}", e));
+				return (error, ClientStatus::Continue);
+			}
+		};
+
+		// answered with the messages, for callers that aren't watching
+		let lookup = match request {
+			Request::History { channel, limit } => HistoryLookup::Channel { channel, limit },
+			Request::Thread { message_id } => HistoryLookup::Thread(message_id),
+			Request::Search { text } => HistoryLookup::Search(text),
+			request => return Self::run_command(task_queue, session, request).await,
+		};
+		let (reply, messages) = oneshot::channel();
+		task_queue
+			.push(TaskData::QueryHistory { lookup, reply })
+			.await;
+		let result = match messages.await {
+			Ok(Ok(messages)) => Ok(serde_json::json!({ "messages": messages })),
+			Ok(Err(e)) => rpc_error(COMMAND_FAILED, e),
+			Err(_) => rpc_error(COMMAND_FAILED, "The daemon is shutting down".to_string()),
+		};
+		(result, ClientStatus::Continue)
+	}
+
+	async fn run_command(
+		task_queue: &mut TaskQueue,
+		session: &mut Session,
+		request: Request,
+	) -> (Result<Value, RpcError>, ClientStatus) {
+		match JsonLinesUI::handle_command(task_queue, session, request).await {
+			Ok(outcome) => {
+				let status = match outcome.stop {
+					true => ClientStatus::Detach,
+					false => ClientStatus::Continue,
+				};
+				let result = serde_json::to_value(&outcome).unwrap_or(Value::Null);
+				(Ok(result), status)
+			}
+			Err(e) => (rpc_error(COMMAND_FAILED, e), ClientStatus::Continue),
+		}
+	}
+}
+
+impl UIConnector for DaemonUI {
+	fn message_received(&mut self, message: Message) {
+		self.emit(&Event::Message { message })
+	}
+
+	fn message_updated(&mut self, message: Message) {
+		self.emit(&Event::Updated { message })
+	}
+
+	fn message_sent(&mut self, message: &Message, result: Result<(), String>) {
+		self.emit(&Event::sent(message, result))
+	}
+
+	fn history_received(&mut self, messages: Vec<Message>) {
+		self.emit(&Event::History { messages })
+	}
+
+	fn presence_changed(&mut self, channel: &str, online: Vec<Box<str>>) {
+		self.emit(&Event::Presence { channel, online })
+	}
+
+	fn typing_changed(&mut self, channel: &str, typing: Vec<Box<str>>) {
+		self.emit(&Event::Typing { channel, typing })
+	}
+
+	fn unread_changed(&mut self, channel: &str, unread: usize) {
+		self.emit(&Event::Unread { channel, unread })
+	}
+
+	fn channels_changed(&mut self, channels: Vec<Subscription>) {
+		self.emit(&Event::Channels { channels })
+	}
+
+	fn channel_encryption_changed(&mut self, channel: &str, encrypted: bool) {
+		self.emit(&Event::Encryption { channel, encrypted })
+	}
+
+	fn file_received(&mut self, attachment: Attachment) {
+		self.emit(&Event::file(attachment))
+	}
+
+	fn transfer_progress(&mut self, progress: TransferProgress) {
+		self.emit(&Event::transfer(progress))
+	}
+
+	fn connection_changed(&mut self, state: ConnectionState) {
+		self.emit(&Event::Connection { state })
+	}
+
+	// also on stderr, for whoever runs the daemon
+	fn notice(&mut self, text: String) {
+		eprintln!("{}", text);
+		self.emit(&Event::Notice { text })
+	}
+
+	fn error(&mut self, text: String) {
+		eprintln!("{}", text);
+		self.emit(&Event::Error { text })
+	}
+
+	fn start(&mut self, mut task_queue: TaskQueue) {
+		// left behind by a daemon that didn't shut down, as only one can run per data directory
+		if self.socket_path.exists()
+			&& std::os::unix::net::UnixStream::connect(&self.socket_path).is_err()
+		{
+			std::fs::remove_file(&self.socket_path).unwrap_or(());
+		}
+
+		// only for this user, as clients can run any command, such as export_identity
+		let listener = UnixListener::bind(&self.socket_path).and_then(|listener| {
+			let owner_only = std::fs::Permissions::from_mode(0o600);
+			std::fs::set_permissions(&self.socket_path, owner_only)?;
+			Ok(listener)
+		});
+		let listener = match listener {
+			Ok(listener) => listener,
+			Err(e) => {
+				eprintln!("Could not listen on {}: {}", self.socket_path.display(), e);
+				tokio::task::spawn(async move { task_queue.push(TaskData::Exit).await });
+				return;
+			}
+		};
+		eprintln!("Listening on {}", self.socket_path.display());
+
+		let session = Session::new(&self.nick);
+		let events = self.events.clone();
+		let nick = self.nick.clone();
+		self.listener_task = Some(tokio::task::spawn(async move {
+			session.start(&mut task_queue).await;
+			Self::listen(listener, task_queue, events, nick).await
+		}));
+	}
+
+	async fn shutdown(&mut self) {
+		if let Some(listener_task) = self.listener_task.take() {
+			listener_task.abort();
+			listener_task.await.unwrap_or(());
+			std::fs::remove_file(&self.socket_path).unwrap_or(());
+		}
+	}
+}
+
+/*
+ * the thin client for the daemon: calls one method with params given as
+ * key=value, for strings, or key:=value, for any other JSON such as numbers,
+ * and prints its result. Watching prints events until the daemon stops
+ */
+pub async fn ctl(data_directory: &str, method: &str, args: &[&str]) -> Result<(), String> {
+	let params = ctl_params(args)?;
+	let socket_path = Path::new(data_directory).join(SOCKET_FILE);
+	let stream = UnixStream::connect(&socket_path)
+		.await
+		.map_err(|e| format!("could not connect to {}: {}", socket_path.display(), e))?;
+	let (reader, mut writer) = stream.into_split();
+	let mut lines = BufReader::new(reader).lines();
+
+	let request = serde_json::json!({
+		"jsonrpc": "2.0",
+		"id": 1,
+		"method": method,
+		"params": params,
+	});
+	write_line(&mut writer, &request.to_string())
+		.await
+		.map_err(|e| e.to_string())?;
+
+	while let Some(line) = lines.next_line().await.map_err(|e| e.to_string())? {
+		let mut reply: Map<String, Value> =
+			serde_json::from_str(&line).map_err(|e| e.to_string())?;
+		// events, while watching
+		if reply.get("method").and_then(Value::as_str) == Some("event") {
+			println!("{}", reply.remove("params").unwrap_or_default());
+			continue;
+		}
+
+		if let Some(error) = reply.remove("error") {
+			let error: RpcError = serde_json::from_value(error).map_err(|e| e.to_string())?;
+			return Err(error.message);
+		}
+		match reply.remove("result") {
+			Some(Value::Object(result)) if result.is_empty() => (),
+			Some(Value::Null) | None => (),
+			Some(result) => println!("{}", result),
+		}
+		if method != "watch" {
+			return Ok(());
+		}
+	}
+
+	Ok(())
+}
+
+fn ctl_params(args: &[&str]) -> Result<Map<String, Value>, String> {
+	let mut params = Map::new();
+	for arg in args {
+		let (key, value) = match arg.split_once(":=") {
+			Some((key, json)) => {
+				let value = serde_json::from_str(json)
+					.map_err(|e| format!("bad JSON for {}: {}", key, e))?;
+				(key, value)
+			}
+			None => match arg.split_once('=') {
+				Some((key, text)) => (key, Value::String(text.to_string())),
+				None => return Err(format!("expected key=value or key:=json, got {}", arg)),
+			},
+		};
+		params.insert(key.to_string(), value);
+	}
+	Ok(params)
+}
+
+fn notification(event: &Event) -> serde_json::Result<String> {
+	serde_json::to_string(&RpcNotification {
+		jsonrpc: "2.0",
+		method: "event",
+		params: event,
+	})
+}
+
+fn response(id: Option<Value>, result: Result<Value, RpcError>) -> Option<String> {
+	let (result, error) = match result {
+		Ok(result) => (Some(result), None),
+		Err(error) => (None, Some(error)),
+	};
+	let response = RpcResponse {
+		jsonrpc: "2.0",
+		id: id?,
+		result,
+		error,
+	};
+	serde_json::to_string(&response).ok()
+}
+
+fn rpc_error<T>(code: i64, message: String) -> Result<T, RpcError> {
+	Err(RpcError { code, message })
+}
+
+async fn write_line(writer: &mut (impl AsyncWrite + Unpin), line: &str) -> std::io::Result<()> {
+	writer.write_all(line.as_bytes()).await?;
+	writer.write_all(b"\n").await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// the response to the request, as JSON, and what's left to do
+	async fn request(
+		task_queue: &mut TaskQueue,
+		session: &mut Session,
+		line: &str,
+	) -> (Option<Value>, ClientStatus) {
+		let (response, status) = DaemonUI::handle_request(task_queue, session, line).await;
+		let response = response.map(|response| serde_json::from_str(&response).unwrap());
+		(response, status)
+	}
+
+	async fn error_code(line: &str) -> Value {
+		let (mut task_queue, mut session) = (TaskQueue::new(), Session::new("alice"));
+		let (response, _) = request(&mut task_queue, &mut session, line).await;
+		response.unwrap()["error"]["code"].clone()
+	}
+
+	#[tokio::test]
+	async fn bad_requests_get_their_error_codes() {
+		assert_eq!(error_code("{not json").await, PARSE_ERROR);
+		assert_eq!(error_code(r#"{"id": 1}"#).await, INVALID_REQUEST);
+		assert_eq!(
+			error_code(r#"{"id": 1, "method": "launch"}"#).await,
+			METHOD_NOT_FOUND
+		);
+		assert_eq!(
+			error_code(r#"{"id": 1, "method": "send", "params": [1]}"#).await,
+			INVALID_PARAMS
+		);
+		assert_eq!(
+			error_code(r#"{"id": 1, "method": "send", "params": {"channel": "general"}}"#).await,
+			INVALID_PARAMS
+		);
+	}
+
+	#[tokio::test]
+	async fn commands_are_queued_and_answered() {
+		let (mut task_queue, mut session) = (TaskQueue::new(), Session::new("alice"));
+		let line = r#"{"jsonrpc": "2.0", "id": "a", "method": "send", "params": {"channel": "general", "text": "hi"}}"#;
+		let (response, _) = request(&mut task_queue, &mut session, line).await;
+		let response = response.unwrap();
+
+		assert_eq!(response["id"], "a");
+		let TaskData::SendMessage(message) = task_queue.try_pop().await.unwrap() else {
+			panic!("no message sent");
+		};
+		assert_eq!(&*message.sender, "alice");
+		assert_eq!(response["result"]["message_id"], message.id.to_string());
+	}
+
+	#[tokio::test]
+	async fn notifications_are_not_answered() {
+		let (mut task_queue, mut session) = (TaskQueue::new(), Session::new(""));
+		let line = r#"{"jsonrpc": "2.0", "method": "nick", "params": {"nick": "bob"}}"#;
+		let (response, _) = request(&mut task_queue, &mut session, line).await;
+
+		assert!(response.is_none());
+		assert_eq!(session.sender().unwrap(), "bob".into());
+	}
+
+	#[tokio::test]
+	async fn history_is_returned_in_the_result() {
+		let (mut task_queue, mut session) = (TaskQueue::new(), Session::new("alice"));
+		let mut messenger = task_queue.clone();
+		let answer = tokio::spawn(async move {
+			let TaskData::QueryHistory { lookup, reply } = messenger.pop().await else {
+				panic!("no history query");
+			};
+			let HistoryLookup::Channel { channel, limit } = lookup else {
+				panic!("not a channel's history");
+			};
+			assert_eq!((&*channel, limit), ("general", 5));
+			let message = Message::text("bob", "general", "hello");
+			reply.send(Ok(vec![message])).unwrap();
+		});
+
+		let line =
+			r#"{"id": 2, "method": "history", "params": {"channel": "general", "limit": 5}}"#;
+		let (response, _) = request(&mut task_queue, &mut session, line).await;
+		answer.await.unwrap();
+
+		let messages = &response.unwrap()["result"]["messages"];
+		assert_eq!(messages[0]["contents"], "hello");
+	}
+
+	#[tokio::test]
+	async fn watch_and_exit_change_the_client() {
+		let (mut task_queue, mut session) = (TaskQueue::new(), Session::new("alice"));
+		let (response, status) = request(
+			&mut task_queue,
+			&mut session,
+			r#"{"id": 1, "method": "watch"}"#,
+		)
+		.await;
+		assert_eq!(response.unwrap()["result"]["version"], PROTOCOL_VERSION);
+		assert!(matches!(status, ClientStatus::Watch));
+
+		let (_, status) = request(
+			&mut task_queue,
+			&mut session,
+			r#"{"id": 2, "method": "exit"}"#,
+		)
+		.await;
+		assert!(matches!(status, ClientStatus::Detach));
+	}
+
+	#[test]
+	fn ctl_params_take_strings_and_json() {
+		let params = ctl_params(&["channel=general", "limit:=5", "text=a=b"]).unwrap();
+		assert_eq!(params["channel"], "general");
+		assert_eq!(params["limit"], 5);
+		assert_eq!(params["text"], "a=b");
+
+		assert!(ctl_params(&["limit:=five"]).is_err());
+		assert!(ctl_params(&["channel"]).is_err());
+	}
+}
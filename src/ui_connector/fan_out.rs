@@ -0,0 +1,208 @@
+/*
+ * Runs several UIs at once, such as the terminal UI along with the web UI and
+ * a transcript, by passing every event on to each of them. Each gets the task
+ * queue when started, so any of them can send commands.
+ * Each UI runs on its own task with its own queue of events, so one handling
+ * them slowly doesn't hold up the messenger or the others. Once its queue is
+ * full it skips the events it missed, and is told how many with an error.
+*/
+use std::time::Duration;
+
+use tokio::{
+	sync::mpsc::{self, error::TrySendError},
+	task::JoinHandle,
+};
+
+use super::{ConnectionState, UIConnector};
+use crate::{
+	attachment::{Attachment, TransferProgress},
+	message::Message,
+	subscriptions::Subscription,
+	task_queue::TaskQueue,
+};
+
+const EVENT_BUFFER: usize = 1024;
+// a UI stuck on its events is stopped rather than waited for
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// the calls on UIConnector, to be made on a UI's own task
+#[derive(Clone)]
+enum UIEvent {
+	MessageReceived(Message),
+	MessageUpdated(Message),
+	MessageSent(Message, Result<(), String>),
+	HistoryReceived(Vec<Message>),
+	PresenceChanged(Box<str>, Vec<Box<str>>),
+	TypingChanged(Box<str>, Vec<Box<str>>),
+	UnreadChanged(Box<str>, usize),
+	ChannelsChanged(Vec<Subscription>),
+	ChannelEncryptionChanged(Box<str>, bool),
+	FileReceived(Attachment),
+	TransferProgress(TransferProgress),
+	ConnectionChanged(ConnectionState),
+	Notice(String),
+	Error(String),
+	Start(TaskQueue),
+	Shutdown,
+}
+
+struct Output {
+	events: mpsc::Sender<UIEvent>,
+	// events skipped since the queue was last full
+	missed: usize,
+	task: JoinHandle<()>,
+}
+
+#[derive(Default)]
+pub struct FanOutUI {
+	outputs: Vec<Output>,
+}
+
+impl FanOutUI {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn with<T: UIConnector + Send + 'static>(mut self, ui_connector: T) -> Self {
+		let (events, receiver) = mpsc::channel(EVENT_BUFFER);
+		let task = tokio::task::spawn(Self::run(ui_connector, receiver));
+		self.outputs.push(Output {
+			events,
+			missed: 0,
+			task,
+		});
+		self
+	}
+
+	async fn run<T: UIConnector>(mut ui_connector: T, mut events: mpsc::Receiver<UIEvent>) {
+		while let Some(event) = events.recv().await {
+			match event {
+				UIEvent::MessageReceived(message) => ui_connector.message_received(message),
+				UIEvent::MessageUpdated(message) => ui_connector.message_updated(message),
+				UIEvent::MessageSent(message, result) => {
+					ui_connector.message_sent(&message, result)
+				}
+				UIEvent::HistoryReceived(messages) => ui_connector.history_received(messages),
+				UIEvent::PresenceChanged(channel, online) => {
+					ui_connector.presence_changed(&channel, online)
+				}
+				UIEvent::TypingChanged(channel, typing) => {
+					ui_connector.typing_changed(&channel, typing)
+				}
+				UIEvent::UnreadChanged(channel, unread) => {
+					ui_connector.unread_changed(&channel, unread)
+				}
+				UIEvent::ChannelsChanged(channels) => ui_connector.channels_changed(channels),
+				UIEvent::ChannelEncryptionChanged(channel, encrypted) => {
+					ui_connector.channel_encryption_changed(&channel, encrypted)
+				}
+				UIEvent::FileReceived(attachment) => ui_connector.file_received(attachment),
+				UIEvent::TransferProgress(progress) => ui_connector.transfer_progress(progress),
+				UIEvent::ConnectionChanged(state) => ui_connector.connection_changed(state),
+				UIEvent::Notice(text) => ui_connector.notice(text),
+				UIEvent::Error(text) => ui_connector.error(text),
+				UIEvent::Start(task_queue) => ui_connector.start(task_queue),
+				UIEvent::Shutdown => {
+					ui_connector.shutdown().await;
+					return;
+				}
+			}
+		}
+	}
+
+	fn dispatch(&mut self, event: UIEvent) {
+		for output in &mut self.outputs {
+			if output.missed > 0 {
+				let warning = format!("Missed {} events, handling them too slowly", output.missed);
+				if output.events.try_send(UIEvent::Error(warning)).is_err() {
+					output.missed += 1;
+					continue;
+				}
+				output.missed = 0;
+			}
+
+			match output.events.try_send(event.clone()) {
+				Ok(()) => (),
+				Err(TrySendError::Full(_)) => output.missed += 1,
+				// it stopped on its own
+				Err(TrySendError::Closed(_)) => (),
+			}
+		}
+	}
+}
+
+impl UIConnector for FanOutUI {
+	fn message_received(&mut self, message: Message) {
+		self.dispatch(UIEvent::MessageReceived(message));
+	}
+
+	fn message_updated(&mut self, message: Message) {
+		self.dispatch(UIEvent::MessageUpdated(message));
+	}
+
+	fn message_sent(&mut self, message: &Message, result: Result<(), String>) {
+		self.dispatch(UIEvent::MessageSent(message.clone(), result));
+	}
+
+	fn history_received(&mut self, messages: Vec<Message>) {
+		self.dispatch(UIEvent::HistoryReceived(messages));
+	}
+
+	fn presence_changed(&mut self, channel: &str, online: Vec<Box<str>>) {
+		self.dispatch(UIEvent::PresenceChanged(channel.into(), online));
+	}
+
+	fn typing_changed(&mut self, channel: &str, typing: Vec<Box<str>>) {
+		self.dispatch(UIEvent::TypingChanged(channel.into(), typing));
+	}
+
+	fn unread_changed(&mut self, channel: &str, unread: usize) {
+		self.dispatch(UIEvent::UnreadChanged(channel.into(), unread));
+	}
+
+	fn channels_changed(&mut self, channels: Vec<Subscription>) {
+		self.dispatch(UIEvent::ChannelsChanged(channels));
+	}
+
+	fn channel_encryption_changed(&mut self, channel: &str, encrypted: bool) {
+		self.dispatch(UIEvent::ChannelEncryptionChanged(channel.into(), encrypted));
+	}
+
+	fn file_received(&mut self, attachment: Attachment) {
+		self.dispatch(UIEvent::FileReceived(attachment));
+	}
+
+	fn transfer_progress(&mut self, progress: TransferProgress) {
+		self.dispatch(UIEvent::TransferProgress(progress));
+	}
+
+	fn connection_changed(&mut self, state: ConnectionState) {
+		self.dispatch(UIEvent::ConnectionChanged(state));
+	}
+
+	fn notice(&mut self, text: String) {
+		self.dispatch(UIEvent::Notice(text));
+	}
+
+	fn error(&mut self, text: String) {
+		self.dispatch(UIEvent::Error(text));
+	}
+
+	// the queues are still empty, so none of the UIs miss it
+	fn start(&mut self, task_queue: TaskQueue) {
+		self.dispatch(UIEvent::Start(task_queue));
+	}
+
+	async fn shutdown(&mut self) {
+		for output in &mut self.outputs {
+			let stopped = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+				output.events.send(UIEvent::Shutdown).await.unwrap_or(());
+				(&mut output.task).await.unwrap_or(());
+			});
+			if stopped.await.is_err() {
+				eprintln!("A UI didn't stop in time");
+				output.task.abort();
+			}
+		}
+	}
+}
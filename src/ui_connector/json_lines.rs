@@ -0,0 +1,603 @@
+/*
+ * A UI for scripts and other frontends, which reads commands as JSON objects
+ * from stdin and writes events as JSON objects to stdout, one per line.
+ * Diagnostics go to stderr. Once stdout is closed, such as by the frontend
+ * exiting, the client exits too.
+ *
+ * Protocol version 1. The first line written is
+ *   {"event": "ready", "version": 1}
+ * and the version only goes up when something is removed or changes meaning,
+ * while new commands, events and fields may be added to the same version, so
+ * readers should ignore what they don't know.
+ *
+ * Commands have a "command" field, and may have an "id" of any JSON type,
+ * which is given back in the command's result:
+ *   send         channel, text, sender?
+ *   reply        message_id, text, sender?
+ *   edit         message_id, text, sender?
+ *   delete       message_id, sender?
+ *   react        message_id, emoji, removed? (false), sender?
+ *   send_file    channel, path, sender?
+ *   nick         nick
+ *   subscribe    channel
+ *   unsubscribe  channel
+ *   channels
+ *   history      channel, limit? (20)
+ *   thread       message_id
+ *   search       text
+ *   read         channel
+ *   typing       channel, active
+ *   command      line, any of the simplified UI's commands, such as
+ *                "encrypt team secret"
+ *   exit
+ * Without a sender, messages are sent as the nick, from the settings or the
+ * last nick command. A sender is used for that command only, leaving the
+ * nick as it was. A message_id may be shortened to its first 8 characters.
+ *
+ * Every command is answered with a result, once queued, which only means it
+ * was understood. What happens after comes as the events below:
+ *   result       id?, ok, error?, message_id? (of a sent message),
+ *                output? (of a command line, such as help)
+ *   sent         message_id, channel, ok, error?
+ *                a message was published, or failed to be
+ *   message      message
+ *   updated      message, after an edit, delete or reaction
+ *   history      messages, for history, thread and search, oldest first
+ *   presence     channel, online
+ *   typing       channel, typing
+ *   unread       channel, unread
+ *   channels     channels, each {channel, favourite, muted}
+ *   encryption   channel, encrypted
+ *   file         sender, channel, file_name, file_size, sha256, location,
+ *                encrypted, verification (as in messages)
+ *   transfer     transfer_id, file_name, direction ("sending" or "receiving"),
+ *                chunks_done, chunk_count
+ *   connection   state ("connecting", "connected" or "disconnected")
+ *   notice       text
+ *   error        text
+ * Messages are written as they are published, see message.rs, with the
+ * reactions, read receipts and verification known locally.
+ * The client exits at the end of stdin, or on exit.
+*/
+use std::io::Write;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+	sync::{
+		mpsc::{unbounded_channel, UnboundedReceiver},
+		oneshot,
+	},
+	task::JoinHandle,
+};
+
+use crate::{
+	attachment::{Attachment, TransferDirection, TransferProgress},
+	message::{Message, Verification},
+	subscriptions::Subscription,
+	task_queue::{TaskData, TaskQueue},
+};
+
+use super::{
+	commands::{handle_line, Session, UIStatus},
+	ConnectionState, UIConnector,
+};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+pub(super) const DEFAULT_HISTORY_LENGTH: usize = 20;
+
+// also the daemon's methods, see daemon.rs
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub(super) enum Request {
+	Send {
+		channel: Box<str>,
+		text: Box<str>,
+		sender: Option<Box<str>>,
+	},
+	Reply {
+		message_id: Box<str>,
+		text: Box<str>,
+		sender: Option<Box<str>>,
+	},
+	Edit {
+		message_id: Box<str>,
+		text: Box<str>,
+		sender: Option<Box<str>>,
+	},
+	Delete {
+		message_id: Box<str>,
+		sender: Option<Box<str>>,
+	},
+	React {
+		message_id: Box<str>,
+		emoji: Box<str>,
+		#[serde(default)]
+		removed: bool,
+		sender: Option<Box<str>>,
+	},
+	SendFile {
+		channel: Box<str>,
+		path: Box<str>,
+		sender: Option<Box<str>>,
+	},
+	Nick {
+		nick: Box<str>,
+	},
+	Subscribe {
+		channel: Box<str>,
+	},
+	Unsubscribe {
+		channel: Box<str>,
+	},
+	Channels,
+	History {
+		channel: Box<str>,
+		#[serde(default = "default_history_length")]
+		limit: usize,
+	},
+	Thread {
+		message_id: Box<str>,
+	},
+	Search {
+		text: Box<str>,
+	},
+	Read {
+		channel: Box<str>,
+	},
+	Typing {
+		channel: Box<str>,
+		active: bool,
+	},
+	Command {
+		line: Box<str>,
+	},
+	Exit,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event<'a> {
+	Ready {
+		version: u32,
+	},
+	Result {
+		#[serde(skip_serializing_if = "Option::is_none")]
+		id: Option<Value>,
+		ok: bool,
+		#[serde(skip_serializing_if = "Option::is_none")]
+		error: Option<String>,
+		#[serde(skip_serializing_if = "Option::is_none")]
+		message_id: Option<String>,
+		#[serde(skip_serializing_if = "Option::is_none")]
+		output: Option<String>,
+	},
+	Sent {
+		message_id: String,
+		channel: &'a str,
+		ok: bool,
+		#[serde(skip_serializing_if = "Option::is_none")]
+		error: Option<String>,
+	},
+	Message {
+		message: Message,
+	},
+	Updated {
+		message: Message,
+	},
+	History {
+		messages: Vec<Message>,
+	},
+	Presence {
+		channel: &'a str,
+		online: Vec<Box<str>>,
+	},
+	Typing {
+		channel: &'a str,
+		typing: Vec<Box<str>>,
+	},
+	Unread {
+		channel: &'a str,
+		unread: usize,
+	},
+	Channels {
+		channels: Vec<Subscription>,
+	},
+	Encryption {
+		channel: &'a str,
+		encrypted: bool,
+	},
+	File {
+		sender: Box<str>,
+		channel: Box<str>,
+		file_name: Box<str>,
+		file_size: usize,
+		sha256: Box<str>,
+		location: Box<str>,
+		encrypted: bool,
+		verification: Verification,
+	},
+	Transfer {
+		transfer_id: Box<str>,
+		file_name: Box<str>,
+		direction: &'a str,
+		chunks_done: usize,
+		chunk_count: usize,
+	},
+	Connection {
+		state: ConnectionState,
+	},
+	Notice {
+		text: String,
+	},
+	Error {
+		text: String,
+	},
+}
+
+// what a command was turned into, before it's answered
+#[derive(Serialize)]
+pub(super) struct Outcome {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub message_id: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub output: Option<String>,
+	// whether to stop reading commands
+	#[serde(skip)]
+	pub stop: bool,
+}
+
+pub struct JsonLinesUI {
+	nick: Box<str>,
+	// for exiting when stdout is closed, set on start
+	task_queue: Option<TaskQueue>,
+	writing: bool,
+	// the task reading commands, and how to stop it
+	input_task: Option<JoinHandle<()>>,
+	stop: Option<oneshot::Sender<()>>,
+}
+
+impl JsonLinesUI {
+	// the nick may be empty, to be set with the nick command
+	pub fn new(nick: &str) -> Self {
+		Self {
+			nick: nick.into(),
+			task_queue: None,
+			writing: true,
+			input_task: None,
+			stop: None,
+		}
+	}
+
+	// false once stdout is closed
+	fn write(event: &Event) -> bool {
+		let line = match serde_json::to_string(event) {
+			Ok(line) => line,
+			Err(e) => {
+				eprintln!("Error writing event: {}", e);
+				return true;
+			}
+		};
+		// a line is written at once, so events from different tasks don't mix
+		writeln!(std::io::stdout().lock(), "{}", line).is_ok()
+	}
+
+	fn emit(&mut self, event: &Event) {
+		if !self.writing || Self::write(event) {
+			return;
+		}
+		self.writing = false;
+		if let Some(task_queue) = &self.task_queue {
+			let mut task_queue = task_queue.clone();
+			tokio::task::spawn(async move { task_queue.push(TaskData::Exit).await });
+		}
+	}
+
+	/*
+	 * stdin is read on a thread of its own, not a blocking task, as the runtime
+	 * waits for those when shutting down, while this may wait for input forever
+	 */
+	fn read_stdin() -> UnboundedReceiver<String> {
+		let (line_sender, lines) = unbounded_channel();
+		std::thread::spawn(move || {
+			// until the input ends, or no one is reading
+			for line in std::io::stdin().lines().map_while(Result::ok) {
+				if line_sender.send(line).is_err() {
+					break;
+				}
+			}
+		});
+		lines
+	}
+
+	// the command's result, and whether to stop reading commands
+	pub(super) async fn handle_request(
+		task_queue: &mut TaskQueue,
+		session: &mut Session,
+		line: &str,
+	) -> (Event<'static>, bool) {
+		let value: Value = match serde_json::from_str(line) {
+			Ok(value) => value,
+			Err(e) => {
+				return (
+					Event::result(None, Err(format!("Not a JSON object: {}", e))),
+					false,
+				)
+			}
+		};
+		let id = value.get("id").cloned();
+		let request = match Request::deserialize(value) {
+			Ok(request) => request,
+			Err(e) => return (Event::result(id, Err(format!("Bad command: {}", e))), false),
+		};
+
+		let res = Self::handle_command(task_queue, session, request).await;
+		let stop = matches!(res, Ok(Outcome { stop: true, .. }));
+		(Event::result(id, res), stop)
+	}
+
+	pub(super) async fn handle_command(
+		task_queue: &mut TaskQueue,
+		session: &mut Session,
+		request: Request,
+	) -> Result<Outcome, String> {
+		let mut outcome = Outcome {
+			message_id: None,
+			output: None,
+			stop: false,
+		};
+
+		let task = match request {
+			Request::Send {
+				channel,
+				text,
+				sender,
+			} => {
+				let sender = Self::sender(session, sender)?;
+				let message = Message::text(&sender, &channel, &text);
+				outcome.message_id = Some(message.id.to_string());
+				TaskData::SendMessage(message)
+			}
+			Request::Reply {
+				message_id,
+				text,
+				sender,
+			} => TaskData::Reply {
+				sender: Self::sender(session, sender)?,
+				reply_to: message_id,
+				contents: text,
+			},
+			Request::Edit {
+				message_id,
+				text,
+				sender,
+			} => TaskData::Edit {
+				sender: Self::sender(session, sender)?,
+				target: message_id,
+				contents: text,
+			},
+			Request::Delete { message_id, sender } => TaskData::Delete {
+				sender: Self::sender(session, sender)?,
+				target: message_id,
+			},
+			Request::React {
+				message_id,
+				emoji,
+				removed,
+				sender,
+			} => TaskData::React {
+				sender: Self::sender(session, sender)?,
+				target: message_id,
+				emoji,
+				removed,
+			},
+			Request::SendFile {
+				channel,
+				path,
+				sender,
+			} => TaskData::SendFile {
+				sender: Self::sender(session, sender)?,
+				channel,
+				path,
+			},
+			Request::Nick { nick } => {
+				session.set_nick(task_queue, nick).await;
+				return Ok(outcome);
+			}
+			Request::Subscribe { channel } => TaskData::NewChannel(channel),
+			Request::Unsubscribe { channel } => TaskData::RemoveChannel(channel),
+			Request::Channels => TaskData::ShowChannels,
+			Request::History { channel, limit } => TaskData::ShowHistory { channel, limit },
+			Request::Thread { message_id } => TaskData::ShowThread(message_id),
+			Request::Search { text } => TaskData::Search(text),
+			Request::Read { channel } => TaskData::MarkRead(channel),
+			Request::Typing { channel, active } => TaskData::SetTyping { channel, active },
+			Request::Command { line } => {
+				match handle_line(task_queue, session, &line).await? {
+					UIStatus::Continue => (),
+					UIStatus::Output(text) => outcome.output = Some(text),
+					UIStatus::Stop => outcome.stop = true,
+				}
+				return Ok(outcome);
+			}
+			Request::Exit => {
+				outcome.stop = true;
+				return Ok(outcome);
+			}
+		};
+
+		task_queue.push(task).await;
+		Ok(outcome)
+	}
+
+	// the given sender is only for this command, the nick is kept
+	fn sender(session: &Session, sender: Option<Box<str>>) -> Result<Box<str>, String> {
+		sender.map_or_else(|| session.sender(), Ok)
+	}
+}
+
+impl<'a> Event<'a> {
+	fn result(id: Option<Value>, res: Result<Outcome, String>) -> Self {
+		match res {
+			Ok(outcome) => Self::Result {
+				id,
+				ok: true,
+				error: None,
+				message_id: outcome.message_id,
+				output: outcome.output,
+			},
+			Err(e) => Self::Result {
+				id,
+				ok: false,
+				error: Some(e),
+				message_id: None,
+				output: None,
+			},
+		}
+	}
+
+	pub(super) fn sent(message: &'a Message, result: Result<(), String>) -> Self {
+		Self::Sent {
+			message_id: message.id.to_string(),
+			channel: &message.channel,
+			ok: result.is_ok(),
+			error: result.err(),
+		}
+	}
+
+	pub(super) fn file(attachment: Attachment) -> Self {
+		Self::File {
+			sender: attachment.sender,
+			channel: attachment.channel,
+			file_name: attachment.file_name,
+			file_size: attachment.file_size,
+			sha256: attachment.sha256,
+			location: attachment.location,
+			encrypted: attachment.encrypted,
+			verification: attachment.verification,
+		}
+	}
+
+	pub(super) fn transfer(progress: TransferProgress) -> Self {
+		let direction = match progress.direction {
+			TransferDirection::Sending => "sending",
+			TransferDirection::Receiving => "receiving",
+		};
+		Self::Transfer {
+			transfer_id: progress.transfer_id,
+			file_name: progress.file_name,
+			direction,
+			chunks_done: progress.chunks_done,
+			chunk_count: progress.chunk_count,
+		}
+	}
+}
+
+fn default_history_length() -> usize {
+	DEFAULT_HISTORY_LENGTH
+}
+
+impl UIConnector for JsonLinesUI {
+	fn message_received(&mut self, message: Message) {
+		self.emit(&Event::Message { message })
+	}
+
+	fn message_updated(&mut self, message: Message) {
+		self.emit(&Event::Updated { message })
+	}
+
+	fn message_sent(&mut self, message: &Message, result: Result<(), String>) {
+		self.emit(&Event::sent(message, result))
+	}
+
+	fn history_received(&mut self, messages: Vec<Message>) {
+		self.emit(&Event::History { messages })
+	}
+
+	fn presence_changed(&mut self, channel: &str, online: Vec<Box<str>>) {
+		self.emit(&Event::Presence { channel, online })
+	}
+
+	fn typing_changed(&mut self, channel: &str, typing: Vec<Box<str>>) {
+		self.emit(&Event::Typing { channel, typing })
+	}
+
+	fn unread_changed(&mut self, channel: &str, unread: usize) {
+		self.emit(&Event::Unread { channel, unread })
+	}
+
+	fn channels_changed(&mut self, channels: Vec<Subscription>) {
+		self.emit(&Event::Channels { channels })
+	}
+
+	fn channel_encryption_changed(&mut self, channel: &str, encrypted: bool) {
+		self.emit(&Event::Encryption { channel, encrypted })
+	}
+
+	fn file_received(&mut self, attachment: Attachment) {
+		self.emit(&Event::file(attachment))
+	}
+
+	fn transfer_progress(&mut self, progress: TransferProgress) {
+		self.emit(&Event::transfer(progress))
+	}
+
+	fn connection_changed(&mut self, state: ConnectionState) {
+		self.emit(&Event::Connection { state })
+	}
+
+	fn notice(&mut self, text: String) {
+		self.emit(&Event::Notice { text })
+	}
+
+	fn error(&mut self, text: String) {
+		self.emit(&Event::Error { text })
+	}
+
+	fn start(&mut self, mut task_queue: TaskQueue) {
+		self.task_queue = Some(task_queue.clone());
+		self.emit(&Event::Ready {
+			version: PROTOCOL_VERSION,
+		});
+
+		let mut lines = Self::read_stdin();
+		let (stop, mut stopped) = oneshot::channel();
+		self.stop = Some(stop);
+
+		let mut session = Session::new(&self.nick);
+		self.input_task = Some(tokio::task::spawn(async move {
+			session.start(&mut task_queue).await;
+			loop {
+				let line = tokio::select! {
+					line = lines.recv() => line,
+					// exiting some other way, such as on a signal
+					_ = &mut stopped => return,
+				};
+				let line = match line {
+					Some(line) => line,
+					None => break,
+				};
+				if line.trim().is_empty() {
+					continue;
+				}
+
+				let (result, stop) =
+					Self::handle_request(&mut task_queue, &mut session, &line).await;
+				if !Self::write(&result) || stop {
+					break;
+				}
+			}
+
+			task_queue.push(TaskData::Exit).await;
+		}));
+	}
+
+	async fn shutdown(&mut self) {
+		if let Some(stop) = self.stop.take() {
+			stop.send(()).unwrap_or(());
+		}
+		if let Some(input_task) = self.input_task.take() {
+			input_task.await.unwrap_or(());
+		}
+	}
+}
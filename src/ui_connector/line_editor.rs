@@ -0,0 +1,213 @@
+/*
+ * Reads commands with line editing, a history kept in the data directory
+ * across runs, and tab completion of command names and subscribed channels.
+ * When stdin isn't a terminal, such as in scripts, lines are read as they are.
+ * The editor runs on its own thread, so waiting for input doesn't hold up the
+ * runtime, and lines are awaited through a LineReader. The thread can't be
+ * stopped while it waits for a line, so once the reader is dropped, such as
+ * when exiting on a signal, the terminal is put back as it was before editing.
+*/
+use std::{
+	path::{Path, PathBuf},
+	sync::{mpsc, Arc, Mutex},
+};
+
+use rustyline::{
+	completion::{Completer, Pair},
+	highlight::Highlighter,
+	hint::Hinter,
+	history::DefaultHistory,
+	validate::Validator,
+	CompletionType, Config, Context, Editor, ExternalPrinter, Helper,
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+#[cfg(unix)]
+use nix::sys::termios::{self, SetArg, Termios};
+
+use super::commands::{is_secret, quote_word, COMMANDS};
+
+const HISTORY_FILE: &str = "command_history.txt";
+const MAX_HISTORY_LENGTH: usize = 1000;
+
+// the subscribed channels, kept up to date by the UI
+pub type Channels = Arc<Mutex<Vec<Box<str>>>>;
+pub type Printer = Box<dyn ExternalPrinter + Send>;
+
+pub struct LineEditor {
+	editor: Editor<CommandCompleter, DefaultHistory>,
+	history_path: PathBuf,
+}
+
+pub struct LineReader {
+	prompts: mpsc::Sender<String>,
+	lines: UnboundedReceiver<Option<String>>,
+	// None when stdin isn't a terminal
+	#[cfg(unix)]
+	terminal_mode: Option<Termios>,
+}
+
+pub struct CommandCompleter {
+	channels: Channels,
+}
+
+impl LineEditor {
+	pub fn open(data_directory: &Path, channels: Channels) -> rustyline::Result<Self> {
+		let config = Config::builder()
+			.max_history_size(MAX_HISTORY_LENGTH)?
+			.history_ignore_dups(true)?
+			.history_ignore_space(true)
+			.completion_type(CompletionType::List)
+			.build();
+		let mut editor = Editor::with_config(config)?;
+		editor.set_helper(Some(CommandCompleter { channels }));
+
+		let history_path = data_directory.join(HISTORY_FILE);
+		if history_path.exists() {
+			editor.load_history(&history_path)?;
+		}
+
+		Ok(Self {
+			editor,
+			history_path,
+		})
+	}
+
+	// prints above the line being edited, without breaking it up
+	pub fn printer(&mut self) -> Option<Printer> {
+		match self.editor.create_external_printer() {
+			Ok(printer) => Some(Box::new(printer)),
+			// there's nothing to print around outside a terminal
+			Err(_) => None,
+		}
+	}
+
+	/*
+	 * not a blocking task, as the runtime waits for those when shutting down,
+	 * while this thread may be waiting for input that never comes
+	 */
+	pub fn spawn(mut self) -> LineReader {
+		let (prompts, prompt_receiver) = mpsc::channel::<String>();
+		let (line_sender, lines) = unbounded_channel();
+		#[cfg(unix)]
+		let terminal_mode = termios::tcgetattr(std::io::stdin()).ok();
+
+		std::thread::spawn(move || {
+			// until the reader is dropped, or the input ends
+			while let Ok(prompt) = prompt_receiver.recv() {
+				let line = self.read_line(&prompt);
+				let ended = line.is_none();
+				if line_sender.send(line).is_err() || ended {
+					break;
+				}
+			}
+		});
+
+		LineReader {
+			prompts,
+			lines,
+			#[cfg(unix)]
+			terminal_mode,
+		}
+	}
+
+	// None at the end of input
+	fn read_line(&mut self, prompt: &str) -> Option<String> {
+		match self.editor.readline(prompt) {
+			Ok(line) => {
+				self.remember(&line);
+				Some(line.trim().to_string())
+			}
+			// Ctrl+C and Ctrl+D end the input, like exit
+			Err(_) => None,
+		}
+	}
+
+	fn remember(&mut self, line: &str) {
+		if line.trim().is_empty() || is_secret(line) {
+			return;
+		}
+		// losing the history is no reason to stop reading commands
+		if let Ok(true) = self.editor.add_history_entry(line) {
+			self.editor.append_history(&self.history_path).unwrap_or(());
+		}
+	}
+}
+
+impl LineReader {
+	// None once there's nothing more to read
+	pub async fn read_line(&mut self, prompt: &str) -> Option<String> {
+		self.prompts.send(prompt.to_string()).ok()?;
+		self.lines.recv().await.flatten()
+	}
+}
+
+// the editor may still be waiting for a line, with the terminal in raw mode
+#[cfg(unix)]
+impl Drop for LineReader {
+	fn drop(&mut self) {
+		if let Some(terminal_mode) = &self.terminal_mode {
+			termios::tcsetattr(std::io::stdin(), SetArg::TCSANOW, terminal_mode).unwrap_or(());
+		}
+	}
+}
+
+impl Completer for CommandCompleter {
+	type Candidate = Pair;
+
+	// the first word is a command, and any other may be a channel
+	fn complete(
+		&self,
+		line: &str,
+		pos: usize,
+		_: &Context<'_>,
+	) -> rustyline::Result<(usize, Vec<Pair>)> {
+		let start = line[..pos]
+			.rfind(char::is_whitespace)
+			.map_or(0, |index| index + 1);
+		let word = &line[start..pos];
+		let first_word = line[..start].trim().is_empty();
+
+		let candidates: Vec<Pair> = match first_word {
+			true => {
+				let (slash, name) = match word.strip_prefix('/') {
+					Some(name) => ("/", name),
+					None => ("", word),
+				};
+				COMMANDS
+					.iter()
+					.filter(|spec| spec.name.starts_with(name))
+					.map(|spec| Pair {
+						display: spec.name.to_string(),
+						replacement: format!("{}{} ", slash, spec.name),
+					})
+					.collect()
+			}
+			false => {
+				let word = word.trim_start_matches(['"', '\'']);
+				let channels = self.channels.lock().map(|channels| channels.clone());
+				channels
+					.unwrap_or_default()
+					.iter()
+					.filter(|channel| channel.starts_with(word))
+					.map(|channel| Pair {
+						display: channel.to_string(),
+						replacement: format!("{} ", quote_word(channel)),
+					})
+					.collect()
+			}
+		};
+
+		Ok((start, candidates))
+	}
+}
+
+impl Hinter for CommandCompleter {
+	type Hint = String;
+}
+
+impl Highlighter for CommandCompleter {}
+
+impl Validator for CommandCompleter {}
+
+impl Helper for CommandCompleter {}
@@ -1,8 +1,141 @@
-use crate::{message::Message, task_queue::TaskQueue};
+use std::{fmt, future::Future, ops::Deref, str::FromStr};
+
+use serde::Serialize;
+
+use crate::{
+	attachment::{Attachment, TransferProgress},
+	message::Message,
+	subscriptions::Subscription,
+	task_queue::TaskQueue,
+};
+
+// which UI the client runs with, chosen in the settings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UIKind {
+	Simplified,
+	Terminal,
+	JsonLines,
+	Web,
+}
+
+// the UIs the client runs with at once, such as "terminal,web"
+#[derive(Debug, Clone, PartialEq)]
+pub struct UIKinds(Vec<UIKind>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+	Connecting,
+	Connected,
+	Disconnected,
+}
+
+impl FromStr for UIKind {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"simplified" => Ok(Self::Simplified),
+			"terminal" => Ok(Self::Terminal),
+			"json" => Ok(Self::JsonLines),
+			"web" => Ok(Self::Web),
+			_ => Err(format!(
+				"unknown UI: {} (expected simplified, terminal, json or web)",
+				s
+			)),
+		}
+	}
+}
+
+impl FromStr for UIKinds {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut kinds = Vec::new();
+		for kind in s.split(',').map(str::trim).map(UIKind::from_str) {
+			let kind = kind?;
+			if kinds.contains(&kind) {
+				return Err(format!("UI given twice: {}", kind));
+			}
+			kinds.push(kind);
+		}
+		if kinds.iter().filter(|kind| kind.is_interactive()).count() > 1 {
+			return Err("only one of simplified, terminal and json can run at once".to_string());
+		}
+		Ok(Self(kinds))
+	}
+}
+
+impl Deref for UIKinds {
+	type Target = [UIKind];
+
+	fn deref(&self) -> &Self::Target {
+		&self.0
+	}
+}
+
+impl fmt::Display for UIKinds {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let kinds: Vec<String> = self.0.iter().map(UIKind::to_string).collect();
+		write!(f, "{}", kinds.join(","))
+	}
+}
+
+impl UIKind {
+	// whether it owns the terminal, reading stdin and writing stdout
+	fn is_interactive(&self) -> bool {
+		matches!(self, Self::Simplified | Self::Terminal | Self::JsonLines)
+	}
+
+	// whether it draws on the terminal, so no other UI should write there
+	pub fn draws_on_terminal(&self) -> bool {
+		matches!(self, Self::Simplified | Self::Terminal)
+	}
+}
+
+impl fmt::Display for UIKind {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Simplified => write!(f, "simplified"),
+			Self::Terminal => write!(f, "terminal"),
+			Self::JsonLines => write!(f, "json"),
+			Self::Web => write!(f, "web"),
+		}
+	}
+}
 
 pub trait UIConnector {
 	fn message_received(&mut self, message: Message);
+	// an earlier message was edited or deleted
+	fn message_updated(&mut self, message: Message);
+	// one of our messages was published, or failed to be
+	fn message_sent(&mut self, message: &Message, result: Result<(), String>);
+	fn history_received(&mut self, messages: Vec<Message>);
+	fn presence_changed(&mut self, channel: &str, online: Vec<Box<str>>);
+	fn typing_changed(&mut self, channel: &str, typing: Vec<Box<str>>);
+	fn unread_changed(&mut self, channel: &str, unread: usize);
+	// the subscribed channels, in the order they should be listed
+	fn channels_changed(&mut self, channels: Vec<Subscription>);
+	fn channel_encryption_changed(&mut self, channel: &str, encrypted: bool);
+	fn file_received(&mut self, attachment: Attachment);
+	fn transfer_progress(&mut self, progress: TransferProgress);
+	fn connection_changed(&mut self, state: ConnectionState);
+	// the outcome of a command, or anything else worth telling the user
+	fn notice(&mut self, text: String);
+	fn error(&mut self, text: String);
 	fn start(&mut self, task_queue: TaskQueue);
+	// waits for the UI to finish, once the messenger has stopped.
+	// the future is Send so the UI can run on its own task, see fan_out.rs
+	fn shutdown(&mut self) -> impl Future<Output = ()> + Send;
 }
 
+pub mod commands;
+#[cfg(unix)]
+pub mod daemon;
+pub mod fan_out;
+pub mod json_lines;
+pub mod line_editor;
 pub mod simplified;
+pub mod terminal;
+pub mod transcript;
+pub mod web;
@@ -1,76 +1,318 @@
+use std::{
+	collections::HashMap,
+	path::PathBuf,
+	sync::{Arc, Mutex},
+};
+
+use tokio::{sync::oneshot, task::JoinHandle};
+
 use crate::{
-	message::Message,
+	attachment::{Attachment, TransferDirection, TransferProgress},
+	message::{short_id, Message, MessageKind, Verification},
+	subscriptions::Subscription,
 	task_queue::{TaskData, TaskQueue},
 };
 
-use super::UIConnector;
+use super::{
+	commands::{handle_line, Session, UIStatus},
+	line_editor::{Channels, LineEditor, Printer},
+	ConnectionState, UIConnector,
+};
 
-enum UIStatus {
-	Continue,
-	Stop,
+pub struct SimplifiedUI {
+	// where the command history is kept
+	data_directory: PathBuf,
+	channels: Channels,
+	// set while editing a line in a terminal
+	printer: Option<Printer>,
+	nick: Box<str>,
+	// the task reading commands, and how to stop it
+	input_task: Option<JoinHandle<()>>,
+	stop: Option<oneshot::Sender<()>>,
 }
 
-pub struct SimplifiedUI {}
-
 impl SimplifiedUI {
-	pub fn new() -> Self {
-		Self {}
-	}
-
-	async fn handle_command(task_queue: &mut TaskQueue, line: String) -> UIStatus {
-		let mut splitter = line.splitn(4, " ");
-		let command = splitter.next().unwrap_or("");
-		let arg1 = splitter.next().unwrap_or("").into();
-		let arg2 = splitter.next().unwrap_or("").into();
-		let arg3 = splitter.next().unwrap_or("").into();
-
-		match command {
-			"" => (),
-			"add_channel" => task_queue.push(TaskData::NewChannel(arg1)).await,
-			"remove_channel" => task_queue.push(TaskData::RemoveChannel(arg1)).await,
-			"send" => {
-				let message = Message {
-					sender: arg1,
-					channel: arg2,
-					contents: arg3,
-				};
-				task_queue.push(TaskData::SendMessage(message)).await;
+	// the nick may be empty, to be set with the nick command
+	pub fn new(data_directory: &str, nick: &str) -> Self {
+		Self {
+			data_directory: data_directory.into(),
+			channels: Arc::new(Mutex::new(Vec::new())),
+			printer: None,
+			nick: nick.into(),
+			input_task: None,
+			stop: None,
+		}
+	}
+
+	fn prompt(session: &Session) -> String {
+		match &session.channel {
+			Some(channel) => format!("[{}]> ", channel),
+			None => "> ".to_string(),
+		}
+	}
+
+	// around the line being edited, if any
+	fn print(&mut self, text: String) {
+		if let Some(printer) = &mut self.printer {
+			if printer.print(text.clone()).is_ok() {
+				return;
 			}
-			"exit" => return UIStatus::Stop,
-			_ => println!("Unknown command"),
 		}
+		println!("{}", text)
+	}
+
+	pub fn format_message(message: &Message) -> String {
+		let reply = match message.reply_to {
+			Some(reply_to) => format!(" re {}", short_id(&reply_to)),
+			None => String::new(),
+		};
+		let lock = match message.encrypted {
+			true => "🔒 ",
+			false => "",
+		};
+
+		format!(
+			"{}[{}] {}{} ({}){}: {}{}",
+			lock,
+			message.channel,
+			message.sender,
+			verification_mark(message.verification),
+			message.short_id(),
+			reply,
+			contents(message),
+			annotations(message)
+		)
+	}
+}
 
-		UIStatus::Continue
+// what a message says, or what happened to it
+pub(super) fn contents(message: &Message) -> String {
+	match &message.kind {
+		MessageKind::Text {
+			contents,
+			edited: false,
+		} => contents.to_string(),
+		MessageKind::Text {
+			contents,
+			edited: true,
+		} => format!("{} (edited)", contents),
+		MessageKind::Deleted => "(message deleted)".to_string(),
+		MessageKind::Encrypted { .. } => "(encrypted, could not decrypt)".to_string(),
+		kind => format!("<unsupported message kind: {}>", kind.name()),
 	}
+}
 
-	fn read_line() -> Option<String> {
-		let mut buffer = String::new();
-		std::io::stdin().read_line(&mut buffer).ok()?;
-		Some(buffer.trim().to_string())
+pub(super) fn verification_mark(verification: Verification) -> &'static str {
+	match verification {
+		Verification::Unsigned => " (unsigned)",
+		Verification::BadSignature => " (INVALID SIGNATURE)",
+		Verification::KeyChanged => " (KEY CHANGED)",
+		Verification::Trusted => "",
+		Verification::Verified => " ✓",
 	}
 }
 
+// reactions and read receipts, shown after the contents
+pub(super) fn annotations(message: &Message) -> String {
+	let reactions: Vec<String> = message
+		.reactions
+		.iter()
+		.map(|(emoji, senders)| format!("{} {}", emoji, senders.len()))
+		.collect();
+	let reactions = match reactions.is_empty() {
+		true => String::new(),
+		false => format!(" [{}]", reactions.join(", ")),
+	};
+
+	let read_by = match message.read_by.is_empty() {
+		true => String::new(),
+		false => format!(
+			" (read by {})",
+			message
+				.read_by
+				.iter()
+				.cloned()
+				.collect::<Vec<_>>()
+				.join(", ")
+		),
+	};
+
+	reactions + &read_by
+}
+
 impl UIConnector for SimplifiedUI {
 	fn message_received(&mut self, message: Message) {
-		println!("message received: {:?}", message)
+		self.print(Self::format_message(&message))
+	}
+
+	fn message_updated(&mut self, message: Message) {
+		self.print(format!("updated: {}", Self::format_message(&message)))
+	}
+
+	// sent messages are shown once they're stored, like any other
+	fn message_sent(&mut self, _: &Message, result: Result<(), String>) {
+		if let Err(e) = result {
+			self.print(format!("Error sending message: {}", e))
+		}
+	}
+
+	// messages are ordered by time, so a reply always comes after what it answers
+	fn history_received(&mut self, messages: Vec<Message>) {
+		let mut depths = HashMap::new();
+
+		for message in &messages {
+			let depth = message
+				.reply_to
+				.and_then(|parent| depths.get(&parent))
+				.map_or(0, |depth| depth + 1);
+			depths.insert(message.id, depth);
+
+			self.print(format!(
+				"{}{}",
+				"  ".repeat(depth),
+				Self::format_message(message)
+			));
+		}
+	}
+
+	fn presence_changed(&mut self, channel: &str, online: Vec<Box<str>>) {
+		self.print(format!("[{}] online: {}", channel, online.join(", ")))
+	}
+
+	fn typing_changed(&mut self, channel: &str, typing: Vec<Box<str>>) {
+		if !typing.is_empty() {
+			self.print(format!("[{}] typing: {}", channel, typing.join(", ")))
+		}
+	}
+
+	// every message is printed as it arrives, so only catching up is worth showing
+	fn unread_changed(&mut self, channel: &str, unread: usize) {
+		if unread == 0 {
+			self.print(format!("[{}] marked as read", channel))
+		}
+	}
+
+	fn channels_changed(&mut self, channels: Vec<Subscription>) {
+		if let Ok(mut names) = self.channels.lock() {
+			*names = channels
+				.iter()
+				.map(|subscription| subscription.channel.clone())
+				.collect();
+		}
+
+		let channels: Vec<String> = channels
+			.iter()
+			.map(|subscription| {
+				let star = if subscription.favourite { "★ " } else { "" };
+				let muted = if subscription.muted { " (muted)" } else { "" };
+				format!("{}{}{}", star, subscription.channel, muted)
+			})
+			.collect();
+		self.print(format!("channels: {}", channels.join(", ")))
+	}
+
+	fn channel_encryption_changed(&mut self, channel: &str, encrypted: bool) {
+		match encrypted {
+			true => self.print(format!("[{}] is now end-to-end encrypted", channel)),
+			false => self.print(format!("[{}] is no longer encrypted", channel)),
+		}
+	}
+
+	fn file_received(&mut self, attachment: Attachment) {
+		self.print(format!(
+			"file received from {}{} in {}: {} ({} bytes, sha256 {}) saved to {}",
+			attachment.sender,
+			verification_mark(attachment.verification),
+			attachment.channel,
+			attachment.file_name,
+			attachment.file_size,
+			attachment.sha256,
+			attachment.location
+		))
+	}
+
+	fn transfer_progress(&mut self, progress: TransferProgress) {
+		let verb = match progress.direction {
+			TransferDirection::Sending => "sending",
+			TransferDirection::Receiving => "receiving",
+		};
+		self.print(format!(
+			"{} {} [{}]: {}/{} chunks",
+			verb,
+			progress.file_name,
+			progress.transfer_id,
+			progress.chunks_done,
+			progress.chunk_count
+		))
+	}
+
+	fn connection_changed(&mut self, state: ConnectionState) {
+		match state {
+			ConnectionState::Connecting => self.print("Connecting...".to_string()),
+			ConnectionState::Connected => self.print("Connected".to_string()),
+			ConnectionState::Disconnected => self.print("Connection lost".to_string()),
+		}
+	}
+
+	fn notice(&mut self, text: String) {
+		self.print(text)
+	}
+
+	fn error(&mut self, text: String) {
+		self.print(text)
 	}
 
 	fn start(&mut self, mut task_queue: TaskQueue) {
-		tokio::task::spawn(async move {
+		let editor = LineEditor::open(&self.data_directory, Arc::clone(&self.channels));
+		let mut editor = match editor {
+			Ok(editor) => editor,
+			// there's no other way to exit, so the client stops
+			Err(e) => {
+				println!("Could not read commands: {}", e);
+				tokio::task::spawn(async move { task_queue.push(TaskData::Exit).await });
+				return;
+			}
+		};
+		self.printer = editor.printer();
+
+		let mut reader = editor.spawn();
+		let (stop, mut stopped) = oneshot::channel();
+		self.stop = Some(stop);
+
+		let mut session = Session::new(&self.nick);
+		self.input_task = Some(tokio::task::spawn(async move {
+			session.start(&mut task_queue).await;
 			loop {
-				let read_line = SimplifiedUI::read_line();
-				match read_line {
-					Some(line) => {
-						let new_status = SimplifiedUI::handle_command(&mut task_queue, line).await;
-						if let UIStatus::Stop = new_status {
-							break;
-						}
-					}
+				let prompt = Self::prompt(&session);
+				let line = tokio::select! {
+					line = reader.read_line(&prompt) => line,
+					// exiting some other way, such as on a signal
+					_ = &mut stopped => return,
+				};
+				let line = match line {
+					Some(line) => line,
 					None => break,
+				};
+
+				match handle_line(&mut task_queue, &mut session, &line).await {
+					Ok(UIStatus::Continue) => (),
+					Ok(UIStatus::Output(text)) => println!("{}", text),
+					Ok(UIStatus::Stop) => break,
+					Err(e) => println!("{}", e),
 				}
 			}
 
 			task_queue.push(TaskData::Exit).await;
-		});
+		}));
+	}
+
+	async fn shutdown(&mut self) {
+		if let Some(stop) = self.stop.take() {
+			stop.send(()).unwrap_or(());
+		}
+		if let Some(input_task) = self.input_task.take() {
+			input_task.await.unwrap_or(());
+		}
+		self.printer = None;
 	}
 }
@@ -0,0 +1,745 @@
+/*
+ * A full-screen terminal UI: the subscribed channels down the side with their
+ * unread counts, the selected channel's messages, an input line and a status
+ * bar. Lines starting with / are the same commands SimplifiedUI takes, and
+ * anything else is sent to the selected channel, or taken as a command in the
+ * console. Notices, errors and history queries go to the console, listed
+ * above the channels.
+ * The connector's methods are called from the messenger's task, so they only
+ * forward events to the UI's own task, which owns the terminal.
+ *   Tab, Shift+Tab, Ctrl+N, Ctrl+P    next / previous channel
+ *   Up, Down, PageUp, PageDown        scroll the messages
+ *   Esc, Ctrl+C                       exit
+*/
+use std::collections::HashSet;
+
+use chrono::DateTime;
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures_util::StreamExt;
+use ratatui::{
+	layout::{Constraint, Layout, Rect},
+	style::{Color, Modifier, Style, Stylize},
+	text::{Line, Span},
+	widgets::{Block, List, ListItem, ListState, Paragraph, Wrap},
+	DefaultTerminal, Frame,
+};
+use tokio::{
+	sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+	task::JoinHandle,
+};
+
+use crate::{
+	attachment::{Attachment, TransferDirection, TransferProgress},
+	message::{short_id, Message},
+	subscriptions::Subscription,
+	task_queue::{TaskData, TaskQueue},
+};
+
+use super::{
+	commands::{handle_line, Session, UIStatus},
+	simplified::{annotations, contents, verification_mark},
+	ConnectionState, UIConnector,
+};
+
+// how many earlier messages a channel is filled with when it's first listed
+const BACKLOG_LENGTH: usize = 50;
+// the oldest lines and messages are dropped past these, as history keeps them
+const MAX_CONSOLE_LINES: usize = 1000;
+const MAX_CHANNEL_MESSAGES: usize = 1000;
+const SIDEBAR_WIDTH: u16 = 24;
+const PAGE_LENGTH: usize = 10;
+const SENDER_COLOURS: [Color; 8] = [
+	Color::Cyan,
+	Color::Green,
+	Color::Yellow,
+	Color::Magenta,
+	Color::Blue,
+	Color::LightRed,
+	Color::LightGreen,
+	Color::LightCyan,
+];
+
+enum UIEvent {
+	Message(Message),
+	History(Vec<Message>),
+	Presence {
+		channel: Box<str>,
+		online: Vec<Box<str>>,
+	},
+	Typing {
+		channel: Box<str>,
+		typing: Vec<Box<str>>,
+	},
+	Unread {
+		channel: Box<str>,
+		unread: usize,
+	},
+	Channels(Vec<Subscription>),
+	Encryption {
+		channel: Box<str>,
+		encrypted: bool,
+	},
+	Connection(ConnectionState),
+	Notice(String),
+	Error(String),
+	// shown in the status bar only
+	Progress(String),
+	// the messenger has stopped
+	Shutdown,
+}
+
+pub struct TerminalUI {
+	nick: Box<str>,
+	events: UnboundedSender<UIEvent>,
+	// taken by the UI's task once started
+	receiver: Option<UnboundedReceiver<UIEvent>>,
+	task: Option<JoinHandle<()>>,
+}
+
+struct ChannelView {
+	subscription: Subscription,
+	// ordered by time
+	messages: Vec<Message>,
+	unread: usize,
+	online: Vec<Box<str>>,
+	typing: Vec<Box<str>>,
+	encrypted: bool,
+}
+
+struct TerminalState {
+	task_queue: TaskQueue,
+	console: Vec<Line<'static>>,
+	channels: Vec<ChannelView>,
+	// 0 is the console, and the channels follow
+	selected: usize,
+	// lines scrolled up from the newest
+	scroll: usize,
+	input: String,
+	// in characters
+	cursor: usize,
+	connection: ConnectionState,
+	status: Line<'static>,
+	// its channel is the selected one
+	session: Session,
+	// channels whose backlog was asked for, and hasn't arrived yet
+	loading: HashSet<Box<str>>,
+}
+
+impl TerminalUI {
+	// the nick may be empty, to be set with /nick
+	pub fn new(nick: &str) -> Self {
+		let (events, receiver) = unbounded_channel();
+		Self {
+			nick: nick.into(),
+			events,
+			receiver: Some(receiver),
+			task: None,
+		}
+	}
+
+	// the UI's task may have stopped, in which case there's no one to tell
+	fn send(&self, event: UIEvent) {
+		self.events.send(event).unwrap_or(());
+	}
+
+	async fn run(mut state: TerminalState, mut events: UnboundedReceiver<UIEvent>) {
+		state.session.start(&mut state.task_queue).await;
+		let mut terminal = ratatui::init();
+		let mut input = EventStream::new();
+
+		// whether the messenger should be told to stop, or has stopped already
+		let quit = loop {
+			if let Err(e) = Self::draw(&mut terminal, &state) {
+				state.error(format!("Error drawing the screen: {}", e));
+				break true;
+			}
+
+			tokio::select! {
+				event = events.recv() => match event {
+					Some(UIEvent::Shutdown) | None => break false,
+					Some(event) => state.apply(event).await,
+				},
+				event = input.next() => match event {
+					Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+						if let UIStatus::Stop = state.handle_key(key).await {
+							break true;
+						}
+					}
+					Some(Ok(_)) => (),
+					Some(Err(_)) | None => break true,
+				},
+			}
+		};
+
+		ratatui::restore();
+		if quit {
+			state.task_queue.push(TaskData::Exit).await;
+		}
+	}
+
+	fn draw(terminal: &mut DefaultTerminal, state: &TerminalState) -> std::io::Result<()> {
+		terminal.draw(|frame| state.draw(frame))?;
+		Ok(())
+	}
+}
+
+impl TerminalState {
+	fn new(task_queue: TaskQueue, session: Session) -> Self {
+		Self {
+			task_queue,
+			console: Vec::new(),
+			channels: Vec::new(),
+			selected: 0,
+			scroll: 0,
+			input: String::new(),
+			cursor: 0,
+			connection: ConnectionState::Connecting,
+			status: Line::default(),
+			session,
+			loading: HashSet::new(),
+		}
+	}
+
+	fn channel(&mut self, name: &str) -> Option<&mut ChannelView> {
+		self.channels
+			.iter_mut()
+			.find(|view| *view.subscription.channel == *name)
+	}
+
+	fn selected_channel(&self) -> Option<&ChannelView> {
+		self.selected
+			.checked_sub(1)
+			.and_then(|index| self.channels.get(index))
+	}
+
+	fn notice(&mut self, text: String) {
+		self.print(Line::raw(text.clone()));
+		self.status = Line::raw(text);
+	}
+
+	fn error(&mut self, text: String) {
+		self.print(Line::raw(text.clone()).red());
+		self.status = Line::raw(text).red();
+	}
+
+	fn print(&mut self, line: Line<'static>) {
+		self.console.push(line);
+		drop_oldest(&mut self.console, MAX_CONSOLE_LINES);
+	}
+
+	async fn apply(&mut self, event: UIEvent) {
+		match event {
+			UIEvent::Message(message) => {
+				if let Some(view) = self.channel(&message.channel) {
+					view.insert(message);
+				}
+			}
+			UIEvent::History(messages) => self.history_received(messages),
+			UIEvent::Presence { channel, online } => {
+				if let Some(view) = self.channel(&channel) {
+					view.online = online;
+				}
+			}
+			UIEvent::Typing { channel, typing } => {
+				if let Some(view) = self.channel(&channel) {
+					view.typing = typing;
+				}
+			}
+			UIEvent::Unread { channel, unread } => {
+				let reading = self
+					.selected_channel()
+					.is_some_and(|view| view.subscription.channel == channel);
+				if let Some(view) = self.channel(&channel) {
+					view.unread = unread;
+				}
+				// what arrives in the channel being looked at is read straight away
+				if reading && unread > 0 {
+					self.task_queue.push(TaskData::MarkRead(channel)).await;
+				}
+			}
+			UIEvent::Channels(subscriptions) => self.channels_changed(subscriptions).await,
+			UIEvent::Encryption { channel, encrypted } => {
+				if let Some(view) = self.channel(&channel) {
+					view.encrypted = encrypted;
+				}
+			}
+			UIEvent::Connection(state) => self.connection = state,
+			UIEvent::Notice(text) => self.notice(text),
+			UIEvent::Error(text) => self.error(text),
+			UIEvent::Progress(text) => self.status = Line::raw(text),
+			UIEvent::Shutdown => (),
+		}
+	}
+
+	/*
+	 * a channel's backlog fills in its view, while anything else asked for,
+	 * such as a search or a thread, is listed in the console
+	 */
+	fn history_received(&mut self, messages: Vec<Message>) {
+		let channel = messages.first().map(|message| message.channel.clone());
+		let backlog = channel.as_ref().is_some_and(|channel| {
+			self.loading.contains(channel)
+				&& messages.iter().all(|message| message.channel == *channel)
+		});
+
+		if !backlog {
+			let mut depths = std::collections::HashMap::new();
+			for message in &messages {
+				let depth = message
+					.reply_to
+					.and_then(|parent| depths.get(&parent))
+					.map_or(0, |depth| depth + 1);
+				depths.insert(message.id, depth);
+
+				let mut line = message_line(message, true);
+				line.spans.insert(0, Span::raw("  ".repeat(depth)));
+				self.print(line);
+			}
+			if self.selected != 0 && !messages.is_empty() {
+				self.status =
+					Line::raw(format!("{} messages listed in the console", messages.len()));
+			}
+		}
+
+		if let Some(channel) = channel {
+			self.loading.remove(&channel);
+		}
+		for message in messages {
+			if let Some(view) = self.channel(&message.channel) {
+				view.insert(message);
+			}
+		}
+	}
+
+	async fn channels_changed(&mut self, subscriptions: Vec<Subscription>) {
+		let mut previous = std::mem::take(&mut self.channels);
+
+		for subscription in subscriptions {
+			let existing = previous
+				.iter()
+				.position(|view| view.subscription.channel == subscription.channel);
+			let view = match existing {
+				Some(index) => {
+					let mut view = previous.swap_remove(index);
+					view.subscription = subscription;
+					view
+				}
+				None => {
+					let channel = subscription.channel.clone();
+					self.loading.insert(channel.clone());
+					let task = TaskData::ShowHistory {
+						channel,
+						limit: BACKLOG_LENGTH,
+					};
+					self.task_queue.push(task).await;
+					ChannelView::new(subscription)
+				}
+			};
+			self.channels.push(view);
+		}
+
+		// stay on the same channel if it's still there, wherever it moved to
+		let selected = self.session_channel_index();
+		self.selected = selected.map_or(0, |index| index + 1);
+		if selected.is_none() {
+			self.session.channel = None;
+		}
+	}
+
+	fn session_channel_index(&self) -> Option<usize> {
+		let channel = self.session.channel.as_ref()?;
+		self.channels
+			.iter()
+			.position(|view| view.subscription.channel == *channel)
+	}
+
+	async fn select(&mut self, selected: usize) {
+		self.selected = selected;
+		self.scroll = 0;
+		self.session.channel = self
+			.selected_channel()
+			.map(|view| view.subscription.channel.clone());
+		let unread = self
+			.selected_channel()
+			.filter(|view| view.unread > 0)
+			.map(|view| view.subscription.channel.clone());
+		if let Some(channel) = unread {
+			self.task_queue.push(TaskData::MarkRead(channel)).await;
+		}
+	}
+
+	async fn handle_key(&mut self, key: KeyEvent) -> UIStatus {
+		let control = key.modifiers.contains(KeyModifiers::CONTROL);
+		let views = self.channels.len() + 1;
+
+		match key.code {
+			KeyCode::Esc => return UIStatus::Stop,
+			KeyCode::Char('c') if control => return UIStatus::Stop,
+			KeyCode::Tab => self.select((self.selected + 1) % views).await,
+			KeyCode::Char('n') if control => self.select((self.selected + 1) % views).await,
+			KeyCode::BackTab => self.select((self.selected + views - 1) % views).await,
+			KeyCode::Char('p') if control => self.select((self.selected + views - 1) % views).await,
+			KeyCode::Up => self.scroll += 1,
+			KeyCode::Down => self.scroll = self.scroll.saturating_sub(1),
+			KeyCode::PageUp => self.scroll += PAGE_LENGTH,
+			KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(PAGE_LENGTH),
+			KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+			KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.chars().count()),
+			KeyCode::Home => self.cursor = 0,
+			KeyCode::End => self.cursor = self.input.chars().count(),
+			KeyCode::Backspace if self.cursor > 0 => {
+				self.cursor -= 1;
+				self.input.remove(self.byte_index());
+			}
+			KeyCode::Delete if self.cursor < self.input.chars().count() => {
+				self.input.remove(self.byte_index());
+			}
+			KeyCode::Char(c) if !control => {
+				let index = self.byte_index();
+				self.input.insert(index, c);
+				self.cursor += 1;
+			}
+			KeyCode::Enter => {
+				let line = std::mem::take(&mut self.input);
+				self.cursor = 0;
+				return self.submit(line.trim()).await;
+			}
+			_ => (),
+		}
+
+		UIStatus::Continue
+	}
+
+	fn byte_index(&self) -> usize {
+		self.input
+			.char_indices()
+			.nth(self.cursor)
+			.map_or(self.input.len(), |(index, _)| index)
+	}
+
+	async fn submit(&mut self, line: &str) -> UIStatus {
+		if line.is_empty() {
+			return UIStatus::Continue;
+		}
+
+		let status = handle_line(&mut self.task_queue, &mut self.session, line).await;
+		match status {
+			// such as help, which is too long for the status bar
+			Ok(UIStatus::Output(text)) => {
+				for line in text.lines() {
+					self.print(Line::raw(line.to_string()));
+				}
+				self.select(0).await;
+			}
+			// follows the session to a joined channel, unless it isn't listed yet
+			Ok(UIStatus::Continue) => match self.session_channel_index() {
+				Some(index) => self.selected = index + 1,
+				None if self.session.channel.is_none() => self.selected = 0,
+				None => (),
+			},
+			Ok(UIStatus::Stop) => return UIStatus::Stop,
+			Err(e) => self.error(e),
+		}
+
+		UIStatus::Continue
+	}
+
+	fn draw(&self, frame: &mut Frame) {
+		let [main, input, status] = Layout::vertical([
+			Constraint::Min(1),
+			Constraint::Length(3),
+			Constraint::Length(1),
+		])
+		.areas(frame.area());
+		let [sidebar, messages] =
+			Layout::horizontal([Constraint::Length(SIDEBAR_WIDTH), Constraint::Min(1)]).areas(main);
+
+		self.draw_sidebar(frame, sidebar);
+		self.draw_messages(frame, messages);
+		self.draw_input(frame, input);
+		self.draw_status(frame, status);
+	}
+
+	fn draw_sidebar(&self, frame: &mut Frame, area: Rect) {
+		let mut items = vec![ListItem::new("console".italic())];
+		items.extend(self.channels.iter().map(|view| {
+			let subscription = &view.subscription;
+			let mut spans = vec![];
+			if subscription.favourite {
+				spans.push(Span::raw("★ ").yellow());
+			}
+			spans.push(Span::raw(subscription.channel.to_string()));
+			if view.unread > 0 && !subscription.muted {
+				spans.push(Span::raw(format!(" ({})", view.unread)).bold());
+			}
+
+			let line = Line::from(spans);
+			match subscription.muted {
+				true => ListItem::new(line.dark_gray()),
+				false => ListItem::new(line),
+			}
+		}));
+
+		let list = List::new(items)
+			.block(Block::bordered().title("Channels"))
+			.highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+		let mut state = ListState::default().with_selected(Some(self.selected));
+		frame.render_stateful_widget(list, area, &mut state);
+	}
+
+	fn draw_messages(&self, frame: &mut Frame, area: Rect) {
+		let (title, lines) = match self.selected_channel() {
+			None => ("console".to_string(), self.console.clone()),
+			Some(view) => {
+				let lock = if view.encrypted { " 🔒" } else { "" };
+				let title = format!("{}{}", view.subscription.channel, lock);
+				let lines = view
+					.messages
+					.iter()
+					.map(|message| message_line(message, false))
+					.collect();
+				(title, lines)
+			}
+		};
+
+		let block = Block::bordered().title(title);
+		let inner = block.inner(area);
+		let visible = visible_lines(lines, inner, self.scroll);
+		let paragraph = Paragraph::new(visible)
+			.block(block)
+			.wrap(Wrap { trim: false });
+		frame.render_widget(paragraph, area);
+	}
+
+	fn draw_input(&self, frame: &mut Frame, area: Rect) {
+		let title = match self.selected_channel() {
+			Some(view) => format!("Message {}", view.subscription.channel),
+			None => "Command".to_string(),
+		};
+		let block = Block::bordered().title(title);
+		let inner = block.inner(area);
+
+		// keep the cursor in sight on long lines
+		let before_cursor: String = self.input.chars().take(self.cursor).collect();
+		let cursor_x = Span::raw(before_cursor).width() as u16;
+		let offset = cursor_x.saturating_sub(inner.width.saturating_sub(1));
+		let input = Paragraph::new(self.input.as_str())
+			.block(block)
+			.scroll((0, offset));
+		frame.render_widget(input, area);
+		frame.set_cursor_position((inner.x + cursor_x - offset, inner.y));
+	}
+
+	fn draw_status(&self, frame: &mut Frame, area: Rect) {
+		let mut spans = vec![match self.connection {
+			ConnectionState::Connecting => Span::raw(" connecting ").black().on_yellow(),
+			ConnectionState::Connected => Span::raw(" connected ").black().on_green(),
+			ConnectionState::Disconnected => Span::raw(" disconnected ").white().on_red(),
+		}];
+
+		if let Some(view) = self.selected_channel() {
+			spans.push(Span::raw(format!(" {} online", view.online.len())));
+			if !view.typing.is_empty() {
+				spans.push(Span::raw(format!(", {} typing", view.typing.join(", "))).italic());
+			}
+		}
+		if !self.status.spans.is_empty() {
+			spans.push(Span::raw(" | "));
+			spans.extend(
+				self.status
+					.spans
+					.iter()
+					.map(|span| span.clone().patch_style(self.status.style)),
+			);
+		}
+
+		frame.render_widget(Line::from(spans), area);
+	}
+}
+
+impl ChannelView {
+	fn new(subscription: Subscription) -> Self {
+		Self {
+			subscription,
+			messages: Vec::new(),
+			unread: 0,
+			online: Vec::new(),
+			typing: Vec::new(),
+			encrypted: false,
+		}
+	}
+
+	// replaces an earlier copy of the message, such as before an edit
+	fn insert(&mut self, message: Message) {
+		self.messages.retain(|existing| existing.id != message.id);
+		let time = message_time(&message);
+		let index = self
+			.messages
+			.partition_point(|existing| message_time(existing) <= time);
+		self.messages.insert(index, message);
+		drop_oldest(&mut self.messages, MAX_CHANNEL_MESSAGES);
+	}
+}
+
+fn drop_oldest<T>(items: &mut Vec<T>, max: usize) {
+	let excess = items.len().saturating_sub(max);
+	items.drain(..excess);
+}
+
+fn message_time(message: &Message) -> u64 {
+	message.local_time().unwrap_or(0)
+}
+
+fn message_line(message: &Message, with_channel: bool) -> Line<'static> {
+	let time = DateTime::from_timestamp_millis(message_time(message) as i64)
+		.map_or_else(String::new, |time| time.format("%H:%M ").to_string());
+
+	let mut spans = vec![Span::raw(time).dark_gray()];
+	if message.encrypted {
+		spans.push(Span::raw("🔒 "));
+	}
+	if with_channel {
+		spans.push(Span::raw(format!("[{}] ", message.channel)));
+	}
+	spans.push(Span::styled(
+		message.sender.to_string(),
+		Style::new().fg(sender_colour(&message.sender)).bold(),
+	));
+	spans.push(Span::raw(verification_mark(message.verification)).dark_gray());
+	spans.push(Span::raw(format!(" ({})", message.short_id())).dark_gray());
+	if let Some(reply_to) = message.reply_to {
+		spans.push(Span::raw(format!(" re {}", short_id(&reply_to))).dark_gray());
+	}
+	spans.push(Span::raw(format!(": {}", contents(message))));
+	spans.push(Span::raw(annotations(message)).dark_gray());
+
+	Line::from(spans)
+}
+
+// the same sender gets the same colour every time
+fn sender_colour(sender: &str) -> Color {
+	let hash = sender.bytes().fold(0usize, |hash, byte| {
+		hash.wrapping_mul(31).wrapping_add(byte as usize)
+	});
+	SENDER_COLOURS[hash % SENDER_COLOURS.len()]
+}
+
+// the newest lines that fit, after skipping the scrolled-past ones
+fn visible_lines(lines: Vec<Line<'static>>, area: Rect, scroll: usize) -> Vec<Line<'static>> {
+	let width = area.width.max(1) as usize;
+	let end = lines.len().saturating_sub(scroll);
+	let mut rows = 0;
+	let mut start = end;
+	while start > 0 {
+		let height = lines[start - 1].width().div_ceil(width).max(1);
+		if rows + height > area.height as usize {
+			break;
+		}
+		rows += height;
+		start -= 1;
+	}
+
+	lines.into_iter().take(end).skip(start).collect()
+}
+
+impl UIConnector for TerminalUI {
+	fn message_received(&mut self, message: Message) {
+		self.send(UIEvent::Message(message))
+	}
+
+	fn message_updated(&mut self, message: Message) {
+		self.send(UIEvent::Message(message))
+	}
+
+	fn message_sent(&mut self, _: &Message, result: Result<(), String>) {
+		if let Err(e) = result {
+			self.send(UIEvent::Error(format!("Error sending message: {}", e)))
+		}
+	}
+
+	fn history_received(&mut self, messages: Vec<Message>) {
+		self.send(UIEvent::History(messages))
+	}
+
+	fn presence_changed(&mut self, channel: &str, online: Vec<Box<str>>) {
+		self.send(UIEvent::Presence {
+			channel: channel.into(),
+			online,
+		})
+	}
+
+	fn typing_changed(&mut self, channel: &str, typing: Vec<Box<str>>) {
+		self.send(UIEvent::Typing {
+			channel: channel.into(),
+			typing,
+		})
+	}
+
+	fn unread_changed(&mut self, channel: &str, unread: usize) {
+		self.send(UIEvent::Unread {
+			channel: channel.into(),
+			unread,
+		})
+	}
+
+	fn channels_changed(&mut self, channels: Vec<Subscription>) {
+		self.send(UIEvent::Channels(channels))
+	}
+
+	fn channel_encryption_changed(&mut self, channel: &str, encrypted: bool) {
+		self.send(UIEvent::Encryption {
+			channel: channel.into(),
+			encrypted,
+		})
+	}
+
+	fn file_received(&mut self, attachment: Attachment) {
+		self.send(UIEvent::Notice(format!(
+			"file received from {}{} in {}: {} ({} bytes) saved to {}",
+			attachment.sender,
+			verification_mark(attachment.verification),
+			attachment.channel,
+			attachment.file_name,
+			attachment.file_size,
+			attachment.location
+		)))
+	}
+
+	// not kept in the console, which it would fill
+	fn transfer_progress(&mut self, progress: TransferProgress) {
+		let verb = match progress.direction {
+			TransferDirection::Sending => "sending",
+			TransferDirection::Receiving => "receiving",
+		};
+		self.send(UIEvent::Progress(format!(
+			"{} {}: {}/{} chunks",
+			verb, progress.file_name, progress.chunks_done, progress.chunk_count
+		)))
+	}
+
+	fn connection_changed(&mut self, state: ConnectionState) {
+		self.send(UIEvent::Connection(state))
+	}
+
+	fn notice(&mut self, text: String) {
+		self.send(UIEvent::Notice(text))
+	}
+
+	fn error(&mut self, text: String) {
+		self.send(UIEvent::Error(text))
+	}
+
+	fn start(&mut self, task_queue: TaskQueue) {
+		if let Some(receiver) = self.receiver.take() {
+			let state = TerminalState::new(task_queue, Session::new(&self.nick));
+			self.task = Some(tokio::task::spawn(Self::run(state, receiver)));
+		}
+	}
+
+	async fn shutdown(&mut self) {
+		self.send(UIEvent::Shutdown);
+		if let Some(task) = self.task.take() {
+			task.await.unwrap_or(());
+		}
+	}
+}
@@ -0,0 +1,156 @@
+/*
+ * Appends a line for each message, notice and error to a file, along with
+ * the time it was written, meant to run alongside another UI, see fan_out.rs.
+ * Lines are written in the simplified UI's format. Presence, typing and
+ * progress aren't kept, as they only matter at the moment. Its own errors
+ * go through the task queue, so the UI it runs alongside shows them.
+*/
+use std::{
+	fs::{File, OpenOptions},
+	io::{LineWriter, Write},
+	path::PathBuf,
+};
+
+use chrono::DateTime;
+
+use super::{
+	simplified::{verification_mark, SimplifiedUI},
+	ConnectionState, UIConnector,
+};
+use crate::{
+	attachment::{Attachment, TransferProgress},
+	message::{timestamp_now, Message},
+	subscriptions::Subscription,
+	task_queue::{TaskData, TaskQueue},
+};
+
+pub struct TranscriptUI {
+	path: PathBuf,
+	// opened on start
+	file: Option<LineWriter<File>>,
+	task_queue: Option<TaskQueue>,
+}
+
+impl TranscriptUI {
+	pub fn new(path: &str) -> Self {
+		Self {
+			path: path.into(),
+			file: None,
+			task_queue: None,
+		}
+	}
+
+	fn report(&self, text: String) {
+		if let Some(task_queue) = &self.task_queue {
+			let mut task_queue = task_queue.clone();
+			tokio::task::spawn(async move { task_queue.push(TaskData::Error(text.into())).await });
+		}
+	}
+
+	fn write(&mut self, line: String) {
+		let Some(file) = &mut self.file else {
+			return;
+		};
+		let time = DateTime::from_timestamp_millis(timestamp_now() as i64)
+			.map_or_else(String::new, |time| {
+				time.format("%Y-%m-%d %H:%M:%S").to_string()
+			});
+
+		if let Err(e) = writeln!(file, "{} {}", time, line) {
+			// once, rather than for every line after
+			self.file = None;
+			self.report(format!("Error writing transcript, stopping it: {}", e));
+		}
+	}
+}
+
+impl UIConnector for TranscriptUI {
+	fn message_received(&mut self, message: Message) {
+		self.write(SimplifiedUI::format_message(&message))
+	}
+
+	fn message_updated(&mut self, message: Message) {
+		self.write(format!(
+			"updated: {}",
+			SimplifiedUI::format_message(&message)
+		))
+	}
+
+	fn message_sent(&mut self, _: &Message, result: Result<(), String>) {
+		if let Err(e) = result {
+			self.write(format!("Error sending message: {}", e))
+		}
+	}
+
+	// already written when first received
+	fn history_received(&mut self, _: Vec<Message>) {}
+
+	fn presence_changed(&mut self, _: &str, _: Vec<Box<str>>) {}
+
+	fn typing_changed(&mut self, _: &str, _: Vec<Box<str>>) {}
+
+	fn unread_changed(&mut self, _: &str, _: usize) {}
+
+	fn channels_changed(&mut self, _: Vec<Subscription>) {}
+
+	fn channel_encryption_changed(&mut self, channel: &str, encrypted: bool) {
+		match encrypted {
+			true => self.write(format!("[{}] is now end-to-end encrypted", channel)),
+			false => self.write(format!("[{}] is no longer encrypted", channel)),
+		}
+	}
+
+	fn file_received(&mut self, attachment: Attachment) {
+		self.write(format!(
+			"file received from {}{} in {}: {} ({} bytes, sha256 {}) saved to {}",
+			attachment.sender,
+			verification_mark(attachment.verification),
+			attachment.channel,
+			attachment.file_name,
+			attachment.file_size,
+			attachment.sha256,
+			attachment.location
+		))
+	}
+
+	fn transfer_progress(&mut self, _: TransferProgress) {}
+
+	fn connection_changed(&mut self, state: ConnectionState) {
+		match state {
+			ConnectionState::Connecting => (),
+			ConnectionState::Connected => self.write("Connected".to_string()),
+			ConnectionState::Disconnected => self.write("Connection lost".to_string()),
+		}
+	}
+
+	fn notice(&mut self, text: String) {
+		self.write(text)
+	}
+
+	fn error(&mut self, text: String) {
+		self.write(text)
+	}
+
+	// the task queue is only used to report its errors
+	fn start(&mut self, task_queue: TaskQueue) {
+		self.task_queue = Some(task_queue);
+		let file = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&self.path);
+		match file {
+			Ok(file) => self.file = Some(LineWriter::new(file)),
+			Err(e) => self.report(format!(
+				"Error opening transcript {}: {}",
+				self.path.display(),
+				e
+			)),
+		}
+	}
+
+	async fn shutdown(&mut self) {
+		if let Some(file) = &mut self.file {
+			file.flush().unwrap_or(());
+		}
+	}
+}
@@ -0,0 +1,513 @@
+/*
+ * Serves an HTTP API and a live event stream on localhost, for browser UIs,
+ * along with a small page using them, or the pages in WEB_PAGE_DIRECTORY.
+ * A new token is made on every start and printed with the page's address.
+ * When another UI draws on the terminal, such as the terminal UI, that and the
+ * web UI's other messages are shown by it instead of printed, and failing to
+ * serve leaves the client running with the other UI.
+ * Every /api request needs it, as an `Authorization: Bearer <token>` header
+ * or, for WebSockets, which browsers can't add headers to, a token query.
+ *   POST   /api/messages                      {channel, text, sender?}
+ *   GET    /api/channels                      the subscriptions, see subscriptions.rs
+ *   POST   /api/channels                      {channel}, subscribes
+ *   DELETE /api/channels/{channel}            unsubscribes
+ *   GET    /api/channels/{channel}/messages?limit=<n>
+ *   POST   /api/commands                      any command of the JSON Lines UI
+ *   GET    /api/events                        a WebSocket, see below
+ * Commands are answered with 202 and their outcome, such as {"message_id"},
+ * once queued, or with 400 and {"error"}. The WebSocket carries the JSON Lines
+ * UI's events, see json_lines.rs, as text frames, and takes its commands,
+ * answering each with a result event. Exit stops the client.
+*/
+use std::{
+	net::SocketAddr,
+	path::{Component, Path, PathBuf},
+	sync::{Arc, Mutex},
+};
+
+use axum::{
+	extract::{
+		ws::{self, WebSocket, WebSocketUpgrade},
+		Path as UrlPath, Query, Request as HttpRequest, State,
+	},
+	http::{header, StatusCode, Uri},
+	middleware::{self, Next},
+	response::{Html, IntoResponse, Response},
+	routing::{delete, get, post},
+	Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as base64_engine, Engine as _};
+use rand_core::{OsRng, RngCore};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{
+	sync::{broadcast, broadcast::error::RecvError, oneshot, watch},
+	task::JoinHandle,
+};
+
+use crate::{
+	attachment::{Attachment, TransferProgress},
+	message::Message,
+	subscriptions::Subscription,
+	task_queue::{HistoryLookup, TaskData, TaskQueue},
+};
+
+use super::{
+	commands::Session,
+	json_lines::{Event, JsonLinesUI, Request, DEFAULT_HISTORY_LENGTH},
+	ConnectionState, UIConnector,
+};
+
+const PAGE: &str = include_str!("web_page.html");
+const TOKEN_LENGTH: usize = 32;
+// events kept for each WebSocket, before it starts missing them
+const EVENT_BUFFER: usize = 1024;
+
+#[derive(Clone)]
+struct WebState {
+	task_queue: TaskQueue,
+	// shared by every request, as there's only the one user
+	session: Arc<tokio::sync::Mutex<Session>>,
+	events: broadcast::Sender<Arc<str>>,
+	channels: Arc<Mutex<Vec<Subscription>>>,
+	token: Arc<str>,
+	page_directory: Option<Arc<Path>>,
+	stopping: watch::Receiver<bool>,
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+	token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+	limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct SendBody {
+	channel: Box<str>,
+	text: Box<str>,
+	sender: Option<Box<str>>,
+}
+
+#[derive(Deserialize)]
+struct ChannelBody {
+	channel: Box<str>,
+}
+
+pub struct WebUI {
+	address: Box<str>,
+	nick: Box<str>,
+	page_directory: Option<PathBuf>,
+	// each event as JSON, for the WebSockets
+	events: broadcast::Sender<Arc<str>>,
+	// the latest subscriptions, for GET /api/channels
+	channels: Arc<Mutex<Vec<Subscription>>>,
+	stopping: watch::Sender<bool>,
+	server_task: Option<JoinHandle<()>>,
+	shared_terminal: bool,
+}
+
+// where the web UI's own messages go, see WebUI::with_shared_terminal
+struct Reporter {
+	task_queue: TaskQueue,
+	shared_terminal: bool,
+}
+
+impl WebUI {
+	// the nick may be empty, to be set with the nick command, and so may the page directory
+	pub fn new(address: &str, nick: &str, page_directory: &str) -> Self {
+		let (events, _) = broadcast::channel(EVENT_BUFFER);
+		Self {
+			address: address.into(),
+			nick: nick.into(),
+			page_directory: (!page_directory.is_empty()).then(|| page_directory.into()),
+			events,
+			channels: Arc::new(Mutex::new(Vec::new())),
+			stopping: watch::Sender::new(false),
+			server_task: None,
+			shared_terminal: false,
+		}
+	}
+
+	// whether another UI draws on the terminal, so nothing should be printed
+	pub fn with_shared_terminal(mut self, shared_terminal: bool) -> Self {
+		self.shared_terminal = shared_terminal;
+		self
+	}
+
+	fn reporter(&self, task_queue: TaskQueue) -> Reporter {
+		Reporter {
+			task_queue,
+			shared_terminal: self.shared_terminal,
+		}
+	}
+
+	// there may be no one watching, in which case the event is dropped
+	fn emit(&self, event: &Event) {
+		match serde_json::to_string(event) {
+			Ok(line) => {
+				self.events.send(line.into()).unwrap_or(0);
+			}
+			Err(e) => eprintln!("Error writing event: {}", e),
+		}
+	}
+
+	fn router(state: WebState) -> Router {
+		let api = Router::new()
+			.route("/messages", post(send_message))
+			.route("/channels", get(get_channels).post(subscribe))
+			.route("/channels/{channel}", delete(unsubscribe))
+			.route("/channels/{channel}/messages", get(get_messages))
+			.route("/commands", post(run_command))
+			.route("/events", get(events))
+			.layer(middleware::from_fn_with_state(state.clone(), authorize));
+
+		Router::new()
+			.nest("/api", api)
+			.fallback(get(page))
+			.with_state(state)
+	}
+
+	async fn serve(address: SocketAddr, state: WebState, mut reporter: Reporter) {
+		let listener = match tokio::net::TcpListener::bind(address).await {
+			Ok(listener) => listener,
+			Err(e) => {
+				let error = format!("Could not listen on {}: {}", address, e);
+				return reporter.fail(error).await;
+			}
+		};
+		let url = format!("Web UI on http://{}/?token={}", address, state.token);
+		reporter.notice(url).await;
+
+		let mut stopping = state.stopping.clone();
+		let server = axum::serve(listener, Self::router(state))
+			.with_graceful_shutdown(async move { stopping.changed().await.unwrap_or(()) });
+		if let Err(e) = server.await {
+			reporter
+				.fail(format!("Error serving the web UI: {}", e))
+				.await;
+		}
+	}
+}
+
+impl UIConnector for WebUI {
+	fn message_received(&mut self, message: Message) {
+		self.emit(&Event::Message { message })
+	}
+
+	fn message_updated(&mut self, message: Message) {
+		self.emit(&Event::Updated { message })
+	}
+
+	fn message_sent(&mut self, message: &Message, result: Result<(), String>) {
+		self.emit(&Event::sent(message, result))
+	}
+
+	fn history_received(&mut self, messages: Vec<Message>) {
+		self.emit(&Event::History { messages })
+	}
+
+	fn presence_changed(&mut self, channel: &str, online: Vec<Box<str>>) {
+		self.emit(&Event::Presence { channel, online })
+	}
+
+	fn typing_changed(&mut self, channel: &str, typing: Vec<Box<str>>) {
+		self.emit(&Event::Typing { channel, typing })
+	}
+
+	fn unread_changed(&mut self, channel: &str, unread: usize) {
+		self.emit(&Event::Unread { channel, unread })
+	}
+
+	fn channels_changed(&mut self, channels: Vec<Subscription>) {
+		if let Ok(mut cached) = self.channels.lock() {
+			cached.clone_from(&channels);
+		}
+		self.emit(&Event::Channels { channels })
+	}
+
+	fn channel_encryption_changed(&mut self, channel: &str, encrypted: bool) {
+		self.emit(&Event::Encryption { channel, encrypted })
+	}
+
+	fn file_received(&mut self, attachment: Attachment) {
+		self.emit(&Event::file(attachment))
+	}
+
+	fn transfer_progress(&mut self, progress: TransferProgress) {
+		self.emit(&Event::transfer(progress))
+	}
+
+	fn connection_changed(&mut self, state: ConnectionState) {
+		self.emit(&Event::Connection { state })
+	}
+
+	// also on stderr, for whoever runs the client, unless another UI shows them
+	fn notice(&mut self, text: String) {
+		if !self.shared_terminal {
+			eprintln!("{}", text);
+		}
+		self.emit(&Event::Notice { text })
+	}
+
+	fn error(&mut self, text: String) {
+		if !self.shared_terminal {
+			eprintln!("{}", text);
+		}
+		self.emit(&Event::Error { text })
+	}
+
+	fn start(&mut self, mut task_queue: TaskQueue) {
+		let address = match self.address.parse::<SocketAddr>() {
+			Ok(address) if address.ip().is_loopback() => Ok(address),
+			Ok(_) => Err(format!("{} isn't a loopback address", self.address)),
+			Err(e) => Err(e.to_string()),
+		};
+		let address = match address {
+			Ok(address) => address,
+			Err(e) => {
+				let mut reporter = self.reporter(task_queue);
+				let error = format!("Bad web UI address: {}", e);
+				tokio::task::spawn(async move { reporter.fail(error).await });
+				return;
+			}
+		};
+
+		let mut token = [0u8; TOKEN_LENGTH];
+		OsRng.fill_bytes(&mut token);
+		let reporter = self.reporter(task_queue.clone());
+		let session = Session::new(&self.nick);
+		let state = WebState {
+			task_queue: task_queue.clone(),
+			session: Arc::new(tokio::sync::Mutex::new(session)),
+			events: self.events.clone(),
+			channels: Arc::clone(&self.channels),
+			token: base64_engine.encode(token).into(),
+			page_directory: self.page_directory.as_deref().map(Arc::from),
+			stopping: self.stopping.subscribe(),
+		};
+
+		self.server_task = Some(tokio::task::spawn(async move {
+			state.session.lock().await.start(&mut task_queue).await;
+			Self::serve(address, state, reporter).await
+		}));
+	}
+
+	async fn shutdown(&mut self) {
+		self.stopping.send(true).unwrap_or(());
+		if let Some(server_task) = self.server_task.take() {
+			server_task.await.unwrap_or(());
+		}
+	}
+}
+
+impl Reporter {
+	async fn notice(&mut self, text: String) {
+		match self.shared_terminal {
+			true => self.task_queue.push(TaskData::Notice(text.into())).await,
+			false => eprintln!("{}", text),
+		}
+	}
+
+	// the client keeps running without the web UI, when there's another to use
+	async fn fail(&mut self, text: String) {
+		match self.shared_terminal {
+			true => self.task_queue.push(TaskData::Error(text.into())).await,
+			false => {
+				eprintln!("{}", text);
+				self.task_queue.push(TaskData::Exit).await;
+			}
+		}
+	}
+}
+
+async fn authorize(State(state): State<WebState>, request: HttpRequest, next: Next) -> Response {
+	let header = request
+		.headers()
+		.get(header::AUTHORIZATION)
+		.and_then(|value| value.to_str().ok())
+		.and_then(|value| value.strip_prefix("Bearer "))
+		.map(str::to_string);
+	let query = Query::<TokenQuery>::try_from_uri(request.uri())
+		.ok()
+		.and_then(|Query(query)| query.token);
+
+	match header.or(query) {
+		Some(token) if same_token(&token, &state.token) => next.run(request).await,
+		_ => error_response(
+			StatusCode::UNAUTHORIZED,
+			"Missing or wrong token".to_string(),
+		),
+	}
+}
+
+async fn send_message(State(state): State<WebState>, Json(body): Json<SendBody>) -> Response {
+	let request = Request::Send {
+		channel: body.channel,
+		text: body.text,
+		sender: body.sender,
+	};
+	command(&state, request).await
+}
+
+async fn get_channels(State(state): State<WebState>) -> Response {
+	let channels = state.channels.lock().map(|channels| channels.clone());
+	Json(channels.unwrap_or_default()).into_response()
+}
+
+async fn subscribe(State(state): State<WebState>, Json(body): Json<ChannelBody>) -> Response {
+	let request = Request::Subscribe {
+		channel: body.channel,
+	};
+	command(&state, request).await
+}
+
+async fn unsubscribe(
+	State(state): State<WebState>,
+	UrlPath(channel): UrlPath<Box<str>>,
+) -> Response {
+	command(&state, Request::Unsubscribe { channel }).await
+}
+
+async fn get_messages(
+	State(state): State<WebState>,
+	UrlPath(channel): UrlPath<Box<str>>,
+	Query(query): Query<HistoryQuery>,
+) -> Response {
+	let (reply, messages) = oneshot::channel();
+	let lookup = HistoryLookup::Channel {
+		channel,
+		limit: query.limit.unwrap_or(DEFAULT_HISTORY_LENGTH),
+	};
+	let task = TaskData::QueryHistory { lookup, reply };
+	state.task_queue.clone().push(task).await;
+
+	match messages.await {
+		Ok(Ok(messages)) => Json(messages).into_response(),
+		Ok(Err(e)) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+		Err(_) => error_response(
+			StatusCode::SERVICE_UNAVAILABLE,
+			"The client is shutting down".to_string(),
+		),
+	}
+}
+
+async fn run_command(State(state): State<WebState>, Json(request): Json<Request>) -> Response {
+	command(&state, request).await
+}
+
+async fn command(state: &WebState, request: Request) -> Response {
+	let mut task_queue = state.task_queue.clone();
+	let mut session = state.session.lock().await;
+	match JsonLinesUI::handle_command(&mut task_queue, &mut session, request).await {
+		Ok(outcome) => {
+			if outcome.stop {
+				task_queue.push(TaskData::Exit).await;
+			}
+			(StatusCode::ACCEPTED, Json(outcome)).into_response()
+		}
+		Err(e) => error_response(StatusCode::BAD_REQUEST, e),
+	}
+}
+
+async fn events(State(state): State<WebState>, upgrade: WebSocketUpgrade) -> Response {
+	upgrade.on_upgrade(move |socket| serve_socket(socket, state))
+}
+
+async fn serve_socket(mut socket: WebSocket, state: WebState) {
+	let mut task_queue = state.task_queue.clone();
+	let mut events = state.events.subscribe();
+	let mut stopping = state.stopping.clone();
+
+	loop {
+		let event = tokio::select! {
+			event = events.recv() => match event {
+				Ok(event) => event.to_string(),
+				Err(RecvError::Lagged(missed)) => {
+					let text = format!("Missed {} events, reading too slowly", missed);
+					match serde_json::to_string(&Event::Error { text }) {
+						Ok(event) => event,
+						Err(_) => continue,
+					}
+				}
+				Err(RecvError::Closed) => break,
+			},
+			received = socket.recv() => match received {
+				Some(Ok(ws::Message::Text(text))) => {
+					let mut session = state.session.lock().await;
+					let (result, stop) =
+						JsonLinesUI::handle_request(&mut task_queue, &mut session, &text).await;
+					if stop {
+						task_queue.push(TaskData::Exit).await;
+					}
+					match serde_json::to_string(&result) {
+						Ok(result) => result,
+						Err(_) => continue,
+					}
+				}
+				Some(Ok(ws::Message::Close(_))) | Some(Err(_)) | None => break,
+				Some(Ok(_)) => continue,
+			},
+			_ = stopping.changed() => break,
+		};
+
+		if socket.send(ws::Message::Text(event.into())).await.is_err() {
+			break;
+		}
+	}
+}
+
+// the bundled page, or a file from the page directory
+async fn page(State(state): State<WebState>, uri: Uri) -> Response {
+	let path = uri.path().trim_start_matches('/');
+	let path = if path.is_empty() { "index.html" } else { path };
+
+	let directory = match &state.page_directory {
+		Some(directory) => directory,
+		None if path == "index.html" => return Html(PAGE).into_response(),
+		None => return StatusCode::NOT_FOUND.into_response(),
+	};
+	// nothing outside the directory
+	let path = Path::new(path);
+	if !path
+		.components()
+		.all(|component| matches!(component, Component::Normal(_)))
+	{
+		return StatusCode::NOT_FOUND.into_response();
+	}
+
+	match tokio::fs::read(directory.join(path)).await {
+		Ok(contents) => ([(header::CONTENT_TYPE, content_type(path))], contents).into_response(),
+		Err(_) => StatusCode::NOT_FOUND.into_response(),
+	}
+}
+
+fn content_type(path: &Path) -> &'static str {
+	match path.extension().and_then(|extension| extension.to_str()) {
+		Some("html") => "text/html; charset=utf-8",
+		Some("css") => "text/css",
+		Some("js") => "text/javascript",
+		Some("json") => "application/json",
+		Some("svg") => "image/svg+xml",
+		Some("png") => "image/png",
+		Some("ico") => "image/x-icon",
+		_ => "application/octet-stream",
+	}
+}
+
+fn error_response(status: StatusCode, error: String) -> Response {
+	(status, Json(json!({ "error": error }))).into_response()
+}
+
+// takes as long whichever byte differs, so the token can't be guessed a byte at a time
+fn same_token(given: &str, token: &str) -> bool {
+	given.len() == token.len()
+		&& given
+			.bytes()
+			.zip(token.bytes())
+			.fold(0, |difference, (a, b)| difference | (a ^ b))
+			== 0
+}
@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+#[derive(Default)]
+struct ChannelReadState {
+	unread: usize,
+	last_message: Option<Uuid>,
+	last_read: Option<Uuid>,
+}
+
+// per-channel count of messages received since the channel was last marked read
+#[derive(Default)]
+pub struct UnreadTracker {
+	channels: HashMap<Box<str>, ChannelReadState>,
+}
+
+impl UnreadTracker {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	// returns the channel's new unread count
+	pub fn message_received(&mut self, channel: &str, id: Uuid) -> usize {
+		let state = self.channels.entry(channel.into()).or_default();
+		state.unread += 1;
+		state.last_message = Some(id);
+		state.unread
+	}
+
+	/*
+	 * returns the newest message in the channel, if it wasn't read already,
+	 * so a read receipt can be sent for it
+	 */
+	pub fn mark_read(&mut self, channel: &str) -> Option<Uuid> {
+		let state = self.channels.get_mut(channel)?;
+		state.unread = 0;
+		if state.last_read == state.last_message {
+			return None;
+		}
+		state.last_read = state.last_message;
+		state.last_read
+	}
+
+	pub fn unread(&self, channel: &str) -> usize {
+		self.channels.get(channel).map_or(0, |state| state.unread)
+	}
+}